@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mudcrab::net::telnet::TelnetMessage;
+
+// from_bytes must never panic, and when it returns a message it must have used at least one
+// byte and no more than it was given. Decoding a whole buffer this way must always finish.
+// Every message must also survive to_vec and back unchanged, escaped 255s included.
+fuzz_target!(|data: &[u8]| {
+    let mut rest = data;
+    while let Some((msg, len)) = TelnetMessage::from_bytes(rest) {
+        assert!(len > 0 && len <= rest.len());
+        let encoded = msg.to_vec();
+        assert_eq!(TelnetMessage::from_bytes(&encoded), Some((msg, encoded.len())));
+        rest = &rest[len..];
+    }
+});
@@ -2,6 +2,15 @@ use serde_derive::Deserialize;
 use std::net::SocketAddr;
 use std::error::Error;
 use std::fs::read_to_string;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use crate::game::prompt::DEFAULT_PROMPT;
+use crate::mudstring::color::ColorSystem;
+use crate::net::Protocol;
+
+// Where to look for a config file when none is given on the command line, in order.
+pub const DEFAULT_CONFIG_PATHS: [&str; 2] = ["./config.json", "/etc/mudcrab/config.json"];
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ListenerConfig {
@@ -9,7 +18,44 @@ pub struct ListenerConfig {
     pub tls_telnet: Option<SocketAddr>,
     pub plain_websocket: Option<SocketAddr>,
     pub tls_websocket: Option<SocketAddr>,
-    pub ssh: Option<SocketAddr>
+    pub ssh: Option<SocketAddr>,
+    // Plain telnet over a Unix domain socket, for local tools. Ignored elsewhere.
+    pub unix: Option<PathBuf>
+}
+
+// Changes to one telnet option's flags. Anything left out keeps the default.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TelnetOptionConfig {
+    pub allow_local: Option<bool>,
+    pub allow_remote: Option<bool>,
+    pub start_local: Option<bool>,
+    pub start_remote: Option<bool>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    // Accept permessage-deflate when a client offers it.
+    pub deflate: bool,
+    // The largest compression window (as a power of two, 9-15) either side may use.
+    pub max_window_bits: u8
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            deflate: true,
+            max_window_bits: 15
+        }
+    }
+}
+
+// The operator control port. Connections must send "auth <token>" before anything else.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ControlConfig {
+    pub addr: SocketAddr,
+    pub token: String
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -18,15 +64,298 @@ pub struct TlsConfig {
     pub pem: String
 }
 
+// Clamps applied to every connection's capabilities after negotiation, regardless of what
+// the client claims.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CapabilityOverrides {
+    pub force_no_color: bool,
+    pub force_screen_reader: bool,
+    pub force_no_gmcp: bool,
+    pub force_no_msdp: bool,
+    pub force_no_mxp: bool,
+    // For clients that handle UTF-8 fine but never say so.
+    pub force_utf8: bool,
+    // Color assumed for each protocol until the client negotiates something else. Unset keeps
+    // the built-in default: no color for telnet, truecolor for WebSocket and SSH.
+    pub telnet_color: Option<ColorSystem>,
+    pub websocket_color: Option<ColorSystem>,
+    pub ssh_color: Option<ColorSystem>
+}
+
+impl CapabilityOverrides {
+    pub fn default_color(&self, protocol: &Protocol) -> Option<ColorSystem> {
+        match protocol {
+            Protocol::Telnet => self.telnet_color,
+            Protocol::WebSocket => self.websocket_color,
+            Protocol::SSH => self.ssh_color,
+            Protocol::Control => None
+        }
+    }
+}
+
+// What terminates each outgoing line. Telnet says CRLF, but some tools only want LF.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    #[serde(rename = "crlf")]
+    CRLF,
+    #[serde(rename = "lf")]
+    LF
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::CRLF => "\r\n",
+            LineEnding::LF => "\n"
+        }
+    }
+}
+
+// How OOB updates to the same package within one tick are combined before sending.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OobBatching {
+    // Send every update as-is.
+    #[serde(rename = "off")]
+    Off,
+    // Only the last update per package is sent.
+    #[default]
+    #[serde(rename = "last")]
+    LastWins,
+    // Keyword arguments of all updates per package are merged, later ones winning.
+    #[serde(rename = "merge")]
+    Merge
+}
+
+// Which OOB protocol carries the data for a telnet client that negotiated both. Sending
+// it over both would only double the traffic.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OobPreference {
+    #[default]
+    #[serde(rename = "gmcp")]
+    Gmcp,
+    #[serde(rename = "msdp")]
+    Msdp
+}
+
+// Scoring of misbehaving connections; see net::suspicion. Past throttle_at a connection's
+// input is only read every throttle_ms, and past disconnect_at it's dropped.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SuspicionConfig {
+    pub throttle_at: u32,
+    pub disconnect_at: u32,
+    pub throttle_ms: u64,
+    // The score falls by one for every this many seconds.
+    pub decay_secs: u64,
+    // Lines a second before it counts as a flood, and the longest line that isn't suspect.
+    pub flood_lines: usize,
+    pub max_line: usize
+}
+
+impl Default for SuspicionConfig {
+    fn default() -> Self {
+        Self {
+            throttle_at: 10,
+            disconnect_at: 25,
+            throttle_ms: 1000,
+            decay_secs: 30,
+            flood_lines: 50,
+            max_line: 8192
+        }
+    }
+}
+
+// Output pacing for slow links, used by connections that turned it on with 'pace'. Each
+// tick such a connection is sent at most lines_per_tick lines and about bytes_per_tick
+// bytes, and nothing while more than high_water_bytes are still waiting on the socket. The
+// rest waits, up to max_pending events; past that the oldest are dropped.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PacingConfig {
+    pub lines_per_tick: usize,
+    pub bytes_per_tick: usize,
+    pub high_water_bytes: usize,
+    pub max_pending: usize
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        Self {
+            lines_per_tick: 10,
+            bytes_per_tick: 2048,
+            high_water_bytes: 16384,
+            max_pending: 2000
+        }
+    }
+}
+
+// Options set on every listening and accepted socket.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SocketConfig {
+    // Disables Nagle's algorithm; MUD output is lots of small writes that shouldn't wait.
+    pub nodelay: bool,
+    pub keepalive: bool,
+    // Idle time before the first probe, and the time between probes. None keeps the OS default.
+    pub keepalive_time_secs: Option<u64>,
+    pub keepalive_interval_secs: Option<u64>,
+    // Pending connections the OS will queue on a listener before refusing more.
+    pub backlog: u32,
+    // Lets several processes share a port (Unix only). SO_REUSEADDR is always set.
+    pub reuse_port: bool,
+    // Tries at binding a listener at startup before giving up, and the wait after the first
+    // failure. The wait doubles after each one. A reload tries each new listener once.
+    pub bind_attempts: u32,
+    pub bind_retry_ms: u64
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: true,
+            keepalive_time_secs: Some(300),
+            keepalive_interval_secs: Some(30),
+            backlog: 1024,
+            reuse_port: false,
+            bind_attempts: 5,
+            bind_retry_ms: 250
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct NetConfig {
     pub listeners: Option<ListenerConfig>,
-    pub tls: Option<TlsConfig>
+    pub tls: Option<TlsConfig>,
+    pub capabilities: Option<CapabilityOverrides>,
+    pub line_ending: Option<LineEnding>,
+    pub oob_batching: Option<OobBatching>,
+    pub oob_preference: Option<OobPreference>,
+    // Readiness events handled per connection poll; grows up to poll_capacity_max under load.
+    pub poll_capacity: Option<usize>,
+    pub poll_capacity_max: Option<usize>,
+    // Hold input until the connection is done negotiating.
+    pub hold_input_until_active: Option<bool>,
+    pub socket: Option<SocketConfig>,
+    // Offer ECHO to every client, so the server echoes what they type.
+    pub server_echo: Option<bool>,
+    pub control: Option<ControlConfig>,
+    pub websocket: Option<WebSocketConfig>,
+    // Seconds a GMCP client may go without typing before it's sent Core.Ping, to keep the
+    // connection alive and measure its round trip. Unset sends none.
+    pub gmcp_ping_secs: Option<u64>,
+    // Throttle and then drop connections that keep misbehaving. Unset trusts everyone.
+    pub suspicion: Option<SuspicionConfig>,
+    // Spread output over several ticks instead of sending it all at once. Unset sends
+    // everything as soon as it's queued.
+    pub pacing: Option<PacingConfig>,
+    // Characters removed from typed input before it's treated as a command, like zero-width
+    // spaces some clients slip in. Unset uses net::telnet::DEFAULT_STRIP_CHARS. A leading
+    // byte order mark is always removed.
+    pub strip_input_chars: Option<Vec<char>>,
+    // Once a telnet connection goes active, ignore further MTTS and CHARSET changes so a
+    // client can't keep changing how output renders. Window size still updates.
+    pub freeze_capabilities: Option<bool>,
+    // File every connect and disconnect is appended to as a line of JSON. See net::audit.
+    pub audit_log: Option<PathBuf>,
+    // Per-listener changes to the telnet options, keyed like the listeners section
+    // ("plain_telnet", "tls_telnet", "unix") and then by option name ("MCCP2", "GMCP", ...).
+    pub telnet_options: Option<HashMap<String, HashMap<String, TelnetOptionConfig>>>
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DebugConfig {
+    // Record every telnet negotiation message per connection and dump it on disconnect.
+    pub trace_negotiation: bool
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GameConfig {
+    // How long execute_process may spend on commands in one tick before the rest wait.
+    pub process_budget_ms: u64,
+    // Shown to every connection once negotiation finishes, greeting first.
+    pub greeting: Option<String>,
+    pub motd: Option<String>,
+    // OOB packages whose payload is run as commands, as if the player had typed it.
+    pub oob_command_packages: Vec<String>,
+    // Seconds without typed input before a connection is warned, and then disconnected.
+    // No timeout means connections never idle out.
+    pub idle_warn_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    // Prompt sent after every command for players who haven't set their own. See game::prompt.
+    pub prompt: String,
+    // Extra MSSP variables like NAME, CODEBASE or CONTACT. PLAYERS and UPTIME are filled in.
+    pub mssp: HashMap<String, String>,
+    // Art shown on connect, before the greeting. Color clients get banner_ansi as-is; clients
+    // without color, screen readers and web clients get banner_plain, or banner_ansi with its
+    // escapes stripped if there is no plain file.
+    pub banner_ansi: Option<PathBuf>,
+    pub banner_plain: Option<PathBuf>,
+    // How many typed commands a lagged session may have waiting. Past that they're dropped.
+    pub max_queued_commands: usize,
+    // How many processes one session may have waiting to run. Commands past that are refused
+    // until some of them have run.
+    pub max_session_processes: usize,
+    // Area files loaded at startup, in order. See game::area.
+    pub areas: Vec<PathBuf>,
+    // Room new players start in, as "module/id". Returning players go back where they were.
+    pub start_room: Option<String>,
+    // Milliseconds a connection must wait between connect or create attempts. 0 allows
+    // them back to back.
+    pub login_cooldown_ms: u64,
+    // Whether an unknown command is answered with the closest real one, as in "Did you mean
+    // 'connect'?".
+    pub suggest_commands: bool,
+    // How many accounts one IP address, or IPv6 /64, may create per account_window_secs. No
+    // limit means any number. Unix socket clients aren't counted.
+    pub max_accounts_per_ip: Option<usize>,
+    pub account_window_secs: u64,
+    // Where accounts are saved whenever one changes, and loaded from at startup. See
+    // game::accounts. Unset keeps accounts only until the server stops.
+    pub accounts_file: Option<PathBuf>,
+    // Where sessions are saved at shutdown and by the savesessions control command. If the
+    // file is there at startup they're restored from it, and it's renamed with .restored
+    // added. See game::snapshot.
+    pub session_snapshot: Option<PathBuf>
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            process_budget_ms: 5,
+            greeting: None,
+            motd: None,
+            oob_command_packages: vec!["Input.Command".to_string()],
+            idle_warn_secs: None,
+            idle_timeout_secs: None,
+            prompt: DEFAULT_PROMPT.to_string(),
+            mssp: Default::default(),
+            banner_ansi: None,
+            banner_plain: None,
+            max_queued_commands: 10,
+            max_session_processes: 5,
+            areas: Vec::new(),
+            start_room: None,
+            login_cooldown_ms: 2000,
+            suggest_commands: true,
+            max_accounts_per_ip: None,
+            account_window_secs: 3600,
+            accounts_file: None,
+            session_snapshot: None
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    pub net: Option<NetConfig>
+    pub net: Option<NetConfig>,
+    pub game: Option<GameConfig>,
+    pub debug: Option<DebugConfig>
 }
 
 impl Config {
@@ -36,4 +365,76 @@ impl Config {
         let conf: Self = serde_json::from_str(&conf_txt)?;
         Ok(conf)
     }
-}
\ No newline at end of file
+
+    pub fn from_reader(mut reader: impl Read) -> Result<Self, Box<dyn Error>> {
+        let mut conf_txt = String::new();
+        reader.read_to_string(&mut conf_txt)?;
+        let conf: Self = serde_json::from_str(&conf_txt)?;
+        Ok(conf)
+    }
+
+    // Loads from the path given on the command line ("-" meaning stdin), or else the first of
+    // DEFAULT_CONFIG_PATHS that exists.
+    pub fn load(arg: Option<String>) -> Result<Self, Box<dyn Error>> {
+        match arg.as_deref() {
+            Some("-") => Self::from_reader(std::io::stdin()),
+            Some(path) => Self::from_file(path.to_string()),
+            None => {
+                let candidates: Vec<PathBuf> = DEFAULT_CONFIG_PATHS.iter().map(PathBuf::from).collect();
+                match resolve_config_path(&candidates) {
+                    Some(path) => Self::from_file(path.to_string_lossy().to_string()),
+                    None => Err(format!("No config file found in: {}", DEFAULT_CONFIG_PATHS.join(", ")).into())
+                }
+            }
+        }
+    }
+}
+
+// The file Config::load would read for arg, if any. Used to reload from the same place later;
+// stdin can't be re-read, so "-" has no path.
+pub fn config_source_path(arg: Option<&str>) -> Option<PathBuf> {
+    match arg {
+        Some("-") => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => {
+            let candidates: Vec<PathBuf> = DEFAULT_CONFIG_PATHS.iter().map(PathBuf::from).collect();
+            resolve_config_path(&candidates)
+        }
+    }
+}
+
+pub fn resolve_config_path(candidates: &[impl AsRef<Path>]) -> Option<PathBuf> {
+    candidates.iter()
+        .map(|p| p.as_ref())
+        .find(|p| p.is_file())
+        .map(|p| p.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_existing_candidate_is_picked() {
+        let dir = std::env::temp_dir().join(format!("mudcrab-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let missing = dir.join("missing.json");
+        let second = dir.join("second.json");
+        let third = dir.join("third.json");
+        std::fs::write(&second, "{}").unwrap();
+        std::fs::write(&third, "{}").unwrap();
+
+        assert_eq!(resolve_config_path(&[&missing, &second, &third]), Some(second.clone()));
+        assert_eq!(resolve_config_path(&[&third, &second]), Some(third.clone()));
+        assert_eq!(resolve_config_path(&[&missing]), None);
+        // A directory isn't a config file.
+        assert_eq!(resolve_config_path(&[&dir, &third]), Some(third));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stdin_has_no_path_to_reload_from() {
+        assert_eq!(config_source_path(Some("-")), None);
+        assert_eq!(config_source_path(Some("game.json")), Some(PathBuf::from("game.json")));
+    }
+}
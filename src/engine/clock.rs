@@ -0,0 +1,119 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+
+// Anything time-based in the systems should ask the Clock resource instead of calling
+// Instant::now() directly, so that it can be swapped for a MockClock when testing. The same
+// goes for wall-clock time and Utc::now().
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    fn utc_now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// A clock that only moves when told to. Clones share the same offset, so a test can keep a
+// handle after giving one to the Engine.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    base: Instant,
+    utc_base: Arc<Mutex<DateTime<Utc>>>,
+    offset: Arc<Mutex<Duration>>
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            base: Instant::now(),
+            utc_base: Arc::new(Mutex::new(Utc::now())),
+            offset: Default::default()
+        }
+    }
+}
+
+impl MockClock {
+    pub fn advance(&self, by: Duration) {
+        let mut offset = self.offset.lock().unwrap();
+        *offset += by;
+    }
+
+    // Makes the wall clock read at from now on, moving with advance() like everything else.
+    pub fn set_utc(&self, at: DateTime<Utc>) {
+        let offset = *self.offset.lock().unwrap();
+        *self.utc_base.lock().unwrap() = at - chrono::Duration::from_std(offset).unwrap();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        let offset = *self.offset.lock().unwrap();
+        *self.utc_base.lock().unwrap() + chrono::Duration::from_std(offset).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct EngineClock(pub Box<dyn Clock>);
+
+impl Default for EngineClock {
+    fn default() -> Self {
+        Self(Box::new(SystemClock))
+    }
+}
+
+impl EngineClock {
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    pub fn utc_now(&self) -> DateTime<Utc> {
+        self.0.utc_now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_only_moves_when_advanced() {
+        let clock = MockClock::default();
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        shared.advance(Duration::from_secs(30));
+        assert_eq!(clock.now(), start + Duration::from_secs(30));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn advancing_the_clock_times_out_an_idle_connection() {
+        use crate::config::GameConfig;
+        use crate::testing::Harness;
+
+        let mut h = Harness::with_game(GameConfig {idle_timeout_secs: Some(600), ..Default::default()});
+        let mut client = h.connect();
+        // Connecting already took the negotiation grace off the 600 seconds.
+        h.advance(Duration::from_secs(580));
+        assert!(!client.text().contains("idle too long"));
+        h.advance(Duration::from_secs(20));
+        assert!(client.text().contains("You have been idle too long. Goodbye!"));
+        h.tick(5);
+        assert!(client.is_closed());
+    }
+}
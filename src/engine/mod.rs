@@ -1,39 +1,54 @@
 mod systems;
-mod resources;
+pub(crate) mod resources;
+pub mod clock;
 
 use legion::*;
 use crate::{
-    config::{Config},
-    net::{ListenerComponent, ConnectionComponent,
-          ProtocolComponent, Protocol, ConnType, PollHandler}
+    config::{Config, ListenerConfig, SocketConfig, GameConfig},
+    net::{ListenerComponent, ListenerSnapshot, ConnectionSnapshot, ConnectionComponent,
+          ProtocolComponent, Protocol, ConnType, PollHandler, NetError, load_tls,
+          telnet::TelnetOption}
 };
 use mio::{Events, Poll, Token, Interest};
 use mio::net::TcpStream;
-use std::io::{Result, Read, Write, Error, ErrorKind, copy};
+use std::io::{Read, Write, copy};
 use std::net::SocketAddr;
 use std::collections::{HashMap};
-use bytes::{Bytes, BytesMut, Buf, BufMut};
 use std::thread::{sleep, yield_now};
 use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crate::engine::resources::{
-    ConnPoll, ListenPoll, TelnetOptions
+    ConnPoll, ListenPoll, TelnetOptions, Draining, ProcessBudget, InputGate, ControlToken, ReloadRequest,
+    GmcpPing, SuspicionPolicy, OutputPacing, TelnetSettings
 };
 
 use crate::game::resources::{
-    UsersOnline, MudSessions, Modules, PendingUserCreations, PendingUserLogins,
-    ObjTypeIndex, ProcessCounter, ProcessIndex
+    UsersOnline, MudSessions, Modules, PendingUserCreations, PendingUserLogins, AccountCreations,
+    ObjTypeIndex, ProcessCounter, ProcessIndex, BootTime, Banner
 };
 
 use crate::game::login_cmds::{LoginCommands};
+use crate::game::game_cmds::{GameCommands};
+use crate::game::control_cmds::{ControlCommands};
+use crate::game::area::load_area;
+use crate::game::accounts::{load_accounts, spawn_accounts, PasswordWorker};
+use crate::game::snapshot::{load_snapshot, restore_sessions, save_snapshot, snapshot_sessions};
+use crate::engine::clock::{Clock, EngineClock, MockClock};
+use crate::net::audit::AuditLog;
 
 
-use crate::engine::systems::{poll_listeners_system, accept_new_connections_system,
+use crate::engine::systems::{poll_listeners_system, accept_new_connections_system, audit_new_connections_system,
                              transfer_events_system, poll_connections_system,
                              process_connection_read_system, connection_health_check_system,
                              process_connection_newdata_system, process_connection_outgoing_system,
-                             session_in_events_system, execute_process_system,
-                             execute_connection_events_system, send_out_events_system};
+                             refresh_exemptions_system, session_in_events_system, execute_process_system,
+                             execute_connection_events_system, execute_control_events_system,
+                             process_logins_system, send_out_events_system,
+                             begin_process_budget_system, detach_closed_connections_system,
+                             reap_connections_system, persist_accounts_system};
 use serde_json::value::Value::Object;
 use std::future::Pending;
 
@@ -45,6 +60,15 @@ pub struct Engine {
     pub config: Config,
     pub world: World,
     pub resources: Resources,
+    schedules: Vec<Schedule>,
+    // Where reloads read from, and a flag anything (like a signal handler) can set to ask
+    // the run loop for one between ticks.
+    config_path: Option<PathBuf>,
+    reload_requested: Arc<AtomicBool>,
+    // Set by SIGTERM or SIGINT; run returns at the end of the tick that sees it.
+    stop_requested: Arc<AtomicBool>,
+    // The Unix socket connect_mock listens on, once it has been called.
+    mock_socket: Option<PathBuf>
 }
 
 impl Engine {
@@ -53,13 +77,32 @@ impl Engine {
         let group_1 = <(ConnectionComponent, ProtocolComponent)>::to_group();
 
         let listen_poll = ListenPoll::new(PollHandler::new(5, Some((0, 300))).unwrap());
-        let conn_poll = ConnPoll::new(PollHandler::new(100, Some((0, 300))).unwrap());
+        let poll_capacity = config.net.as_ref().and_then(|n| n.poll_capacity).unwrap_or(100);
+        let mut conn_handler = PollHandler::new(poll_capacity, Some((0, 300))).unwrap();
+        conn_handler.set_max_capacity(config.net.as_ref().and_then(|n| n.poll_capacity_max).unwrap_or(poll_capacity * 16));
+        let conn_poll = ConnPoll::new(conn_handler);
+
+        let overrides = config.net.as_ref()
+            .and_then(|n| n.capabilities.clone())
+            .unwrap_or_default();
+
+        let telnet_settings = TelnetSettings::from_config(config.net.as_ref());
+        let batching = config.net.as_ref()
+            .and_then(|n| n.oob_batching)
+            .unwrap_or_default();
+        let gate = InputGate(config.net.as_ref().and_then(|n| n.hold_input_until_active).unwrap_or(false));
+        let socket = config.net.as_ref().and_then(|n| n.socket.clone()).unwrap_or_default();
+        let debug = config.debug.clone().unwrap_or_default();
+        let game = config.game.clone().unwrap_or_default();
+        let control_token = ControlToken(config.net.as_ref().and_then(|n| n.control.as_ref()).map(|c| c.token.clone()));
+        let reload_requested = Arc::new(AtomicBool::new(false));
 
         let mut resources = Resources::default();
         resources.insert(listen_poll);
         resources.insert(conn_poll);
-        resources.insert(TelnetOptions::default());
+        resources.insert(TelnetOptions::from_config(config.net.as_ref()));
         resources.insert(UsersOnline::default());
+        resources.insert(Banner::load(&game));
         resources.insert(MudSessions::default());
         resources.insert(Modules::default());
         resources.insert(ObjTypeIndex::default());
@@ -67,31 +110,254 @@ impl Engine {
         resources.insert(ProcessIndex::default());
         resources.insert(PendingUserLogins::default());
         resources.insert(PendingUserCreations::default());
-        resources.insert(LoginCommands::default());
+        resources.insert(AccountCreations::default());
+        resources.insert(PasswordWorker::spawn());
+        resources.insert(LoginCommands {suggest: game.suggest_commands, ..Default::default()});
+        resources.insert(GameCommands {suggest: game.suggest_commands, ..Default::default()});
+        resources.insert(ControlCommands::default());
+        resources.insert(control_token);
+        resources.insert(ReloadRequest(reload_requested.clone()));
+        let clock = EngineClock::default();
+        resources.insert(BootTime(clock.utc_now()));
+        resources.insert(clock);
+        resources.insert(overrides);
+        resources.insert(Draining::default());
+        resources.insert(debug);
+        resources.insert(telnet_settings);
+        resources.insert(batching);
+        resources.insert(gate);
+        resources.insert(GmcpPing(config.net.as_ref().and_then(|n| n.gmcp_ping_secs).map(Duration::from_secs)));
+        resources.insert(SuspicionPolicy(config.net.as_ref().and_then(|n| n.suspicion.clone())));
+        resources.insert(OutputPacing(config.net.as_ref().and_then(|n| n.pacing.clone())));
+        resources.insert(socket);
+        resources.insert(AuditLog::from_config(config.net.as_ref()));
+        resources.insert(ProcessBudget::new(Duration::from_millis(game.process_budget_ms)));
+        resources.insert(game);
 
         let w_options = WorldOptions {
             groups: vec![group_1],
         };
 
         let mut world = World::new(w_options);
+        Self::load_areas(&mut world, &resources);
+        Self::load_accounts(&mut world, &resources);
+        Self::restore_snapshot(&mut world, &resources);
         Self {
             config,
             world,
-            resources
+            resources,
+            schedules: Self::build_schedules(),
+            config_path: None,
+            reload_requested,
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            mock_socket: None
+        }
+    }
+
+    // An engine with no config and a MockClock, for driving commands and sessions in tests.
+    // Nothing listens until connect_mock is called. Advance the clock past the negotiation
+    // grace, or answer it, before expecting a connection to go active.
+    pub fn new_for_test() -> (Self, MockClock) {
+        let mut engine = Self::new(Config {net: None, game: None, debug: None});
+        let clock = MockClock::default();
+        engine.set_clock(clock.clone());
+        (engine, clock)
+    }
+
+    // Connects a client to a telnet listener on a Unix socket in the temp directory, so tests
+    // need no TCP port. The engine accepts it on its next tick, like any other connection.
+    #[cfg(unix)]
+    pub fn connect_mock(&mut self) -> std::io::Result<std::os::unix::net::UnixStream> {
+        static NEXT_MOCK: AtomicUsize = AtomicUsize::new(0);
+        let path = match &self.mock_socket {
+            Some(path) => path.clone(),
+            None => {
+                let n = NEXT_MOCK.fetch_add(1, Ordering::Relaxed);
+                let path = std::env::temp_dir().join(format!("mudcrab-{}-{}.sock", std::process::id(), n));
+                self.register_unix_listener(&path, Protocol::Telnet)?;
+                self.mock_socket = Some(path.clone());
+                path
+            }
+        };
+        std::os::unix::net::UnixStream::connect(path)
+    }
+
+    // Meant for before the engine runs, so boot time is taken from the new clock too.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.resources.insert(BootTime(clock.utc_now()));
+        self.resources.insert(EngineClock(Box::new(clock)));
+    }
+
+    pub fn set_config_path(&mut self, path: Option<PathBuf>) {
+        self.config_path = path;
+    }
+
+    pub fn reload_handle(&self) -> Arc<AtomicBool> {
+        self.reload_requested.clone()
+    }
+
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop_requested.clone()
+    }
+
+    fn handle_reload_request(&mut self) {
+        if !self.reload_requested.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        // Done first, so rotation works even when the config can't be read.
+        self.reopen_logs();
+        match self.config_path.clone() {
+            Some(path) => {
+                if let Err(e) = self.reload_config(path.to_string_lossy()) {
+                    println!("Config reload failed: {}", e);
+                }
+            },
+            None => println!("Config reload requested, but the config wasn't read from a file.")
+        }
+    }
+
+    // Reopens every file-based log, after logrotate or the like has moved them.
+    pub fn reopen_logs(&mut self) {
+        if let Some(mut audit) = self.resources.get_mut::<AuditLog>() {
+            audit.reopen();
         }
     }
 
-    pub fn register_listener(&mut self, addr: SocketAddr, protocol: Protocol, ctype: ConnType) -> Result<()> {
+    pub fn set_draining(&self, draining: bool) {
+        if let Some(drain) = self.resources.get::<Draining>() {
+            drain.set(draining);
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.resources.get::<Draining>().map(|d| d.is_set()).unwrap_or(false)
+    }
+
+    pub fn register_listener(&mut self, addr: SocketAddr, protocol: Protocol, ctype: ConnType) -> std::result::Result<(), NetError> {
+        self.open_listener(addr, protocol, ctype, true)
+    }
+
+    // Startup can wait out a port the last run still holds. A reload runs on the tick loop,
+    // so it tries once and leaves retrying to the next reload.
+    fn open_listener(&mut self, addr: SocketAddr, protocol: Protocol, ctype: ConnType, retry: bool) -> std::result::Result<(), NetError> {
         let mut poller = self.resources.get_mut::<ListenPoll>().unwrap();
         let tok = poller.get_next();
-        let mut listen = ListenerComponent::new(addr, protocol, ctype, tok)?;
-        poller.handler.poller.registry().register(&mut listen.listener, tok, Interest::READABLE)?;
+        let mut socket = self.resources.get::<SocketConfig>().map(|s| s.clone()).unwrap_or_default();
+        if !retry {
+            socket.bind_attempts = 1;
+        }
+        let mut listen = ListenerComponent::new(addr, protocol, ctype, tok, socket)?;
+        listen.telnet_options = self.listener_telnet_options(&format!("{}_{}", listen.ctype.name(), listen.protocol.name()));
+        listen.websocket = self.config.net.as_ref().and_then(|n| n.websocket.clone()).unwrap_or_default();
+        if let ConnType::TLS = listen.ctype {
+            // Without net.tls the listener still opens, but refuses every connection.
+            if let Some(tls) = self.config.net.as_ref().and_then(|n| n.tls.as_ref()) {
+                listen.set_tls(load_tls(tls)?);
+            }
+        }
+        poller.handler.poller.registry().register(listen.listener.source_mut(), tok, Interest::READABLE)?;
+        println!("Listening on {}", listen.label);
         let mut entity = self.world.push((listen,));
         Ok(())
     }
 
+    #[cfg(unix)]
+    pub fn register_unix_listener(&mut self, path: &Path, protocol: Protocol) -> std::result::Result<(), NetError> {
+        let mut poller = self.resources.get_mut::<ListenPoll>().unwrap();
+        let tok = poller.get_next();
+        let socket = self.resources.get::<SocketConfig>().map(|s| s.clone()).unwrap_or_default();
+        let mut listen = ListenerComponent::unix(path, protocol, tok, socket)?;
+        listen.telnet_options = self.listener_telnet_options("unix");
+        poller.handler.poller.registry().register(listen.listener.source_mut(), tok, Interest::READABLE)?;
+        println!("Listening on {}", listen.label);
+        self.world.push((listen,));
+        Ok(())
+    }
+
+    // The option set for a listener whose config key is given, if config changes it.
+    fn listener_telnet_options(&self, key: &str) -> Option<Arc<HashMap<u8, TelnetOption>>> {
+        let changes = self.config.net.as_ref()?.telnet_options.as_ref()?.get(key)?;
+        let base = self.resources.get::<TelnetOptions>()?;
+        Some(base.with_changes(changes).0)
+    }
+
+    pub fn listeners_snapshot(&self) -> Vec<ListenerSnapshot> {
+        <&ListenerComponent>::query().iter(&self.world).map(|lis| lis.snapshot()).collect()
+    }
+
+    pub fn connections_snapshot(&self) -> Vec<ConnectionSnapshot> {
+        <(&ConnectionComponent, &ProtocolComponent)>::query().iter(&self.world)
+            .map(|(conn, prot)| prot.snapshot(conn))
+            .collect()
+    }
+
+    // A broken area is reported and skipped; the rest still load.
+    fn load_areas(world: &mut World, resources: &Resources) {
+        let paths = resources.get::<GameConfig>().map(|g| g.areas.clone()).unwrap_or_default();
+        let (mut modules, mut index) = match (resources.get_mut::<Modules>(), resources.get_mut::<ObjTypeIndex>()) {
+            (Some(m), Some(i)) => (m, i),
+            _ => return
+        };
+        for path in paths {
+            match load_area(world, &mut modules, &mut index, &path) {
+                Ok(_) => println!("Loaded area {}", path.display()),
+                Err(e) => println!("Could not load {}: {}", path.display(), e)
+            }
+        }
+    }
+
+    fn load_accounts(world: &mut World, resources: &Resources) {
+        let path = match resources.get::<GameConfig>().and_then(|g| g.accounts_file.clone()) {
+            Some(path) if path.exists() => path,
+            _ => return
+        };
+        match load_accounts(&path) {
+            Ok(accounts) => println!("Loaded {} accounts from {}", spawn_accounts(world, accounts), path.display()),
+            // Starting without them would overwrite the file with nothing at the first save,
+            // so it's moved out of the way for someone to look at.
+            Err(e) => {
+                let aside = path.with_extension("corrupt");
+                match std::fs::rename(&path, &aside) {
+                    Ok(_) => println!("Could not load accounts from {}: {}. Moved it to {}", path.display(), e, aside.display()),
+                    Err(moved) => println!("Could not load accounts from {}: {}. Could not move it aside either: {}", path.display(), e, moved)
+                }
+            }
+        }
+    }
+
+    // Runs after the areas load, so restored players find their rooms.
+    fn restore_snapshot(world: &mut World, resources: &Resources) {
+        let path = match resources.get::<GameConfig>().and_then(|g| g.session_snapshot.clone()) {
+            Some(path) if path.exists() => path,
+            _ => return
+        };
+        match load_snapshot(&path) {
+            Ok(snapshots) => {
+                println!("Restored {} sessions from {}", restore_sessions(world, &snapshots), path.display());
+                // Moved aside, so a later start that wasn't preceded by a save doesn't bring
+                // back accounts as they were back then.
+                let mut done = path.clone().into_os_string();
+                done.push(".restored");
+                if let Err(e) = std::fs::rename(&path, &done) {
+                    println!("Could not move {} aside: {}", path.display(), e);
+                }
+            },
+            Err(e) => println!("Could not restore sessions from {}: {}", path.display(), e)
+        }
+    }
+
+    // Writes every session to game.session_snapshot, for the next start to pick up. Returns
+    // how many were saved.
+    pub fn save_sessions(&self) -> std::io::Result<usize> {
+        let path = self.resources.get::<GameConfig>().and_then(|g| g.session_snapshot.clone())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no session_snapshot configured"))?;
+        let snapshots = snapshot_sessions(&self.world);
+        save_snapshot(&path, &snapshots)?;
+        Ok(snapshots.len())
+    }
+
     pub fn setup(&mut self) {
-        if let Some(n) = &self.config.net {
+        if let Some(n) = self.config.net.clone() {
             if let Some(l) = &n.listeners {
                 let mut success = 0;
                 if let Some(plain_telnet) = &l.plain_telnet {
@@ -102,6 +368,34 @@ impl Engine {
                     }
 
                 }
+                if let Some(addr) = &l.plain_websocket {
+                    if let Err(e) = self.register_listener(*addr, Protocol::WebSocket, ConnType::Plain) {
+                        panic!("Could not open a listening port for plain websocket on {}: {}", addr, e);
+                    }
+                    success += 1;
+                }
+                for (addr, protocol) in [(l.tls_telnet, Protocol::Telnet), (l.tls_websocket, Protocol::WebSocket)] {
+                    if let Some(addr) = addr {
+                        if let Err(e) = self.register_listener(addr, protocol, ConnType::TLS) {
+                            panic!("Could not open a TLS listening port on {}: {}", addr, e);
+                        }
+                        success += 1;
+                    }
+                }
+                if let Some(control) = &n.control {
+                    if let Err(e) = self.register_listener(control.addr, Protocol::Control, ConnType::Plain) {
+                        panic!("Could not open the control port on {}: {}", control.addr, e);
+                    }
+                }
+                #[cfg(unix)]
+                {
+                    if let Some(path) = &l.unix {
+                        if let Err(e) = self.register_unix_listener(path, Protocol::Telnet) {
+                            panic!("Could not open a unix socket at {}: {}", path.display(), e);
+                        }
+                        success += 1;
+                    }
+                }
 
                 if success == 0 {
                     panic!("Program has no listeners!");
@@ -115,42 +409,165 @@ impl Engine {
         }
     }
 
-    pub fn run(&mut self) {
+    // Re-reads the config file and applies whatever can change without a restart. Returns a
+    // note for each change that could not be applied live.
+    pub fn reload_config(&mut self, path: impl AsRef<str>) -> std::result::Result<Vec<String>, Box<dyn std::error::Error>> {
+        let new_conf = Config::from_file(path.as_ref().to_string())?;
+        let mut skipped = Vec::new();
+
+        let overrides = new_conf.net.as_ref()
+            .and_then(|n| n.capabilities.clone())
+            .unwrap_or_default();
+        self.resources.insert(overrides);
+
+        // Connections already open keep the options they negotiated with.
+        self.resources.insert(TelnetOptions::from_config(new_conf.net.as_ref()));
+        self.resources.insert(TelnetSettings::from_config(new_conf.net.as_ref()));
+
+        let batching = new_conf.net.as_ref()
+            .and_then(|n| n.oob_batching)
+            .unwrap_or_default();
+        self.resources.insert(batching);
+        self.resources.insert(InputGate(new_conf.net.as_ref().and_then(|n| n.hold_input_until_active).unwrap_or(false)));
+        self.resources.insert(GmcpPing(new_conf.net.as_ref().and_then(|n| n.gmcp_ping_secs).map(Duration::from_secs)));
+        self.resources.insert(SuspicionPolicy(new_conf.net.as_ref().and_then(|n| n.suspicion.clone())));
+        self.resources.insert(OutputPacing(new_conf.net.as_ref().and_then(|n| n.pacing.clone())));
 
-        let mut interval = Duration::from_millis(10);
+        self.resources.insert(new_conf.net.as_ref().and_then(|n| n.socket.clone()).unwrap_or_default());
+        self.resources.insert(AuditLog::from_config(new_conf.net.as_ref()));
+        self.resources.insert(ControlToken(new_conf.net.as_ref().and_then(|n| n.control.as_ref()).map(|c| c.token.clone())));
+        self.resources.insert(new_conf.debug.clone().unwrap_or_default());
 
-        let mut listen_schedule = Schedule::builder()
+        let game = new_conf.game.clone().unwrap_or_default();
+        self.resources.insert(Banner::load(&game));
+        if let Some(mut budget) = self.resources.get_mut::<ProcessBudget>() {
+            budget.limit = Duration::from_millis(game.process_budget_ms);
+        }
+        if let Some(mut cmds) = self.resources.get_mut::<LoginCommands>() {
+            cmds.suggest = game.suggest_commands;
+        }
+        if let Some(mut cmds) = self.resources.get_mut::<GameCommands>() {
+            cmds.suggest = game.suggest_commands;
+        }
+        self.resources.insert(game);
+
+        let old_listeners = self.config.net.as_ref().and_then(|n| n.listeners.clone());
+        let new_listeners = new_conf.net.as_ref().and_then(|n| n.listeners.clone());
+        skipped.extend(self.reload_listeners(old_listeners, new_listeners));
+
+        let old_control = self.config.net.as_ref().and_then(|n| n.control.as_ref()).map(|c| c.addr);
+        let new_control = new_conf.net.as_ref().and_then(|n| n.control.as_ref()).map(|c| c.addr);
+        if old_control != new_control {
+            skipped.push("control port changed; restart to apply.".to_string());
+        }
+
+        let old_tls = self.config.net.as_ref().and_then(|n| n.tls.as_ref()).map(|t| (t.key.clone(), t.pem.clone()));
+        let new_tls = new_conf.net.as_ref().and_then(|n| n.tls.as_ref()).map(|t| (t.key.clone(), t.pem.clone()));
+        if old_tls != new_tls {
+            skipped.push("TLS settings changed; restart to apply.".to_string());
+        }
+
+        for note in skipped.iter() {
+            println!("Config reload: {}", note);
+        }
+        self.config = new_conf;
+        Ok(skipped)
+    }
+
+    fn reload_listeners(&mut self, old: Option<ListenerConfig>, new: Option<ListenerConfig>) -> Vec<String> {
+        let mut skipped = Vec::new();
+        if old.as_ref().and_then(|l| l.unix.clone()) != new.as_ref().and_then(|l| l.unix.clone()) {
+            skipped.push("unix socket listener changed; restart to apply.".to_string());
+        }
+        let addrs = |l: &Option<ListenerConfig>| l.as_ref()
+            .map(|l| [l.plain_telnet, l.tls_telnet, l.plain_websocket, l.tls_websocket, l.ssh])
+            .unwrap_or_default();
+        // TLS listeners load their certificate when opened and TLS changes wait for a restart,
+        // so only plain ones are opened live.
+        let kinds = [
+            ("plain_telnet", Protocol::Telnet, ConnType::Plain, true),
+            ("tls_telnet", Protocol::Telnet, ConnType::TLS, false),
+            ("plain_websocket", Protocol::WebSocket, ConnType::Plain, true),
+            ("tls_websocket", Protocol::WebSocket, ConnType::TLS, false),
+            ("ssh", Protocol::SSH, ConnType::Plain, false)
+        ];
+
+        let (old_addrs, new_addrs) = (addrs(&old), addrs(&new));
+
+        for (i, (name, protocol, ctype, live)) in kinds.iter().enumerate() {
+            match (old_addrs[i], new_addrs[i]) {
+                (None, Some(addr)) if !*live => {
+                    skipped.push(format!("{} listener on {} can't be opened live.", name, addr));
+                },
+                (None, Some(addr)) => {
+                    if let Err(e) = self.open_listener(addr, protocol.clone(), ctype.clone(), false) {
+                        skipped.push(format!("Could not open new {} listener on {}: {}", name, addr, e));
+                    }
+                },
+                (Some(old_addr), Some(new_addr)) if old_addr != new_addr => {
+                    skipped.push(format!("{} listener moved from {} to {}; restart to apply.", name, old_addr, new_addr));
+                },
+                (Some(old_addr), None) => {
+                    skipped.push(format!("{} listener on {} removed; restart to apply.", name, old_addr));
+                },
+                _ => {}
+            }
+        }
+        skipped
+    }
+
+    fn build_schedules() -> Vec<Schedule> {
+        let listen_schedule = Schedule::builder()
             .add_system(poll_listeners_system())
             .add_system(accept_new_connections_system())
             .build();
 
-        let mut socket_io_schedule = Schedule::builder()
+        let socket_io_schedule = Schedule::builder()
+            .add_system(audit_new_connections_system())
             .add_system(poll_connections_system())
             .add_system(process_connection_read_system())
             .add_system(process_connection_newdata_system())
             .add_system(process_connection_outgoing_system())
             .add_system(connection_health_check_system())
+            .add_system(detach_closed_connections_system())
+            .add_system(reap_connections_system())
             .build();
 
 
-        let mut game_events_schedule = Schedule::builder()
+        let game_events_schedule = Schedule::builder()
             .add_system(execute_connection_events_system())
+            .add_system(process_logins_system())
+            .add_system(execute_control_events_system())
             .add_system(transfer_events_system())
             .add_system(send_out_events_system())
+            .add_system(refresh_exemptions_system())
             .add_system(session_in_events_system())
+            .add_system(begin_process_budget_system())
             .add_system(execute_process_system())
+            .add_system(persist_accounts_system())
             .build();
 
-        let mut delta = interval.clone();
+        vec![listen_schedule, socket_io_schedule, game_events_schedule]
+    }
 
-        loop {
-            self.resources.insert(Delta(delta));
-            let now = Instant::now();
-            listen_schedule.execute(&mut self.world, &mut self.resources);
+    // Runs every schedule exactly once. run() is just this in a loop, but embedders and tests
+    // can drive it themselves.
+    pub fn tick_once(&mut self, delta: Duration) {
+        self.resources.insert(Delta(delta));
+        for schedule in self.schedules.iter_mut() {
+            schedule.execute(&mut self.world, &mut self.resources);
+        }
+    }
 
-            socket_io_schedule.execute(&mut self.world, &mut self.resources);
+    pub fn run(&mut self) {
 
-            game_events_schedule.execute(&mut self.world, &mut self.resources);
+        let interval = Duration::from_millis(10);
+        let mut delta = interval;
+
+        while !self.stop_requested.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            self.tick_once(delta);
+            self.handle_reload_request();
 
             delta = now.elapsed();
 
@@ -159,5 +576,451 @@ impl Engine {
                 sleep(Duration::from_nanos(sleep_for));
             }
         }
+        self.shutdown();
+    }
+
+    // Saves the sessions if game.session_snapshot is set, then closes every listener, which
+    // removes any Unix socket files, so the next start finds nothing left behind.
+    pub fn shutdown(&mut self) {
+        if self.resources.get::<GameConfig>().map(|g| g.session_snapshot.is_some()).unwrap_or(false) {
+            match self.save_sessions() {
+                Ok(saved) => println!("Saved {} sessions.", saved),
+                Err(e) => println!("Could not save sessions: {}", e)
+            }
+        }
+        let listeners: Vec<Entity> = <(Entity, &ListenerComponent)>::query().iter(&self.world).map(|(ent, _)| *ent).collect();
+        for ent in listeners {
+            self.world.remove(ent);
+        }
+        println!("Shut down.");
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::TransportType;
+
+    #[test]
+    fn boot_time_comes_from_the_engine_clock() {
+        use chrono::{TimeZone, Utc};
+
+        let at = Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap();
+        let clock = MockClock::default();
+        clock.set_utc(at);
+        let mut engine = Engine::new(Config {net: None, game: None, debug: None});
+        engine.set_clock(clock);
+        assert_eq!(engine.resources.get::<BootTime>().unwrap().0, at);
+    }
+
+    #[test]
+    fn registered_listener_reports_its_label() {
+        let (mut engine, _clock) = Engine::new_for_test();
+        engine.register_listener("127.0.0.1:0".parse().unwrap(), Protocol::Telnet, ConnType::Plain).unwrap();
+        let snapshot = engine.listeners_snapshot();
+        assert_eq!(snapshot.len(), 1);
+        let port = snapshot[0].addr.unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(snapshot[0].label, format!("plain-telnet:127.0.0.1:{}", port));
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    #[test]
+    fn draining_refuses_new_connections_but_keeps_existing_ones() {
+        use crate::testing::{Client, Harness};
+
+        let mut h = Harness::new();
+        let mut player = h.login("alice");
+        h.engine.set_draining(true);
+
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut refused = Client {stream};
+        h.tick(3);
+        assert!(refused.read().contains("not accepting new connections"));
+        assert!(refused.is_closed());
+
+        assert!(h.command(&mut player, "help").contains("look"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn draining_closes_websocket_clients_without_a_text_line() {
+        use crate::testing::{temp_path, Client, Harness};
+
+        let mut h = Harness::new();
+        let path = temp_path("ws.sock");
+        h.engine.register_unix_listener(&path, Protocol::WebSocket).unwrap();
+        h.engine.set_draining(true);
+
+        let stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut refused = Client {stream};
+        h.tick(3);
+        assert!(refused.read_bytes().is_empty());
+        assert!(refused.is_closed());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn login_commands_are_dispatched_for_a_mock_connection() {
+        use crate::net::telnet::NEGOTIATION_GRACE;
+        use crate::testing::Client;
+
+        let (mut engine, clock) = Engine::new_for_test();
+        let stream = engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        let run = |engine: &mut Engine, client: &mut Client, line: &str| {
+            client.write(format!("{}\r\n", line).as_bytes());
+            for _ in 0..8 {
+                engine.tick_once(Duration::from_millis(10));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            while engine.resources.get::<PasswordWorker>().unwrap().is_busy() {
+                engine.tick_once(Duration::from_millis(10));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            engine.tick_once(Duration::from_millis(10));
+            client.text()
+        };
+        run(&mut engine, &mut client, "");
+        clock.advance(NEGOTIATION_GRACE + Duration::from_secs(1));
+        run(&mut engine, &mut client, "");
+
+        assert!(run(&mut engine, &mut client, "connect").contains("SYNTAX: connect <username>=<password>"));
+        assert!(run(&mut engine, &mut client, "help").contains("create"));
+        // The failed connect started the login cooldown.
+        clock.advance(Duration::from_secs(5));
+        run(&mut engine, &mut client, "create alice=password");
+        assert!(run(&mut engine, &mut client, "help").contains("look"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_connection_that_agreed_to_echo_reports_it_in_the_snapshot() {
+        use crate::net::telnet::codes;
+        use crate::testing::{Client, Harness};
+
+        let net: crate::config::NetConfig = serde_json::from_str(r#"{"server_echo": true}"#).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(3);
+        assert!(client.read_bytes().windows(3).any(|w| w == [codes::IAC, codes::WILL, codes::ECHO]));
+        assert_eq!(h.engine.connections_snapshot()[0].echo.to_string(), "echo=off sga=off linemode=off");
+
+        client.write(&[codes::IAC, codes::DO, codes::ECHO]);
+        h.tick(3);
+        let snapshot = h.engine.connections_snapshot();
+        assert!(snapshot[0].echo.echo);
+        assert!(snapshot[0].echo.to_string().starts_with("echo=on"), "{}", snapshot[0].echo);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tick_once_advances_a_queued_connection_one_step() {
+        use crate::net::telnet::NEGOTIATION_GRACE;
+
+        let (mut engine, clock) = Engine::new_for_test();
+        let _client = engine.connect_mock().unwrap();
+        assert!(engine.connections_snapshot().is_empty());
+
+        engine.tick_once(Duration::from_millis(10));
+        let snapshot = engine.connections_snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert!(!snapshot[0].active);
+
+        engine.tick_once(Duration::from_millis(10));
+        assert!(!engine.connections_snapshot()[0].active);
+
+        clock.advance(NEGOTIATION_GRACE + Duration::from_secs(1));
+        engine.tick_once(Duration::from_millis(10));
+        assert!(engine.connections_snapshot()[0].active);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reload_updates_the_motd_and_opens_new_listeners() {
+        use crate::net::telnet::NEGOTIATION_GRACE;
+        use crate::testing::{temp_path, Client, Harness};
+
+        let mut h = Harness::with_game(GameConfig {motd: Some("Old news.".to_string()), ..Default::default()});
+
+        let path = temp_path("reload.json");
+        std::fs::write(&path, r#"{
+            "net": {"listeners": {"plain_telnet": "127.0.0.1:0", "tls_telnet": "127.0.0.1:0"}},
+            "game": {"motd": "Fresh news."}
+        }"#).unwrap();
+        let skipped = h.engine.reload_config(path.to_string_lossy()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(skipped.iter().any(|note| note.starts_with("tls_telnet listener")));
+        let listeners = h.engine.listeners_snapshot();
+        assert!(listeners.iter().any(|l| l.label.starts_with("plain-telnet:127.0.0.1:")));
+
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(3);
+        h.advance(NEGOTIATION_GRACE + Duration::from_secs(1));
+        let text = client.text();
+        assert!(text.contains("Fresh news."), "{:?}", text);
+        assert!(!text.contains("Old news."));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reload_applies_server_echo_to_new_connections() {
+        use crate::net::telnet::codes;
+        use crate::testing::{temp_path, Client, Harness};
+
+        let mut h = Harness::new();
+        let path = temp_path("reload.json");
+        std::fs::write(&path, r#"{"net": {"server_echo": true}}"#).unwrap();
+        let skipped = h.engine.reload_config(path.to_string_lossy()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(skipped.is_empty(), "{:?}", skipped);
+
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(3);
+        assert!(client.read_bytes().windows(3).any(|w| w == [codes::IAC, codes::WILL, codes::ECHO]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reload_does_not_wait_on_a_port_in_use() {
+        use crate::testing::{temp_path, Harness};
+
+        let mut h = Harness::new();
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = temp_path("reload.json");
+        std::fs::write(&path, format!(r#"{{"net": {{
+            "listeners": {{"plain_telnet": "{}"}},
+            "socket": {{"bind_attempts": 5, "bind_retry_ms": 2000}}
+        }}}}"#, taken.local_addr().unwrap())).unwrap();
+        let started = std::time::Instant::now();
+        let skipped = h.engine.reload_config(path.to_string_lossy()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(1), "reload took {:?}", started.elapsed());
+        assert!(skipped.iter().any(|note| note.starts_with("Could not open new plain_telnet listener")), "{:?}", skipped);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sighup_reloads_the_config_between_ticks() {
+        use crate::testing::temp_path;
+
+        let (mut engine, _clock) = Engine::new_for_test();
+        let path = temp_path("sighup.json");
+        std::fs::write(&path, r#"{"game": {"motd": "Reloaded."}}"#).unwrap();
+        engine.set_config_path(Some(path.clone()));
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, engine.reload_handle()).unwrap();
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+        assert!(engine.reload_handle().load(Ordering::SeqCst));
+        engine.handle_reload_request();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(!engine.reload_handle().load(Ordering::SeqCst));
+        let game = engine.resources.get::<GameConfig>().unwrap();
+        assert_eq!(game.motd.as_deref(), Some("Reloaded."));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_reload_request_reopens_a_rotated_audit_log() {
+        use crate::testing::{temp_path, Harness};
+
+        let path = temp_path("audit.log");
+        let rotated = path.with_extension("log.1");
+        let net = serde_json::from_value(serde_json::json!({"audit_log": path})).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        let _first = h.connect();
+        std::fs::rename(&path, &rotated).unwrap();
+
+        // What SIGHUP does. There's no config file to reload, but the log is reopened anyway.
+        h.engine.reload_handle().store(true, Ordering::SeqCst);
+        h.engine.handle_reload_request();
+        let _second = h.connect();
+
+        let old = std::fs::read_to_string(&rotated).unwrap();
+        let new = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(rotated).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(old.lines().count(), 1, "{}", old);
+        assert_eq!(new.lines().count(), 1, "{}", new);
+    }
+
+    #[test]
+    fn accepted_connections_have_nodelay_set() {
+        let (mut engine, _clock) = Engine::new_for_test();
+        engine.register_listener("127.0.0.1:0".parse().unwrap(), Protocol::Telnet, ConnType::Plain).unwrap();
+        let addr = engine.listeners_snapshot()[0].addr.unwrap();
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        for _ in 0..20 {
+            engine.tick_once(Duration::from_millis(10));
+            if !engine.connections_snapshot().is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(5));
+        }
+
+        let conn = <&ConnectionComponent>::query().iter(&engine.world).next().expect("connection was never accepted");
+        match &conn.transport {
+            TransportType::TCP(stream) => assert!(stream.nodelay().unwrap()),
+            _ => panic!("expected a plain TCP transport")
+        }
+    }
+
+    #[test]
+    fn tls_listener_without_a_config_refuses_connections() {
+        let (mut engine, _clock) = Engine::new_for_test();
+        engine.register_listener("127.0.0.1:0".parse().unwrap(), Protocol::Telnet, ConnType::TLS).unwrap();
+        let addr = engine.listeners_snapshot()[0].addr.unwrap();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        for _ in 0..5 {
+            engine.tick_once(Duration::from_millis(10));
+            sleep(Duration::from_millis(5));
+        }
+
+        assert!(engine.connections_snapshot().is_empty());
+        // Closed without a single cleartext byte.
+        let mut buf = [0u8; 64];
+        assert_eq!(client.read(&mut buf).unwrap_or(0), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_unix_socket_listener_speaks_telnet() {
+        use crate::net::telnet::NEGOTIATION_GRACE;
+        use crate::testing::{temp_path, Client, Harness};
+
+        let path = temp_path("listener.sock");
+        let mut h = Harness::new();
+        h.engine.register_unix_listener(&path, Protocol::Telnet).unwrap();
+
+        let stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(3);
+        h.advance(NEGOTIATION_GRACE + Duration::from_secs(1));
+        client.read();
+        assert!(h.command(&mut client, "create bob=secret").contains("Welcome, bob!"));
+
+        drop(h);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_stop_request_ends_run_and_removes_the_socket_file() {
+        use crate::testing::temp_path;
+
+        let path = temp_path("stop.sock");
+        let (mut engine, _clock) = Engine::new_for_test();
+        engine.register_unix_listener(&path, Protocol::Telnet).unwrap();
+        assert!(path.exists());
+
+        engine.stop_handle().store(true, Ordering::SeqCst);
+        engine.run();
+        assert!(engine.listeners_snapshot().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listeners_start_their_own_telnet_options() {
+        use crate::net::telnet::codes as tc;
+        use crate::testing::{temp_path, Client, Harness};
+
+        let net = serde_json::from_str(r#"{"telnet_options": {"unix": {"GMCP": {"start_local": false}}}}"#).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        let path = temp_path("options.sock");
+        h.engine.register_listener("127.0.0.1:0".parse().unwrap(), Protocol::Telnet, ConnType::Plain).unwrap();
+        h.engine.register_unix_listener(&path, Protocol::Telnet).unwrap();
+
+        let addr = h.engine.listeners_snapshot().iter().find_map(|l| l.addr).unwrap();
+        let mut tcp = std::net::TcpStream::connect(addr).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut local = Client {stream};
+        h.tick(5);
+
+        let mut plain = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Ok(n) = tcp.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            plain.extend_from_slice(&buf[..n]);
+        }
+        let local = local.read_bytes();
+        let will_gmcp = [tc::IAC, tc::WILL, tc::GMCP];
+        let will_msdp = [tc::IAC, tc::WILL, tc::MSDP];
+        assert!(plain.windows(3).any(|w| w == will_gmcp), "{:?}", plain);
+        assert!(!local.windows(3).any(|w| w == will_gmcp), "{:?}", local);
+        assert!(local.windows(3).any(|w| w == will_msdp));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_connection_snapshot_shows_negotiated_options() {
+        use crate::net::telnet::codes::{self as tc, TelnetOptionCode};
+        use crate::testing::{Client, Harness};
+
+        let mut h = Harness::new();
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(3);
+        client.write(&[tc::IAC, tc::WILL, tc::NAWS]);
+        h.tick(3);
+
+        let snapshot = h.engine.connections_snapshot();
+        let naws = snapshot[0].telnet_options.iter().find(|o| o.code == TelnetOptionCode::Naws).unwrap();
+        assert!(naws.configured.allow_remote);
+        assert!(naws.state.remote.enabled);
+        assert!(!naws.state.local.enabled);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ready_listeners_take_turns_accepting() {
+        use crate::testing::temp_path;
+
+        let (mut engine, _clock) = Engine::new_for_test();
+        let path = temp_path("fair.sock");
+        engine.register_listener("127.0.0.1:0".parse().unwrap(), Protocol::Telnet, ConnType::Plain).unwrap();
+        engine.register_unix_listener(&path, Protocol::Telnet).unwrap();
+        let addr = engine.listeners_snapshot().iter().find_map(|l| l.addr).unwrap();
+
+        // Unix peers show up with port 0, so the port tells the two listeners apart.
+        let mut clients = Vec::new();
+        let mut batch = |engine: &mut Engine, n: usize| -> Vec<bool> {
+            for _ in 0..n {
+                clients.push((Some(std::net::TcpStream::connect(addr).unwrap()), None));
+                clients.push((None, Some(std::os::unix::net::UnixStream::connect(&path).unwrap())));
+            }
+            let before = <&ConnectionComponent>::query().iter(&engine.world).count();
+            engine.tick_once(Duration::from_millis(10));
+            let mut accepted: Vec<(usize, bool)> = <&ConnectionComponent>::query().iter(&engine.world)
+                .map(|conn| (conn.token.0, conn.addr.port() == 0))
+                .collect();
+            accepted.sort();
+            accepted.into_iter().skip(before).map(|(_, unix)| unix).collect()
+        };
+
+        let first = batch(&mut engine, 3);
+        assert_eq!(first.len(), 6);
+        assert!(first.windows(2).all(|w| w[0] != w[1]), "{:?}", first);
+        let second = batch(&mut engine, 1);
+        assert_eq!(second.len(), 2);
+        assert_ne!(second[0], first[0], "the same listener went first twice");
+    }
+}
@@ -1,13 +1,18 @@
 use crate::net::{PollHandler, Protocol, ConnType, TransportType};
+use crate::net::error::NetError;
 use mio::net::TcpStream;
 use std::net::SocketAddr;
 use mio::{Token};
 use std::io::{Result, Error};
 use std::cmp::max;
-use crate::net::telnet::{TelnetOption};
+use crate::net::telnet::{TelnetOption, DEFAULT_STRIP_CHARS};
 use crate::net::telnet::codes as tc;
+use crate::net::telnet::codes::TelnetOptionCode;
+use crate::config::{TelnetOptionConfig, SuspicionConfig, PacingConfig, LineEnding, NetConfig, OobPreference};
 use std::collections::{HashMap};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 pub struct TelnetOptions(pub Arc<HashMap<u8, TelnetOption>>);
 
@@ -15,6 +20,7 @@ impl Default for TelnetOptions {
     fn default() -> Self {
         let mut map: HashMap<u8, TelnetOption> = Default::default();
 
+        map.insert(tc::ECHO, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: false});
         map.insert(tc::SGA, TelnetOption {allow_local: true, allow_remote: true, start_remote: false, start_local: true});
         map.insert(tc::NAWS, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
         map.insert(tc::MTTS, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
@@ -25,12 +31,145 @@ impl Default for TelnetOptions {
         map.insert(tc::GMCP, TelnetOption {allow_local: true, allow_remote: true, start_remote: false, start_local: true});
         map.insert(tc::MSDP, TelnetOption {allow_local: true, allow_remote: true, start_remote: false, start_local: true});
         map.insert(tc::LINEMODE, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
+        map.insert(tc::NEW_ENVIRON, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
+        map.insert(tc::ENVIRON, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
         map.insert(tc::TELOPT_EOR, TelnetOption {allow_local: true, allow_remote: true, start_remote: false, start_local: true});
 
         Self(Arc::new(map))
     }
 }
 
+impl TelnetOptions {
+    // The defaults, with ECHO offered up front when config asks the server to echo.
+    pub fn from_config(net: Option<&NetConfig>) -> Self {
+        let mut options = Self::default();
+        if net.and_then(|n| n.server_echo).unwrap_or(false) {
+            options.set_start_local(tc::ECHO, true);
+        }
+        options
+    }
+
+    // A copy with changes from config applied. Options are named as in TelnetOptionCode;
+    // names we don't know are skipped with a warning.
+    pub fn with_changes(&self, changes: &HashMap<String, TelnetOptionConfig>) -> Self {
+        let mut map = (*self.0).clone();
+        for (name, change) in changes.iter() {
+            let code = match TelnetOptionCode::from_name(name) {
+                Some(code) => u8::from(code),
+                None => {
+                    println!("Unknown telnet option in config: {}", name);
+                    continue;
+                }
+            };
+            let opt = map.entry(code).or_default();
+            if let Some(v) = change.allow_local { opt.allow_local = v; }
+            if let Some(v) = change.allow_remote { opt.allow_remote = v; }
+            if let Some(v) = change.start_local { opt.start_local = v; }
+            if let Some(v) = change.start_remote { opt.start_remote = v; }
+        }
+        Self(Arc::new(map))
+    }
+
+    // Offers op to every new connection, or stops doing so.
+    pub fn set_start_local(&mut self, op: u8, start: bool) {
+        if let Some(opt) = Arc::make_mut(&mut self.0).get_mut(&op) {
+            opt.start_local = start;
+        }
+    }
+}
+
+// When set, input from a connection is held until its negotiation finishes, so an autologin
+// sent straight away is handled with the client's real capabilities.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct InputGate(pub bool);
+
+// Config copied onto every new telnet connection. WebSocket connections get strip_chars too.
+#[derive(Clone, Debug)]
+pub struct TelnetSettings {
+    pub line_ending: LineEnding,
+    pub strip_chars: Arc<Vec<char>>,
+    pub freeze_capabilities: bool,
+    pub oob_preference: OobPreference
+}
+
+impl TelnetSettings {
+    pub fn from_config(net: Option<&NetConfig>) -> Self {
+        Self {
+            line_ending: net.and_then(|n| n.line_ending).unwrap_or_default(),
+            strip_chars: Arc::new(net.and_then(|n| n.strip_input_chars.clone())
+                .unwrap_or_else(|| DEFAULT_STRIP_CHARS.to_vec())),
+            freeze_capabilities: net.and_then(|n| n.freeze_capabilities).unwrap_or(false),
+            oob_preference: net.and_then(|n| n.oob_preference).unwrap_or_default()
+        }
+    }
+}
+
+// How long a GMCP client may be quiet before it's pinged. See ProtocolComponent::check_ping.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct GmcpPing(pub Option<Duration>);
+
+// How misbehaving connections are scored, if they are. See net::suspicion.
+#[derive(Default, Clone, Debug)]
+pub struct SuspicionPolicy(pub Option<SuspicionConfig>);
+
+// Output pacing for connections that turned it on, if any. See ProtocolComponent::send_paced.
+#[derive(Default, Clone, Debug)]
+pub struct OutputPacing(pub Option<PacingConfig>);
+
+// The shared secret control connections authenticate with. None refuses them all.
+#[derive(Default, Clone, Debug)]
+pub struct ControlToken(pub Option<String>);
+
+// Shared with the engine's run loop, which reloads the config between ticks when it's set.
+#[derive(Default, Clone, Debug)]
+pub struct ReloadRequest(pub Arc<AtomicBool>);
+
+impl ReloadRequest {
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// While set, new connections are turned away with a maintenance message. Existing
+// connections are unaffected.
+#[derive(Default, Clone, Debug)]
+pub struct Draining(pub Arc<AtomicBool>);
+
+impl Draining {
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, draining: bool) {
+        self.0.store(draining, Ordering::SeqCst);
+    }
+}
+
+// Caps how long command processing may run each tick so network I/O isn't starved.
+// Processes that don't get a turn stay queued for the next tick, and one part way through
+// its steps carries on from there.
+#[derive(Debug)]
+pub struct ProcessBudget {
+    pub limit: Duration,
+    pub tick_started: Option<Instant>
+}
+
+impl ProcessBudget {
+    pub fn new(limit: Duration) -> Self {
+        Self {
+            limit,
+            tick_started: None
+        }
+    }
+
+    pub fn exhausted(&self, now: Instant) -> bool {
+        match self.tick_started {
+            Some(started) => now.duration_since(started) >= self.limit,
+            None => false
+        }
+    }
+}
+
 pub struct ConnPoll {
     pub handler: PollHandler,
     pub write_ready: Vec<Token>,
@@ -49,12 +188,11 @@ impl ConnPoll {
         }
     }
 
-    pub fn poll(&mut self) -> usize {
-        if self.handler.poll().is_err() {
-            panic!("Something went wrong with connection polling!")
-        }
+    // On error nothing is ready, and the caller decides whether to carry on.
+    pub fn poll(&mut self) -> std::result::Result<usize, NetError> {
         self.write_ready.clear();
         self.read_ready.clear();
+        self.handler.poll()?;
 
         for event in self.handler.events.iter() {
             let key = event.token();
@@ -66,7 +204,7 @@ impl ConnPoll {
                 self.write_ready.push(key);
             }
         }
-        max(self.write_ready.len(), self.read_ready.len())
+        Ok(max(self.write_ready.len(), self.read_ready.len()))
     }
 
     pub fn get_next(&mut self) -> Token {
@@ -81,7 +219,9 @@ pub struct ListenPoll {
     pub handler: PollHandler,
     pub conns: Vec<(TcpStream, SocketAddr, Protocol, ConnType)>,
     pub accept_ready: Vec<Token>,
-    pub next: usize
+    pub next: usize,
+    // Bumped every tick any listener is ready, to pick which of them goes first.
+    pub rotation: usize
 }
 
 impl ListenPoll {
@@ -90,7 +230,8 @@ impl ListenPoll {
             handler,
             conns: Default::default(),
             accept_ready: Default::default(),
-            next: 0
+            next: 0,
+            rotation: 0
         }
     }
 
@@ -99,11 +240,9 @@ impl ListenPoll {
         Token(self.next)
     }
 
-    pub fn poll(&mut self) -> usize {
-        if self.handler.poll().is_err() {
-            panic!("Something went wrong with listen polling!")
-        }
+    pub fn poll(&mut self) -> std::result::Result<usize, NetError> {
         self.accept_ready.clear();
+        self.handler.poll()?;
 
         for event in self.handler.events.iter() {
             let key = event.token();
@@ -111,6 +250,39 @@ impl ListenPoll {
                 self.accept_ready.push(key);
             }
         }
-        self.accept_ready.len()
+        Ok(self.accept_ready.len())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use mio::Interest;
+    use mio::net::UnixStream;
+    use std::collections::HashSet;
+    use std::io::Write;
+
+    #[test]
+    fn every_ready_connection_is_seen_when_the_buffer_overflows() {
+        let mut handler = PollHandler::new(8, Some((0, 0))).unwrap();
+        handler.set_max_capacity(64);
+        let mut poll = ConnPoll::new(handler);
+
+        let mut pairs = Vec::new();
+        for _ in 0..200 {
+            let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+            let tok = poll.get_next();
+            poll.handler.poller.registry().register(&mut ours, tok, Interest::READABLE).unwrap();
+            theirs.write_all(b"look\n").unwrap();
+            pairs.push((ours, theirs));
+        }
+
+        let mut seen = HashSet::new();
+        for _ in 0..10 {
+            poll.poll().unwrap();
+            seen.extend(poll.read_ready.iter().cloned());
+        }
+        assert_eq!(seen.len(), 200);
+        assert_eq!(poll.handler.events.capacity(), 64);
     }
 }
@@ -1,69 +1,163 @@
 use legion::*;
 use crate::engine::Delta;
-use crate::engine::resources::{ListenPoll, ConnPoll, TelnetOptions};
+use crate::engine::resources::{ListenPoll, ConnPoll, TelnetOptions, Draining, ProcessBudget, InputGate,
+                               ControlToken, ReloadRequest, GmcpPing, SuspicionPolicy, OutputPacing, TelnetSettings};
 use crate::net::{ConnectionComponent, ListenerComponent, TransportType, ProtocolStatus,
                  Protocol, ConnType, ConnectionStatus, ProtocolComponent, ProtocolType,
-                 ProtocolEvent, ProtocolOutEvent};
+                 ProtocolEvent, ProtocolOutEvent, FlushAction, NetError, MAX_PENDING_INPUT, MAX_ZERO_WRITES, WriteStop,
+                 batch_oob, configure_stream, write_buffered};
 use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
 use legion::systems::CommandBuffer;
 use mio::{Events, Poll, Token, Interest};
 use mio::net::TcpStream;
-use bytes::Buf;
 use std::time::{Duration, Instant};
 use crate::game::objects::{MudSession};
-use std::collections::{VecDeque, HashSet, HashMap};
-use crate::game::process::ProcessComponent;
+use std::collections::{HashSet, HashMap};
+use crate::game::process::{ProcessComponent, StepResult};
 use crate::game::login_cmds::{LoginCommands};
-use crate::game::resources::{ProcessCounter, ProcessIndex, PendingUserCreations};
+use crate::game::control_cmds::{ControlCommands, ControlContext, response};
+use crate::game::game_cmds::{GameCommands, GameContext, is_clear_queue};
+use crate::game::objects::{UserComponent, MudObjectComponent, VitalsComponent, MudProtoTypeComponent, LocationComponent,
+                           LocationType, ContentsComponent, ExitsComponent, MudObjectSession, DescriptionComponent,
+                           ModuleComponent, AccountChanged};
+use ring::constant_time::verify_slices_are_equal;
+use crate::game::accounts::{save_accounts, PasswordJob, PasswordResult, PasswordWorker};
+use crate::game::location::{location_of, add_to_contents, remove_from_location};
+use legion::world::SubWorld;
+use crate::game::resources::{ProcessCounter, ProcessIndex, PendingUserCreations, PendingUserLogins, AccountCreations,
+                             UsersOnline, MudSessions, BootTime, Banner};
+use crate::engine::clock::EngineClock;
+use crate::net::filter::apply_filters;
+use crate::net::audit::AuditLog;
+use crate::game::input::oob_commands;
+use crate::config::{CapabilityOverrides, DebugConfig, GameConfig, OobBatching};
+use crate::mudstring::text::Text;
+use crate::mudstring::ansi::strip_control;
 
 #[system]
 pub fn poll_listeners(#[resource] lis_poll: &mut ListenPoll) {
-    lis_poll.poll();
+    // Usually EINTR; whatever it was, the next tick polls again.
+    if let Err(e) = lis_poll.poll() {
+        println!("Listener poll failed: {}", e);
+    }
 }
 
-#[system(for_each)]
-pub fn accept_new_connections(cmd: &mut CommandBuffer, lis: &mut ListenerComponent, #[resource] lis_poll: &mut ListenPoll, #[resource] con_poll: &mut ConnPoll, #[resource] tel_opts: &TelnetOptions) {
-    if !lis_poll.accept_ready.contains(&lis.token) {
-        return
-    }
+// Shared state for turning accepted sockets into connection entities.
+struct Admission<'a> {
+    con_poll: &'a mut ConnPoll,
+    tel_opts: &'a TelnetOptions,
+    clock: &'a EngineClock,
+    overrides: &'a CapabilityOverrides,
+    draining: &'a Draining,
+    debug: &'a DebugConfig,
+    telnet_settings: &'a TelnetSettings
+}
 
-    loop {
-        match lis.listener.accept() {
-            Ok((mut t, a)) => {
-                let tok = con_poll.get_next();
-                if let Err(e) = con_poll.handler.poller.registry().register(&mut t, tok.clone(), Interest::READABLE | Interest::WRITABLE) {
-                    panic!("Something going wrong with conn poll!");
-                } else {
-                    let mut conn = match lis.ctype {
-                        ConnType::Plain => ConnectionComponent::new(t, a, lis.protocol.clone(), tok, None),
-                        ConnType::TLS => ConnectionComponent::new(t, a, lis.protocol.clone(), tok, None)
-                    };
-                    let mut prot = match lis.protocol {
-                        Protocol::Telnet => ProtocolComponent::telnet(tel_opts.0.clone()),
-                        Protocol::WebSocket => ProtocolComponent::websocket(),
-                        Protocol::SSH => ProtocolComponent::ssh()
-                    };
-                    prot.start(&mut conn);
-                    cmd.push((conn, prot));
-                }
+impl<'a> Admission<'a> {
+    fn admit(&mut self, cmd: &mut CommandBuffer, lis: &ListenerComponent, mut t: TransportType, a: SocketAddr) {
+        // Operators still need the control port to see the drain through.
+        if self.draining.is_set() && !matches!(lis.protocol, Protocol::Control) {
+            println!("{} refused connection from {}: draining", lis.label, a);
+            // Anything else would get a bare text line where it expects a TLS handshake or an
+            // HTTP response, so those are just closed.
+            if matches!((&lis.ctype, &lis.protocol), (ConnType::Plain, Protocol::Telnet)) {
+                let _ = t.write_all(b"The server is undergoing maintenance and is not accepting new connections. Please try again later.\r\n");
+            }
+            return;
+        }
+        // Never fall back to cleartext on a port clients expect to be encrypted.
+        if matches!(lis.ctype, ConnType::TLS) && lis.tls.is_none() {
+            println!("{} refused connection from {}: no TLS configuration loaded", lis.label, a);
+            return;
+        }
+        println!("{} accepted connection from {}", lis.label, a);
+        if let TransportType::TCP(stream) = &t {
+            if let Err(e) = configure_stream(stream, &lis.socket) {
+                println!("{} could not set socket options for {}: {}", lis.label, a, e);
+            }
+        }
+        let tok = self.con_poll.get_next();
+        // Dropping the socket closes it; one bad registration shouldn't take the server down.
+        if let Err(e) = self.con_poll.handler.poller.registry().register(t.socket_mut(), tok, Interest::READABLE | Interest::WRITABLE) {
+            println!("{} could not register connection from {}: {}", lis.label, a, e);
+            return;
+        }
+        let mut conn = match lis.ctype {
+            ConnType::Plain => ConnectionComponent::new(t, a, lis.protocol.clone(), tok, None),
+            ConnType::TLS => ConnectionComponent::new(t, a, lis.protocol.clone(), tok, lis.tls.clone())
+        };
+        conn.listener = lis.label.clone();
+        let mut prot = match lis.protocol {
+            Protocol::Telnet => {
+                let options = lis.telnet_options.clone().unwrap_or_else(|| self.tel_opts.0.clone());
+                ProtocolComponent::telnet(options, self.clock.now())
             },
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::WouldBlock => {
-                        break;
-                    },
-                    _ => {
-                        break;
-                    }
-                }
+            Protocol::WebSocket => ProtocolComponent::websocket(lis.websocket.clone(), self.clock.now()),
+            Protocol::SSH => ProtocolComponent::ssh(self.clock.now()),
+            Protocol::Control => ProtocolComponent::control(self.clock.now())
+        };
+        if let ProtocolType::Telnet(telnet) = &mut prot.ptype {
+            telnet.line_ending = self.telnet_settings.line_ending;
+            telnet.strip_chars = self.telnet_settings.strip_chars.clone();
+            telnet.freeze_when_active = self.telnet_settings.freeze_capabilities;
+            telnet.oob_preference = self.telnet_settings.oob_preference;
+            if self.debug.trace_negotiation {
+                telnet.enable_trace(self.clock.now());
             }
         }
+        if let ProtocolType::WebSocket(ws) = &mut prot.ptype {
+            ws.strip_chars = self.telnet_settings.strip_chars.clone();
+        }
+        if let Some(color) = self.overrides.default_color(&lis.protocol) {
+            prot.capabilities.color = Some(color);
+        }
+        prot.start(&mut conn, self.clock.now());
+        prot.capabilities.apply_overrides(self.overrides);
+        cmd.push((conn, prot));
+    }
+}
+
+// Takes one connection at a time from each ready listener in turn until they all would
+// block, so one busy port can't make the others wait. Which listener goes first rotates
+// every tick.
+#[system]
+#[write_component(ListenerComponent)]
+#[allow(clippy::too_many_arguments)]
+pub fn accept_new_connections(world: &mut SubWorld, cmd: &mut CommandBuffer, #[resource] lis_poll: &mut ListenPoll, #[resource] con_poll: &mut ConnPoll, #[resource] tel_opts: &TelnetOptions,
+                           #[resource] clock: &EngineClock, #[resource] overrides: &CapabilityOverrides,
+                           #[resource] draining: &Draining, #[resource] debug: &DebugConfig,
+                           #[resource] telnet_settings: &TelnetSettings) {
+    let mut ready: Vec<&mut ListenerComponent> = <&mut ListenerComponent>::query().iter_mut(world)
+        .filter(|lis| lis_poll.accept_ready.contains(&lis.token))
+        .collect();
+    if ready.is_empty() {
+        return
+    }
+    ready.sort_by_key(|lis| lis.token.0);
+    let first = lis_poll.rotation % ready.len();
+    ready.rotate_left(first);
+    lis_poll.rotation = lis_poll.rotation.wrapping_add(1);
+
+    let mut admission = Admission {con_poll, tel_opts, clock, overrides, draining, debug, telnet_settings};
+    while !ready.is_empty() {
+        // mio only reports readiness again once a listener has hit WouldBlock, so each one
+        // stays in the rotation until it does.
+        ready.retain_mut(|lis| match lis.listener.accept() {
+            Ok((t, a)) => {
+                admission.admit(cmd, lis, t, a);
+                true
+            },
+            Err(_) => false
+        });
     }
 }
 
 #[system]
 pub fn poll_connections(#[resource] conn_poll: &mut ConnPoll) {
-    conn_poll.poll();
+    if let Err(e) = conn_poll.poll() {
+        println!("Connection poll failed: {}", e);
+    }
 }
 
 #[system(for_each)]
@@ -107,10 +201,47 @@ pub fn process_connection_read(ent: &Entity, conn: &mut ConnectionComponent,
     }
 }
 
+// OOB messages from configured command packages become the lines they carry, in place, so
+// flood scoring, login cooldowns and the session's queue treat them like typed input.
+fn bridge_oob_commands(prot: &mut ProtocolComponent, first_new: usize, packages: &[String]) {
+    let new: Vec<ProtocolEvent> = prot.in_buffer.drain(first_new..).collect();
+    for ev in new {
+        match ev {
+            ProtocolEvent::OOB(package, args, kwargs) if packages.iter().any(|p| p.eq_ignore_ascii_case(&package)) => {
+                prot.in_buffer.extend(oob_commands(packages, &package, &args, &kwargs).into_iter().map(ProtocolEvent::Line));
+            },
+            ev => prot.in_buffer.push_back(ev)
+        }
+    }
+}
+
 #[system(par_for_each)]
-pub fn process_connection_newdata(conn: &mut ConnectionComponent, prot: &mut ProtocolComponent) {
+pub fn process_connection_newdata(conn: &mut ConnectionComponent, prot: &mut ProtocolComponent,
+                                  #[resource] overrides: &CapabilityOverrides, #[resource] clock: &EngineClock,
+                                  #[resource] policy: &SuspicionPolicy, #[resource] game: &GameConfig) {
     if conn.new_data {
-        prot.process_new_data(conn);
+        let now = clock.now();
+        // A throttled connection's input waits in read_buff until it may be read again.
+        if prot.suspicion.next_read.map(|t| t > now).unwrap_or(false) {
+            if conn.read_buff.len() > MAX_PENDING_INPUT {
+                println!("Dropping throttled connection from {}: too much input waiting", conn.addr);
+                conn.status = ConnectionStatus::ClientError(NetError::BufferOverflow(MAX_PENDING_INPUT).into());
+            }
+            return;
+        }
+        let first_new = prot.in_buffer.len();
+        if let Err(e) = prot.process_new_data(conn, now) {
+            println!("Dropping connection from {}: {}", conn.addr, e);
+            conn.status = ConnectionStatus::ClientError(e.into());
+        }
+        bridge_oob_commands(prot, first_new, &game.oob_command_packages);
+        if let Some(config) = &policy.0 {
+            prot.review_conduct(conn, first_new, now, config);
+        }
+        prot.capabilities.apply_overrides(overrides);
+        if let Some(profile) = prot.profile {
+            profile.apply(&mut prot.capabilities);
+        }
         conn.new_data = false;
     }
 }
@@ -126,19 +257,24 @@ pub fn process_connection_outgoing(conn: &mut ConnectionComponent, #[resource] c
         return;
     }
 
-    while !conn.write_buff.is_empty() {
-        match conn.transport.write(conn.write_buff.as_ref()) {
-            Ok(len) => {
-                conn.write_buff.advance(len);
-            },
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::WouldBlock => {
-                        conn.write_ready = false;
-                        break;
-                    },
-                    _ => {
-                        break;
+    // Anything rustls buffered on a previous tick has to go out before new data.
+    if !flush_transport(conn) {
+        return;
+    }
+
+    let stop = write_buffered(&mut conn.transport, &mut conn.write_buff);
+    note_write(conn, stop);
+
+    if conn.write_ready {
+        flush_transport(conn);
+    }
+
+    if conn.take_flushed() {
+        for action in std::mem::take(&mut conn.on_flush) {
+            match action {
+                FlushAction::Close => {
+                    if conn.is_active() {
+                        conn.status = ConnectionStatus::ServerClosed;
                     }
                 }
             }
@@ -146,18 +282,166 @@ pub fn process_connection_outgoing(conn: &mut ConnectionComponent, #[resource] c
     }
 }
 
+// Updates conn after a pass of write_buffered, dropping it once its transport has taken
+// nothing for MAX_ZERO_WRITES passes in a row.
+fn note_write(conn: &mut ConnectionComponent, stop: std::io::Result<WriteStop>) {
+    match stop {
+        Ok(WriteStop::ZeroWrite) => {
+            // Leave the rest for the next tick, unless this keeps happening.
+            conn.zero_writes += 1;
+            if conn.zero_writes >= MAX_ZERO_WRITES && conn.is_active() {
+                println!("Dropping connection from {}: its transport stopped taking data", conn.addr);
+                conn.status = ConnectionStatus::ClientError(Error::new(ErrorKind::WriteZero, "transport stopped taking data"));
+            }
+        },
+        Ok(WriteStop::WouldBlock) => {
+            conn.write_ready = false;
+            conn.zero_writes = 0;
+        },
+        Ok(WriteStop::Drained) => conn.zero_writes = 0,
+        Err(_) => {}
+    }
+}
+
+// Pushes out ciphertext rustls is still holding. A real error marks the connection broken,
+// so the reaper closes it.
+fn flush_transport(conn: &mut ConnectionComponent) -> bool {
+    match conn.transport.flush_pending() {
+        Ok(done) => done,
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+            conn.write_ready = false;
+            false
+        },
+        Err(e) => {
+            if conn.is_active() {
+                println!("Dropping connection from {}: {}", conn.addr, e);
+                conn.status = ConnectionStatus::ClientError(e);
+            }
+            false
+        }
+    }
+}
+
+// Takes closed connections out of their sessions, and ends any session that is left with
+// none, so UsersOnline and MudSessions only ever list people who are really here.
+#[system]
+#[read_component(ConnectionComponent)]
+#[read_component(ProtocolComponent)]
+#[read_component(LocationComponent)]
+#[write_component(ContentsComponent)]
+#[write_component(MudSession)]
+pub fn detach_closed_connections(world: &mut SubWorld, cmd: &mut CommandBuffer, #[resource] online: &mut UsersOnline,
+                                 #[resource] sessions: &mut MudSessions) {
+    let closed: Vec<(Entity, Entity)> = <(Entity, &ConnectionComponent, &ProtocolComponent)>::query().iter(world)
+        .filter(|(_, conn, _)| !conn.is_active())
+        .filter_map(|(ent, _, prot)| prot.session.map(|sess| (*ent, sess)))
+        .collect();
+
+    for (conn, session) in closed {
+        if let Ok(mut entry) = world.entry_mut(session) {
+            if let Ok(msess) = entry.get_component_mut::<MudSession>() {
+                msess.connections.remove(&conn);
+            }
+        }
+    }
+
+    let ended: Vec<(Entity, Entity, Entity)> = <(Entity, &MudSession)>::query().iter(world)
+        .filter(|(_, msess)| msess.connections.is_empty())
+        .map(|(ent, msess)| (*ent, msess.user, msess.puppet))
+        .collect();
+
+    for (session, user, puppet) in ended {
+        if sessions.0.get(&user) == Some(&session) {
+            sessions.0.remove(&user);
+            online.0.remove(&user);
+        }
+        // Nobody is playing the puppet any more, so it leaves the room until they return.
+        remove_from_location(world, puppet);
+        cmd.remove_component::<MudObjectSession>(puppet);
+        cmd.remove(session);
+    }
+}
+
+// How long a server-closed connection may keep flushing its write buffer before it's dropped.
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Runs before anything can reap a new connection, so every disconnect in the audit log has
+// a connect before it.
+#[system(for_each)]
+pub fn audit_new_connections(conn: &mut ConnectionComponent, #[resource] audit: &mut AuditLog, #[resource] clock: &EngineClock) {
+    if !conn.audited {
+        conn.audited = true;
+        audit.connect(conn, clock.utc_now());
+    }
+}
+
+#[system(for_each)]
+pub fn reap_connections(ent: &Entity, conn: &mut ConnectionComponent, prot: Option<&ProtocolComponent>, cmd: &mut CommandBuffer,
+                        #[resource] con_poll: &mut ConnPoll, #[resource] clock: &EngineClock, #[resource] audit: &mut AuditLog) {
+    if conn.is_active() {
+        return;
+    }
+    let now = clock.now();
+    let closed_at = *conn.closed_at.get_or_insert(now);
+
+    if let ConnectionStatus::ServerClosed = conn.status {
+        let flushing = now.duration_since(closed_at) < CLOSE_FLUSH_TIMEOUT;
+        // Give our final words a chance to reach the client.
+        if !conn.is_drained() && flushing {
+            return;
+        }
+        // Then end the TLS session properly, or clients report the stream as truncated.
+        // process_connection_outgoing keeps flushing it like any other pending ciphertext.
+        if conn.begin_close_notify() {
+            let _ = conn.transport.flush_pending();
+        }
+        if !conn.is_drained() && flushing {
+            return;
+        }
+    } else if conn.begin_close_notify() {
+        // The client went first. Answer in kind if the socket will still take it.
+        let _ = conn.transport.flush_pending();
+    }
+
+    println!("Closing connection from {}: {:?}", conn.addr, conn.status);
+    audit.disconnect(conn, prot, now, clock.utc_now());
+    let _ = con_poll.handler.poller.registry().deregister(conn.transport.socket_mut());
+    cmd.remove(*ent);
+}
+
 #[system(par_for_each)]
-pub fn connection_health_check(conn: &mut ConnectionComponent, prot: &mut ProtocolComponent) {
-    prot.health_check(conn);
+pub fn connection_health_check(conn: &mut ConnectionComponent, prot: &mut ProtocolComponent, #[resource] clock: &EngineClock,
+                               #[resource] game: &GameConfig, #[resource] banner: &Banner, #[resource] ping: &GmcpPing) {
+    if prot.health_check(conn, clock.now()) {
+        if let Some(art) = banner.for_client(&prot.capabilities) {
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(art.as_str())));
+        }
+        if let Some(greeting) = &game.greeting {
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(greeting.as_str())));
+        }
+        if let Some(motd) = &game.motd {
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(motd.as_str())));
+        }
+    }
+    prot.check_idle(clock.now(), game.idle_warn_secs.map(Duration::from_secs), game.idle_timeout_secs.map(Duration::from_secs));
+    prot.check_ping(conn, clock.now(), ping.0);
 }
 
 
 #[system(for_each)]
-pub fn execute_connection_events(ent: &Entity, conn: &mut ConnectionComponent, prot: &mut ProtocolComponent, #[resource] lcmds: &mut LoginCommands) {
-    if prot.session.is_some() {
+#[allow(clippy::too_many_arguments)]
+pub fn execute_connection_events(ent: &Entity, prot: &mut ProtocolComponent, #[resource] lcmds: &mut LoginCommands,
+                                 #[resource] gate: &InputGate, #[resource] game: &GameConfig,
+                                 #[resource] creations: &mut PendingUserCreations, #[resource] logins: &mut PendingUserLogins,
+                                 #[resource] online: &UsersOnline, #[resource] boot: &BootTime, #[resource] clock: &EngineClock) {
+    if prot.session.is_some() || prot.is_control() {
+        return
+    }
+    if gate.0 && !prot.is_active() {
         return
     }
 
+    let cooldown = Duration::from_millis(game.login_cooldown_ms);
     if let Some(ev) = prot.in_buffer.pop_front() {
 
         match ev {
@@ -166,69 +450,420 @@ pub fn execute_connection_events(ent: &Entity, conn: &mut ConnectionComponent, p
                     println!("This should not happen yet!");
                     // TODO: this will call the function for running a menu screen command.
                 } else {
-                    lcmds.execute(prot, s);
+                    lcmds.execute(prot, s, clock.now(), cooldown);
                 }
-            },
-            ProtocolEvent::OOB(cmd, args, kwargs) => {
-
             },
             ProtocolEvent::RequestMSSP => {
-                // TODO: This should render MSSP data to a HashMap and push it to msess.out_events
+                prot.out_buffer.push_back(ProtocolOutEvent::MSSP(mssp_variables(game, online, boot)));
             },
             ProtocolEvent::CreateUser(user, pass) => {
-
+                creations.0.push_back((*ent, user, pass));
             },
             ProtocolEvent::Login(user, pass) => {
+                logins.0.push_back((*ent, user, pass));
+            },
+            ProtocolEvent::Ready(caps) => {
+                println!("Connection ready: {} {} ({:?} color, {}x{})", caps.client_name, caps.client_version,
+                         caps.color, caps.width, caps.height);
+            },
+            // Nothing on the login screen wraps to the window, and OOB that wasn't a command
+            // has nothing to act on yet.
+            ProtocolEvent::Resize(..) | ProtocolEvent::OOB(..) => {}
+        }
+    }
+}
+
+// Runs one line per control connection per tick. Until a connection has authenticated,
+// the only thing it may do is "auth <token>"; a wrong token closes it.
+#[system]
+#[read_component(UserComponent)]
+#[read_component(LocationComponent)]
+#[read_component(MudObjectComponent)]
+#[read_component(MudProtoTypeComponent)]
+#[write_component(MudSession)]
+#[write_component(ProtocolComponent)]
+pub fn execute_control_events(world: &mut SubWorld, #[resource] ccmds: &ControlCommands, #[resource] token: &ControlToken,
+                              #[resource] reload: &ReloadRequest, #[resource] clock: &EngineClock, #[resource] game: &GameConfig) {
+    let mut pending: Vec<(Entity, bool, String)> = Vec::new();
+    for (ent, prot) in <(Entity, &mut ProtocolComponent)>::query().iter_mut(world) {
+        if let ProtocolType::Control { authenticated } = prot.ptype {
+            if let Some(ProtocolEvent::Line(line)) = prot.in_buffer.pop_front() {
+                pending.push((*ent, authenticated, line));
+            }
+        }
+    }
 
+    for (ent, authenticated, line) in pending {
+        let mut close = false;
+        let reply = if authenticated {
+            let mut ctx = ControlContext {world, game, now: clock.now(), reload_requested: false};
+            let reply = ccmds.execute(&mut ctx, &line);
+            if ctx.reload_requested {
+                reload.request();
+            }
+            reply
+        } else {
+            let given = line.strip_prefix("auth ").map(|t| t.trim());
+            // Compared in constant time, so response timing doesn't leak how much was right.
+            let ok = matches!((given, &token.0), (Some(given), Some(expected))
+                if !expected.is_empty() && verify_slices_are_equal(given.as_bytes(), expected.as_bytes()).is_ok());
+            close = !ok;
+            if ok {
+                response(Ok(serde_json::Value::Null))
+            } else {
+                response(Err("not authenticated".to_string()))
+            }
+        };
+
+        if let Ok(mut entry) = world.entry_mut(ent) {
+            if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+                if !close {
+                    prot.ptype = ProtocolType::Control { authenticated: true };
+                }
+                prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(reply.to_string().as_ref())));
+                prot.closing |= close;
             }
         }
     }
 }
 
+// Works through the account creations and logins queued by execute_connection_events. A
+// connection that gets in is attached to its user's session, which is created (through the
+// CommandBuffer) if the user has none yet.
+#[system]
+#[allow(clippy::too_many_arguments)]
+#[read_component(UserComponent)]
+#[read_component(MudProtoTypeComponent)]
+#[read_component(LocationComponent)]
+#[write_component(ContentsComponent)]
+#[write_component(MudSession)]
+#[write_component(ProtocolComponent)]
+#[read_component(ConnectionComponent)]
+pub fn process_logins(world: &mut SubWorld, cmd: &mut CommandBuffer, #[resource] creations: &mut PendingUserCreations,
+                      #[resource] logins: &mut PendingUserLogins, #[resource] online: &mut UsersOnline,
+                      #[resource] sessions: &mut MudSessions, #[resource] clock: &EngineClock,
+                      #[resource] game: &GameConfig, #[resource] recent: &mut AccountCreations,
+                      #[resource] passwords: &mut PasswordWorker) {
+    let now = clock.now();
+    let start = game.start_room.as_ref().and_then(|objid| <&MudProtoTypeComponent>::query().iter(world)
+        .find(|p| &p.objid == objid)
+        .map(|p| p.entity));
+    // Users made this tick aren't in the world yet, so they are looked up here too.
+    let mut created: HashMap<String, (Entity, String)> = HashMap::new();
 
-#[system(for_each)]
-pub fn transfer_events(cmd: &mut CommandBuffer, wrl: &mut World, msess: &mut MudSession) {
-    for ent in msess.connections.iter() {
-        if let Ok(mut entry) = wrl.entry_mut(*ent) {
-            if let Ok(mut prot) = entry.get_component_mut::<ProtocolComponent>() {
-                for ev in prot.in_buffer.iter() {
-                    msess.in_events.push_back(ev.clone());
+    // Hashing happens on the password thread; whatever it has finished is acted on here.
+    for result in passwords.finished() {
+        match result {
+            PasswordResult::Hashed {conn, username, hash} => {
+                let user = cmd.push((UserComponent {username: username.clone(), password: hash.clone(), ..Default::default()}, AccountChanged));
+                created.insert(username.to_lowercase(), (user, hash));
+                println!("Created account {}", strip_control(&username));
+                // The connection may have gone while it waited. The account stays made.
+                if world.entry_ref(conn).is_ok() {
+                    attach_session(world, cmd, online, sessions, conn, user, &username, now, start);
+                }
+            },
+            PasswordResult::Verified {conn, username, user: Some(user)} if world.entry_ref(conn).is_ok() => {
+                attach_session(world, cmd, online, sessions, conn, user, &username, now, start);
+            },
+            PasswordResult::Verified {conn, ..} => {
+                send_to_connection(world, conn, Text::from("Invalid username or password."));
+            }
+        }
+    }
+
+    while let Some((conn, username, password)) = creations.0.pop_front() {
+        let key = username.to_lowercase();
+        let taken = created.contains_key(&key) || passwords.is_creating(&key) || <&UserComponent>::query().iter(world)
+            .any(|u| u.username.to_lowercase() == key);
+        if taken {
+            send_to_connection(world, conn, Text::from("That username is already taken."));
+            continue;
+        }
+        if let Some(limit) = game.max_accounts_per_ip {
+            // Unix socket clients have no address of their own to count, so they aren't.
+            let ip = world.entry_ref(conn).ok()
+                .and_then(|entry| entry.get_component::<ConnectionComponent>().ok().and_then(|c| c.peer_ip()));
+            let window = Duration::from_secs(game.account_window_secs);
+            if let Some(ip) = ip {
+                if !recent.allow(ip, now, limit, window) {
+                    send_to_connection(world, conn, Text::from("Sorry, too many accounts have been created from your address recently. Please try again later."));
+                    continue;
+                }
+            }
+        }
+        passwords.submit(PasswordJob::Hash {conn, username, password});
+    }
+
+    while let Some((conn, username, password)) = logins.0.pop_front() {
+        let key = username.to_lowercase();
+        let user = match created.get(&key) {
+            Some((user, hash)) => Some((*user, hash.clone())),
+            None => <(Entity, &UserComponent)>::query().iter(world)
+                .find(|(_, u)| u.username.to_lowercase() == key)
+                .map(|(ent, u)| (*ent, u.password.clone()))
+        };
+        passwords.submit(PasswordJob::Verify {conn, username, user, password});
+    }
+}
+
+// Rewrites the accounts file when any account has changed since it was last written.
+#[system]
+#[read_component(UserComponent)]
+#[read_component(AccountChanged)]
+pub fn persist_accounts(world: &mut SubWorld, cmd: &mut CommandBuffer, #[resource] game: &GameConfig) {
+    let changed: Vec<Entity> = <Entity>::query().filter(component::<AccountChanged>()).iter(world).cloned().collect();
+    if changed.is_empty() {
+        return;
+    }
+    for ent in changed {
+        cmd.remove_component::<AccountChanged>(ent);
+    }
+    if let Some(path) = &game.accounts_file {
+        let accounts: Vec<UserComponent> = <&UserComponent>::query().iter(world).cloned().collect();
+        if let Err(e) = save_accounts(path, &accounts) {
+            println!("Could not save accounts to {}: {}", path.display(), e);
+        }
+    }
+}
+
+// The configured MSSP variables plus the live ones, sorted so every crawler sees the same order.
+fn mssp_variables(game: &GameConfig, online: &UsersOnline, boot: &BootTime) -> Vec<(String, String)> {
+    let mut out: Vec<(String, String)> = game.mssp.iter()
+        .filter(|(k, _)| !matches!(k.as_str(), "PLAYERS" | "UPTIME"))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    out.push(("PLAYERS".to_string(), online.0.len().to_string()));
+    out.push(("UPTIME".to_string(), boot.0.timestamp().to_string()));
+    out.sort();
+    out
+}
+
+// Puts a newly played puppet back in the room it was last in, or the start room if it has
+// never been anywhere.
+fn place_puppet(world: &mut SubWorld, cmd: &mut CommandBuffer, puppet: Entity, session: Entity, start: Option<Entity>) {
+    cmd.add_component(puppet, MudObjectSession {session});
+    let room = match location_of(world, puppet) {
+        Some(room) => room,
+        None => match start {
+            Some(room) => {
+                cmd.add_component(puppet, LocationComponent {ltype: LocationType::Room, entity: room});
+                room
+            },
+            None => return
+        }
+    };
+    add_to_contents(world, puppet, room);
+}
+
+fn send_to_connection(world: &mut SubWorld, conn: Entity, text: Text) {
+    if let Ok(mut entry) = world.entry_mut(conn) {
+        if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(text));
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+// A new session starts out not exempt. refresh_exemptions grants it, and records that in
+// the audit log, before the session runs anything.
+fn attach_session(world: &mut SubWorld, cmd: &mut CommandBuffer, online: &mut UsersOnline, sessions: &mut MudSessions,
+                  conn: Entity, user: Entity, username: &str, now: Instant, start: Option<Entity>) {
+    let mut exempt = false;
+    let existing = sessions.0.get(&user).cloned();
+    let session = match existing {
+        Some(session) => {
+            if let Ok(mut entry) = world.entry_mut(session) {
+                if let Ok(msess) = entry.get_component_mut::<MudSession>() {
+                    msess.connections.insert(conn);
+                    exempt = msess.exempt;
                 }
-                prot.in_buffer.clear();
+            }
+            session
+        },
+        None => {
+            let mut connections = HashSet::new();
+            connections.insert(conn);
+            // Until there are characters, the account stands in for the player and puppet.
+            let session = cmd.push((MudSession {
+                user,
+                player: user,
+                puppet: user,
+                created: now,
+                last_input: now,
+                in_events: Default::default(),
+                out_events: Default::default(),
+                connections,
+                processes: 0,
+                busy_until: None,
+                command_queue: Default::default(),
+                exempt: false
+            },));
+            sessions.0.insert(user, session);
+            online.0.insert(user, now);
+            place_puppet(world, cmd, user, session, start);
+            session
+        }
+    };
+
+    if let Ok(mut entry) = world.entry_mut(conn) {
+        if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+            prot.user = Some(user);
+            prot.session = Some(session);
+            prot.exempt = exempt;
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(format!("Welcome, {}!", username).as_ref())));
+        }
+    }
+}
+
+
+// Moves input from each session's connections into the session and the session's output
+// out to every connection. Login events only mean something before a connection has a
+// session, so any still queued by then are dropped instead of reaching the game.
+#[system]
+#[write_component(MudSession)]
+#[write_component(ProtocolComponent)]
+pub fn transfer_events(world: &mut SubWorld, #[resource] gate: &InputGate) {
+    let sessions: Vec<(Entity, Vec<Entity>, Vec<ProtocolOutEvent>)> = <(Entity, &mut MudSession)>::query().iter_mut(world)
+        .map(|(ent, msess)| (*ent, msess.connections.iter().cloned().collect(), msess.out_events.drain(..).collect()))
+        .collect();
 
-                for ev in msess.out_events.iter() {
-                    prot.out_buffer.push_back(ev.clone());
+    for (session, conns, outgoing) in sessions {
+        let mut incoming = Vec::new();
+        for conn in conns {
+            if let Ok(mut entry) = world.entry_mut(conn) {
+                if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+                    if !gate.0 || prot.is_active() {
+                        incoming.extend(prot.in_buffer.drain(..).filter(|ev| !ev.is_login()));
+                    }
+                    prot.out_buffer.extend(outgoing.iter().cloned());
                 }
             }
         }
+        if incoming.is_empty() {
+            continue;
+        }
+        if let Ok(mut entry) = world.entry_mut(session) {
+            if let Ok(msess) = entry.get_component_mut::<MudSession>() {
+                msess.in_events.extend(incoming);
+            }
+        }
     }
-    msess.out_events.clear();
 }
 
 #[system(par_for_each)]
-pub fn send_out_events(prot: &mut ProtocolComponent, conn: &mut ConnectionComponent) {
-    while let Some(ev) = prot.out_buffer.pop_front() {
-        prot.send_event(ev, conn);
+pub fn send_out_events(prot: &mut ProtocolComponent, conn: &mut ConnectionComponent, #[resource] batching: &OobBatching,
+                       #[resource] pacing: &OutputPacing) {
+    if prot.holds_output() {
+        return;
+    }
+    let finishing = prot.closing || prot.has_pending_disconnect();
+    let pending = apply_filters(&mut prot.filters, std::mem::take(&mut prot.out_buffer));
+    let batched = batch_oob(pending, *batching);
+    match &pacing.0 {
+        // A closing connection gets everything at once, so its last words aren't cut off.
+        Some(config) if prot.wants_pacing && !finishing => prot.send_paced(conn, batched, config),
+        _ => {
+            let mut events = std::mem::take(&mut prot.paced);
+            events.extend(batched);
+            for ev in events {
+                prot.send_event(ev, conn);
+            }
+        }
+    }
+    if prot.closing && conn.is_active() {
+        conn.status = ConnectionStatus::ServerClosed;
     }
 }
 
+fn spawn_process(session: Entity, msess: &mut MudSession, cmd: &mut CommandBuffer, pid: &mut ProcessCounter,
+                 pdx: &mut ProcessIndex, command: String, now: Instant) {
+    let id = pid.next(pdx);
+    let process = ProcessComponent::from_command(session, msess, id, command, now);
+    let proc_ent = cmd.push((process, ));
+    pdx.0.insert(id, proc_ent);
+    msess.processes += 1;
+}
+
+fn queue_command(msess: &mut MudSession, command: String, limit: usize) {
+    if msess.command_queue.len() >= limit && !msess.exempt {
+        msess.out_events.push_back(ProtocolOutEvent::Line(Text::from("You have too many commands queued. That one was dropped.")));
+    } else {
+        msess.command_queue.push_back(command);
+    }
+}
+
+// Runs command, or queues it behind lag or earlier queued commands. A session already at
+// its process cap is refused instead, until some of those have run.
+#[allow(clippy::too_many_arguments)]
+fn admit_command(session: Entity, msess: &mut MudSession, cmd: &mut CommandBuffer, pid: &mut ProcessCounter,
+                 pdx: &mut ProcessIndex, command: String, now: Instant, game: &GameConfig) {
+    if msess.is_lagged(now) || !msess.command_queue.is_empty() {
+        queue_command(msess, command, game.max_queued_commands);
+    } else if msess.processes >= game.max_session_processes && !msess.exempt {
+        msess.out_events.push_back(ProtocolOutEvent::Line(Text::from("You have too many commands waiting to run. That one was refused.")));
+    } else {
+        spawn_process(session, msess, cmd, pid, pdx, command, now);
+    }
+}
+
+// Keeps each session's exemption in step with its account, so an admin who is demoted or
+// loses throttle_exempt is held to the limits again without logging in anew. Every change
+// goes in the audit log.
 #[system(for_each)]
-pub fn session_in_events(cmd: &mut CommandBuffer, msess: &mut MudSession, #[resource] pid: &mut ProcessCounter, #[resource] pdx: &mut ProcessIndex) {
+#[read_component(UserComponent)]
+#[write_component(ProtocolComponent)]
+pub fn refresh_exemptions(msess: &mut MudSession, world: &mut SubWorld, #[resource] audit: &mut AuditLog,
+                          #[resource] clock: &EngineClock) {
+    let (account, exempt) = match world.entry_ref(msess.user).ok()
+        .and_then(|entry| entry.get_component::<UserComponent>().ok().map(|u| (u.username.clone(), u.is_throttle_exempt()))) {
+        Some(found) => found,
+        None => return
+    };
+    if exempt == msess.exempt {
+        return;
+    }
+    msess.exempt = exempt;
+    audit.exemption(&account, exempt, clock.utc_now());
+    for conn in msess.connections.iter() {
+        if let Ok(mut entry) = world.entry_mut(*conn) {
+            if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+                prot.exempt = exempt;
+            }
+        }
+    }
+}
+
+#[system(for_each)]
+#[allow(clippy::too_many_arguments)]
+pub fn session_in_events(ent: &Entity, cmd: &mut CommandBuffer, msess: &mut MudSession, #[resource] pid: &mut ProcessCounter,
+                         #[resource] pdx: &mut ProcessIndex, #[resource] clock: &EngineClock, #[resource] game: &GameConfig,
+                         #[resource] online: &UsersOnline, #[resource] boot: &BootTime) {
+    // Queued commands wait out lag and the process cap, then go first, one a tick like
+    // anything else.
+    let capped = msess.processes >= game.max_session_processes && !msess.exempt;
+    if !msess.is_lagged(clock.now()) && !capped {
+        if let Some(s) = msess.command_queue.pop_front() {
+            spawn_process(*ent, msess, cmd, pid, pdx, s, clock.now());
+            return;
+        }
+    }
+
     // Pop an event off of MudSession and execute it, if applicable.
     if let Some(ev) = msess.in_events.pop_front() {
         match ev {
             ProtocolEvent::Line(s) => {
-                println!("Got a process command: {}", s);
-                pid.0 += 1;
-                let mut process = ProcessComponent::from_command(msess, pid.0, s);
-                let mut ent = cmd.push((process, ));
-                pdx.0.insert(pid.0, ent);
-            },
-            ProtocolEvent::OOB(cmd, args, kwargs) => {
-
+                println!("Got a process command: {}", strip_control(&s));
+                msess.last_input = clock.now();
+                if is_clear_queue(&s) {
+                    // Never queued itself, or it couldn't do its job.
+                    spawn_process(*ent, msess, cmd, pid, pdx, s, clock.now());
+                } else {
+                    admit_command(*ent, msess, cmd, pid, pdx, s, clock.now(), game);
+                }
             },
             ProtocolEvent::RequestMSSP => {
-                // TODO: This should render MSSP data to a HashMap and push it to msess.out_events
+                msess.out_events.push_back(ProtocolOutEvent::MSSP(mssp_variables(game, online, boot)));
             },
             _ => {
 
@@ -237,7 +872,551 @@ pub fn session_in_events(cmd: &mut CommandBuffer, msess: &mut MudSession, #[reso
     }
 }
 
+#[system]
+pub fn begin_process_budget(#[resource] budget: &mut ProcessBudget, #[resource] clock: &EngineClock) {
+    budget.tick_started = Some(clock.now());
+}
+
 #[system(for_each)]
-pub fn execute_process(ent: &Entity, proc: &mut ProcessComponent, wrl: &mut World, cmd: &mut CommandBuffer) {
-    println!("FOUND A PROCESS: {:?}", proc);
-}
\ No newline at end of file
+#[allow(clippy::too_many_arguments)]
+#[write_component(MudSession)]
+#[write_component(UserComponent)]
+#[write_component(ProtocolComponent)]
+#[read_component(ConnectionComponent)]
+#[read_component(MudObjectComponent)]
+#[read_component(MudProtoTypeComponent)]
+#[read_component(MudObjectSession)]
+#[read_component(VitalsComponent)]
+#[read_component(ExitsComponent)]
+#[read_component(DescriptionComponent)]
+#[read_component(ModuleComponent)]
+#[write_component(LocationComponent)]
+#[write_component(ContentsComponent)]
+pub fn execute_process(ent: &Entity, proc: &mut ProcessComponent, world: &mut SubWorld, cmd: &mut CommandBuffer,
+                       #[resource] gcmds: &GameCommands, #[resource] budget: &ProcessBudget,
+                       #[resource] clock: &EngineClock, #[resource] game: &GameConfig, #[resource] pdx: &mut ProcessIndex,
+                       #[resource] audit: &mut AuditLog, #[resource] pacing: &OutputPacing) {
+    if budget.exhausted(clock.now()) {
+        // Out of time this tick. The process stays queued and runs on a later one.
+        return;
+    }
+
+    if let Some(session) = proc.session {
+        // A command spawned before the lag ahead of it was imposed still waits it out.
+        let lagged = world.entry_ref(session).ok()
+            .and_then(|entry| entry.get_component::<MudSession>().ok().map(|msess| msess.is_lagged(clock.now())))
+            .unwrap_or(false);
+        if lagged && proc.steps.is_none() && !is_clear_queue(&proc.command) {
+            return;
+        }
+        let mut ctx = GameContext {
+            world,
+            cmd,
+            session,
+            user: proc.enactor_user,
+            now: clock.now(),
+            utc_now: clock.utc_now(),
+            pacing_available: pacing.0.is_some(),
+            continuation: None
+        };
+        let mut steps = proc.steps.take();
+        if steps.is_none() {
+            // Whatever skips the limits is on the record.
+            if ctx.with_session(|msess| msess.exempt).unwrap_or(false) {
+                let account = ctx.with_user(|user| user.username.clone()).unwrap_or_default();
+                audit.exempt_command(&account, &proc.command, ctx.utc_now);
+            }
+            gcmds.execute(&mut ctx, proc.command.clone());
+            steps = ctx.continuation.take();
+        }
+        // A command left in steps goes on until it's done or the tick is out of time, and
+        // then picks up where it left off on the next one.
+        while let Some(step) = &mut steps {
+            if budget.exhausted(clock.now()) {
+                proc.steps = steps;
+                return;
+            }
+            if step.step(&mut ctx) == StepResult::Done {
+                steps = None;
+            }
+        }
+        ctx.send_prompt(&game.prompt);
+        ctx.with_session(|msess| msess.processes = msess.processes.saturating_sub(1));
+    }
+    pdx.0.remove(&proc.id);
+    cmd.remove(*ent);
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use legion::*;
+    use crate::config::{GameConfig, NetConfig};
+    use crate::game::game_cmds::{GameCmd, GameCommands, GameContext};
+    use crate::game::objects::{MudSession, UserComponent};
+    use crate::mudstring::text::Text;
+    use crate::game::resources::{MudSessions, UsersOnline};
+    use crate::net::telnet::NEGOTIATION_GRACE;
+    use crate::net::telnet::codes as tc;
+    use crate::testing::{temp_path, Client, Harness};
+    use std::time::Duration;
+
+    type GameFunc = fn(&mut GameContext, String, &[GameCmd]);
+
+    #[test]
+    fn lines_reach_the_session_but_login_events_do_not() {
+        use crate::engine::resources::{InputGate, TelnetOptions};
+        use crate::engine::systems::transfer_events_system;
+        use crate::net::{ProtocolComponent, ProtocolEvent};
+        use std::collections::HashSet;
+        use std::time::Instant;
+
+        let now = Instant::now();
+        let mut world = World::default();
+        let mut resources = Resources::default();
+        resources.insert(InputGate(false));
+        let mut prot = ProtocolComponent::telnet(TelnetOptions::default().0, now);
+        prot.in_buffer.push_back(ProtocolEvent::Line("look".to_string()));
+        prot.in_buffer.push_back(ProtocolEvent::CreateUser("mallory".to_string(), "secret".to_string()));
+        prot.in_buffer.push_back(ProtocolEvent::Login("mallory".to_string(), "secret".to_string()));
+        let conn = world.push((prot,));
+        let user = world.push((UserComponent::default(),));
+        let mut connections = HashSet::new();
+        connections.insert(conn);
+        let session = world.push((MudSession {
+            user, player: user, puppet: user, created: now, last_input: now,
+            in_events: Default::default(), out_events: Default::default(), connections,
+            processes: 0, busy_until: None, command_queue: Default::default(), exempt: false
+        },));
+
+        Schedule::builder().add_system(transfer_events_system()).build().execute(&mut world, &mut resources);
+
+        let entry = world.entry(session).unwrap();
+        let events: Vec<&ProtocolEvent> = entry.get_component::<MudSession>().unwrap().in_events.iter().collect();
+        assert!(matches!(events.as_slice(), [ProtocolEvent::Line(line)] if line == "look"), "{:?}", events);
+        assert!(world.entry(conn).unwrap().get_component::<ProtocolComponent>().unwrap().in_buffer.is_empty());
+    }
+
+    fn lunge_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+        ctx.send_line(Text::from("You lunge."));
+        ctx.add_lag(Duration::from_secs(2));
+    }
+
+    fn recover_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+        ctx.send_line(Text::from("You recover."));
+    }
+
+    // A harness whose game has lunge, which lags the player for two seconds, and recover.
+    fn combat_harness(game: GameConfig) -> Harness {
+        let mut h = Harness::with_game(game);
+        let mut cmds = h.engine.resources.get_mut::<GameCommands>().unwrap();
+        for (name, func) in [("lunge", lunge_command as GameFunc), ("recover", recover_command)] {
+            cmds.register("test", GameCmd {
+                name: name.to_string(), aliases: Default::default(), func,
+                help: String::new(), syntax: name.to_string(), shorthelp: String::new(), module: None
+            });
+        }
+        drop(cmds);
+        h
+    }
+
+    #[test]
+    fn command_lag_delays_the_next_command() {
+        let mut h = combat_harness(GameConfig::default());
+        let mut client = h.login("alice");
+        client.write(b"lunge\r\nrecover\r\n");
+        h.tick(8);
+        let text = client.text();
+        assert!(text.contains("You lunge."));
+        assert!(!text.contains("You recover."), "{:?}", text);
+
+        h.advance(Duration::from_millis(1900));
+        assert!(!client.text().contains("You recover."));
+        h.advance(Duration::from_millis(200));
+        assert!(client.text().contains("You recover."));
+    }
+
+    #[test]
+    fn commands_queued_during_lag_are_bounded() {
+        let mut h = combat_harness(GameConfig {max_queued_commands: 3, ..Default::default()});
+        let mut client = h.login("alice");
+        h.send(&mut client, "lunge");
+        client.read();
+        client.write("recover\r\n".repeat(5).as_bytes());
+        h.tick(10);
+        assert_eq!(client.text().matches("That one was dropped.").count(), 2);
+
+        h.advance(Duration::from_secs(2));
+        h.tick(10);
+        assert_eq!(client.text().matches("You recover.").count(), 3);
+    }
+
+    #[test]
+    fn clearqueue_runs_through_lag_and_drops_the_queue() {
+        let mut h = combat_harness(GameConfig::default());
+        let mut client = h.login("alice");
+        h.send(&mut client, "lunge");
+        client.read();
+        client.write(b"recover\r\nrecover\r\nclearqueue\r\n");
+        h.tick(8);
+        assert!(client.text().contains("Cleared 2 queued command(s)."));
+
+        h.advance(Duration::from_secs(2));
+        h.tick(4);
+        assert!(!client.text().contains("You recover."));
+    }
+
+    #[test]
+    fn commands_past_the_process_cap_are_refused_until_one_runs() {
+        use crate::engine::resources::ProcessBudget;
+
+        let mut h = combat_harness(GameConfig {max_session_processes: 2, ..Default::default()});
+        let mut client = h.login("alice");
+        // With no time to run anything, every command stays waiting.
+        h.engine.resources.get_mut::<ProcessBudget>().unwrap().limit = Duration::ZERO;
+        client.write("recover\r\n".repeat(3).as_bytes());
+        h.tick(8);
+        let text = client.text();
+        assert_eq!(text.matches("That one was refused.").count(), 1, "{:?}", text);
+        assert!(!text.contains("You recover."));
+
+        h.engine.resources.get_mut::<ProcessBudget>().unwrap().limit = Duration::from_millis(5);
+        h.tick(3);
+        assert_eq!(client.text().matches("You recover.").count(), 2);
+        let text = h.command(&mut client, "recover");
+        assert!(text.contains("You recover."));
+        assert!(!text.contains("refused"));
+    }
+
+    #[test]
+    fn hashing_a_password_does_not_hold_up_other_connections() {
+        use crate::game::accounts::PasswordWorker;
+
+        let mut h = Harness::new();
+        let mut waiting = h.connect();
+        let mut other = h.connect();
+        waiting.write(b"create alice=password\r\n");
+        other.write(b"help\r\n");
+        h.tick(8);
+        assert!(h.engine.resources.get::<PasswordWorker>().unwrap().is_busy());
+        assert!(other.text().contains("connect"));
+
+        h.settle_passwords();
+        assert!(waiting.text().contains("Welcome, alice!"));
+    }
+
+    #[test]
+    fn input_is_held_until_negotiation_finishes() {
+        let net: NetConfig = serde_json::from_str(r#"{"hold_input_until_active": true}"#).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(3);
+
+        let accounts = |h: &Harness| <&UserComponent>::query().iter(&h.engine.world).count();
+
+        h.send(&mut client, "create bob=secret");
+        assert_eq!(accounts(&h), 0);
+
+        h.advance(NEGOTIATION_GRACE + Duration::from_secs(1));
+        h.tick(8);
+        h.settle_passwords();
+        assert_eq!(accounts(&h), 1);
+        assert!(client.text().contains("Welcome, bob!"));
+    }
+
+    #[test]
+    fn output_sent_while_negotiating_gets_the_negotiated_color() {
+        use crate::mudstring::{color::Color, style::Style, text::Segment};
+        use crate::net::{ProtocolComponent, ProtocolOutEvent, telnet::replay::RECORDINGS};
+
+        let mut h = Harness::new();
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(2);
+        let alert = Text::from(vec![Segment {
+            text: "alert".to_string(),
+            style: Some(Style {color: Some(Color::from_rgb(255, 0, 0)), ..Default::default()})
+        }]);
+        for prot in <&mut ProtocolComponent>::query().iter_mut(&mut h.engine.world) {
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(alert.clone()));
+        }
+        h.tick(2);
+        assert!(!client.read().contains("alert"));
+
+        // Mudlet's side of the handshake, which settles on truecolor.
+        for read in RECORDINGS[0].bytes {
+            client.write(read);
+            h.tick(1);
+        }
+        h.tick(3);
+        let out = client.read();
+        assert!(out.contains("\x1b[38;2;255;0;0malert"), "{:?}", out);
+    }
+
+    #[test]
+    fn oob_input_command_runs_like_typed_input() {
+        let mut h = Harness::new();
+        let mut client = h.login("alice");
+        let typed = h.command(&mut client, "look");
+        assert!(!typed.trim().is_empty());
+
+        client.write(&[tc::IAC, tc::DO, tc::GMCP]);
+        h.tick(3);
+        client.read();
+        let mut sub = vec![tc::IAC, tc::SB, tc::GMCP];
+        sub.extend_from_slice(br#"Input.Command "look""#);
+        sub.extend_from_slice(&[tc::IAC, tc::SE]);
+        client.write(&sub);
+        h.tick(8);
+        assert_eq!(client.text(), typed);
+    }
+
+    #[test]
+    fn commands_sent_over_oob_count_toward_the_flood_limit() {
+        use crate::net::ProtocolComponent;
+
+        let net: NetConfig = serde_json::from_str(r#"{"suspicion": {"throttle_at": 5, "flood_lines": 5}}"#).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        let mut client = h.login("alice");
+        client.write(&[tc::IAC, tc::DO, tc::GMCP]);
+        h.tick(3);
+        client.read();
+        let mut sub = vec![tc::IAC, tc::SB, tc::GMCP];
+        sub.extend_from_slice(format!("Input.Command {:?}", "look\n".repeat(10)).as_bytes());
+        sub.extend_from_slice(&[tc::IAC, tc::SE]);
+        client.write(&sub);
+        h.tick(3);
+        assert!(<&ProtocolComponent>::query().iter(&h.engine.world).any(|prot| prot.suspicion.throttled));
+    }
+
+    #[test]
+    fn login_creates_one_session_and_records_it() {
+        let mut h = Harness::new();
+        let mut first = h.login("alice");
+        h.send(&mut first, "quit");
+        let mut client = h.connect();
+        h.send(&mut client, "connect alice=wrong");
+        assert!(client.text().contains("Invalid username or password."));
+        h.advance(Duration::from_secs(3));
+        h.send(&mut client, "connect alice=password");
+
+        let sessions: Vec<(Entity, Entity)> = <(Entity, &MudSession)>::query().iter(&h.engine.world)
+            .map(|(ent, msess)| (*ent, msess.user))
+            .collect();
+        assert_eq!(sessions.len(), 1);
+        let (session, user) = sessions[0];
+        assert_eq!(h.engine.resources.get::<MudSessions>().unwrap().0.get(&user), Some(&session));
+        assert!(h.engine.resources.get::<UsersOnline>().unwrap().0.contains_key(&user));
+    }
+
+    #[test]
+    fn a_session_ends_when_its_only_connection_closes() {
+        let mut h = Harness::new();
+        let client = h.login("alice");
+        let users = |h: &Harness| h.engine.resources.get::<UsersOnline>().unwrap().0.len();
+        let sessions = |h: &Harness| h.engine.resources.get::<MudSessions>().unwrap().0.len();
+        assert_eq!((users(&h), sessions(&h)), (1, 1));
+
+        drop(client);
+        h.tick(8);
+        assert_eq!((users(&h), sessions(&h)), (0, 0));
+        assert_eq!(<&MudSession>::query().iter(&h.engine.world).count(), 0);
+    }
+
+    #[test]
+    fn close_after_flush_waits_for_the_buffer_to_drain() {
+        use crate::net::{ConnectionComponent, ConnectionStatus, FlushAction};
+        use std::io::{Read, Write};
+
+        const PAYLOAD: usize = 4 * 1024 * 1024;
+        let mut h = Harness::new();
+        let mut client = h.connect();
+        for conn in <&mut ConnectionComponent>::query().iter_mut(&mut h.engine.world) {
+            conn.write_all(&vec![b'x'; PAYLOAD]).unwrap();
+            conn.after_flush(FlushAction::Close);
+        }
+        h.tick(3);
+        let status = |h: &Harness| <&ConnectionComponent>::query().iter(&h.engine.world)
+            .map(|conn| matches!(conn.status, ConnectionStatus::Active))
+            .next();
+        assert_eq!(status(&h), Some(true), "closed before the client read anything");
+
+        let mut received = 0;
+        let mut buf = vec![0u8; 65536];
+        for _ in 0..2000 {
+            match client.stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received += n,
+                Err(_) => h.tick(1)
+            }
+        }
+        assert_eq!(received, PAYLOAD);
+        assert_eq!(status(&h), None);
+    }
+
+    #[test]
+    fn a_disconnect_message_is_written_in_full_before_the_socket_closes() {
+        use crate::net::{ConnectionComponent, ProtocolComponent, ProtocolOutEvent};
+        use std::io::Read;
+
+        // Far more than the socket buffer, so the transport only ever takes part of it.
+        const PAYLOAD: usize = 2 * 1024 * 1024;
+        let mut h = Harness::new();
+        let mut client = h.connect();
+        let mut farewell = "x".repeat(PAYLOAD);
+        farewell.push_str("Goodbye!");
+        for prot in <&mut ProtocolComponent>::query().iter_mut(&mut h.engine.world) {
+            prot.out_buffer.push_back(ProtocolOutEvent::Disconnect(Text::from(farewell.as_ref())));
+        }
+        h.tick(3);
+        let connections = |h: &Harness| <&ConnectionComponent>::query().iter(&h.engine.world).count();
+        assert_eq!(connections(&h), 1, "removed before the client read anything");
+
+        let mut received = Vec::new();
+        let mut buf = vec![0u8; 65536];
+        for _ in 0..2000 {
+            match client.stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(_) => h.tick(1)
+            }
+        }
+        let text = String::from_utf8_lossy(&received);
+        assert_eq!(text.matches('x').count(), PAYLOAD);
+        assert!(text.trim_end().ends_with("Goodbye!"), "{:?}", &text[text.len().saturating_sub(40)..]);
+        h.tick(3);
+        assert_eq!(connections(&h), 0);
+    }
+
+    // A transport that accepts writes but never takes a byte.
+    struct ZeroWriter;
+
+    impl std::io::Write for ZeroWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_transport_that_takes_nothing_stops_the_write_loop_and_is_dropped() {
+        use super::note_write;
+        use crate::net::{write_buffered, ConnectionComponent, ConnectionStatus, WriteStop, MAX_ZERO_WRITES};
+        use bytes::BytesMut;
+        use std::io::ErrorKind;
+
+        let mut buf = BytesMut::from(&b"hello"[..]);
+        assert_eq!(write_buffered(&mut ZeroWriter, &mut buf).unwrap(), WriteStop::ZeroWrite);
+        assert_eq!(&buf[..], b"hello");
+
+        let mut h = Harness::new();
+        let mut client = h.connect();
+        for conn in <&mut ConnectionComponent>::query().iter_mut(&mut h.engine.world) {
+            for pass in 1..=MAX_ZERO_WRITES {
+                assert!(conn.is_active(), "dropped after {} passes", pass - 1);
+                let stop = write_buffered(&mut ZeroWriter, &mut buf);
+                note_write(conn, stop);
+            }
+            assert!(matches!(&conn.status, ConnectionStatus::ClientError(e) if e.kind() == ErrorKind::WriteZero));
+        }
+        h.tick(3);
+        assert_eq!(<&ConnectionComponent>::query().iter(&h.engine.world).count(), 0);
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn a_tls_flush_that_fails_marks_the_connection_broken() {
+        use super::flush_transport;
+        use crate::net::{ConnectionComponent, ConnectionStatus, Protocol, TransportType};
+        use rustls::{NoClientAuth, ServerConfig, ServerSession, Session, StreamOwned};
+        use std::sync::Arc;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (sock, peer) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        // Nothing written after this can leave.
+        sock.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut sess = ServerSession::new(&Arc::new(ServerConfig::new(NoClientAuth::new())));
+        sess.send_close_notify();
+        assert!(sess.wants_write());
+        let transport = TransportType::TLS(StreamOwned::new(sess, mio::net::TcpStream::from_std(sock)));
+        let mut conn = ConnectionComponent::new(transport, peer, Protocol::Telnet, mio::Token(1), None);
+
+        assert!(!flush_transport(&mut conn));
+        assert!(matches!(conn.status, ConnectionStatus::ClientError(_)), "{:?}", conn.status);
+    }
+
+    #[test]
+    fn unix_socket_clients_are_not_held_to_the_per_ip_limit() {
+        let mut h = Harness::with_game(GameConfig {max_accounts_per_ip: Some(2), account_window_secs: 60, ..Default::default()});
+        let users = |h: &Harness| <&UserComponent>::query().iter(&h.engine.world).count();
+        let _alice = h.login("alice");
+        let _bob = h.login("bob");
+
+        let mut carol = h.connect();
+        h.send(&mut carol, "create carol=password");
+        assert!(!carol.text().contains("too many accounts"));
+        assert_eq!(users(&h), 3);
+    }
+
+    #[test]
+    fn an_exempt_admin_floods_past_limits_that_hold_a_player_back() {
+        use crate::engine::resources::ProcessBudget;
+        use crate::net::ProtocolComponent;
+
+        let audit = temp_path("audit.log");
+        let net: NetConfig = serde_json::from_value(serde_json::json!({
+            "suspicion": {"throttle_at": 5, "flood_lines": 5}, "audit_log": audit
+        })).unwrap();
+        let mut h = Harness::with_config(Some(net), Some(GameConfig {max_session_processes: 2, ..Default::default()}));
+        let mut alice = h.login("alice");
+        for user in <&mut UserComponent>::query().iter_mut(&mut h.engine.world) {
+            user.admin = true;
+            user.throttle_exempt = true;
+        }
+        let mut bob = h.login("bob");
+
+        h.engine.resources.get_mut::<ProcessBudget>().unwrap().limit = Duration::ZERO;
+        alice.write("look\r\n".repeat(10).as_bytes());
+        bob.write("look\r\n".repeat(10).as_bytes());
+        h.tick(8);
+        let users: Vec<(Entity, String)> = <(Entity, &UserComponent)>::query().iter(&h.engine.world)
+            .map(|(ent, u)| (*ent, u.username.clone()))
+            .collect();
+        let throttled = |h: &Harness, name: &str| <&ProtocolComponent>::query().iter(&h.engine.world)
+            .filter(|prot| users.iter().any(|(ent, u)| prot.user == Some(*ent) && u == name))
+            .any(|prot| prot.suspicion.throttled);
+        assert!(throttled(&h, "bob"));
+        assert!(!throttled(&h, "alice"));
+        assert!(bob.text().contains("That one was refused."));
+        assert!(!alice.text().contains("refused"));
+        // Once there's time to run them, the commands alice sent are audited.
+        h.engine.resources.get_mut::<ProcessBudget>().unwrap().limit = Duration::from_millis(5);
+        h.tick(4);
+
+        // Demoted, alice is held to the limits again straight away.
+        for user in <&mut UserComponent>::query().iter_mut(&mut h.engine.world) {
+            user.admin = false;
+        }
+        h.tick(2);
+        assert!(<&MudSession>::query().iter(&h.engine.world).all(|msess| !msess.exempt));
+        assert!(<&ProtocolComponent>::query().iter(&h.engine.world).all(|prot| !prot.exempt));
+
+        let log = std::fs::read_to_string(&audit).unwrap();
+        std::fs::remove_file(audit).unwrap();
+        let records: Vec<serde_json::Value> = log.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let exemptions: Vec<bool> = records.iter()
+            .filter(|r| r["event"] == "exemption" && r["account"] == "alice")
+            .map(|r| r["exempt"].as_bool().unwrap())
+            .collect();
+        assert_eq!(exemptions, vec![true, false]);
+        assert!(records.iter().any(|r| r["event"] == "exempt_command" && r["account"] == "alice" && r["command"] == "look"));
+        assert!(!records.iter().any(|r| r["event"] == "exempt_command" && r["account"] == "bob"));
+    }
+}
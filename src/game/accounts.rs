@@ -0,0 +1,243 @@
+use legion::*;
+use ring::pbkdf2;
+use std::collections::HashSet;
+use std::fs::{read_to_string, rename, write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use crate::game::objects::UserComponent;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+// Checked in place of a real hash when the username doesn't exist, so a miss takes as long
+// as a wrong password and doesn't give away which accounts exist. Nothing matches it.
+const DUMMY_HASH: &str = "pbkdf2-sha256$100000$AAAAAAAAAAAAAAAAAAAAAA==$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+// Accounts are kept in one JSON file, GameConfig.accounts_file: everything on each
+// UserComponent, aliases and variables included. It's rewritten whenever an account changes.
+pub fn save_accounts(path: impl AsRef<Path>, accounts: &[UserComponent]) -> std::io::Result<()> {
+    // Written beside the old file and moved over it, so a crash mid-write loses nothing.
+    let path = path.as_ref();
+    let partial = path.with_extension("partial");
+    write(&partial, serde_json::to_string_pretty(accounts)?)?;
+    rename(partial, path)
+}
+
+pub fn load_accounts(path: impl AsRef<Path>) -> std::io::Result<Vec<UserComponent>> {
+    Ok(serde_json::from_str(&read_to_string(path)?)?)
+}
+
+// A salted PBKDF2-SHA256 hash of password, as "pbkdf2-sha256$iterations$salt$hash" with the
+// salt and hash in base64. This is what UserComponent.password holds.
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    let mut hash = [0u8; 32];
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+    format!("pbkdf2-sha256${}${}${}", PBKDF2_ITERATIONS, base64::encode(salt), base64::encode(hash))
+}
+
+// Whether password is the one stored was made from. Anything that isn't a hash matches nothing.
+pub fn verify_password(stored: &str, password: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+    if parts.len() != 4 || parts[0] != "pbkdf2-sha256" {
+        return false;
+    }
+    let iterations = match parts[1].parse::<u32>().ok().and_then(NonZeroU32::new) {
+        Some(n) => n,
+        None => return false
+    };
+    match (base64::decode(parts[2]), base64::decode(parts[3])) {
+        (Ok(salt), Ok(hash)) => pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &hash).is_ok(),
+        _ => false
+    }
+}
+
+// Hashing work handed to PasswordWorker. Each is tagged with the connection that asked.
+pub enum PasswordJob {
+    Hash {conn: Entity, username: String, password: String},
+    // user is None for a username that doesn't exist; that's checked against DUMMY_HASH.
+    Verify {conn: Entity, username: String, user: Option<(Entity, String)>, password: String}
+}
+
+pub enum PasswordResult {
+    Hashed {conn: Entity, username: String, hash: String},
+    Verified {conn: Entity, username: String, user: Option<Entity>}
+}
+
+// Runs PBKDF2 on a thread of its own, since each hash takes long enough to stall a tick.
+// Jobs are answered in the order they were given.
+pub struct PasswordWorker {
+    jobs: Sender<PasswordJob>,
+    results: Mutex<Receiver<PasswordResult>>,
+    pending: usize,
+    // Lowercased usernames whose accounts are being hashed, so nobody else can take them.
+    creating: HashSet<String>
+}
+
+impl PasswordWorker {
+    pub fn spawn() -> Self {
+        let (jobs, inbox) = channel::<PasswordJob>();
+        let (outbox, results) = channel();
+        std::thread::Builder::new().name("passwords".to_string()).spawn(move || {
+            // Ends once the worker is dropped and the jobs channel closes.
+            for job in inbox {
+                let result = match job {
+                    PasswordJob::Hash {conn, username, password} => {
+                        PasswordResult::Hashed {conn, username, hash: hash_password(&password)}
+                    },
+                    PasswordJob::Verify {conn, username, user, password} => {
+                        let stored = user.as_ref().map(|(_, hash)| hash.as_str()).unwrap_or(DUMMY_HASH);
+                        let ok = verify_password(stored, &password);
+                        PasswordResult::Verified {conn, username, user: user.filter(|_| ok).map(|(ent, _)| ent)}
+                    }
+                };
+                if outbox.send(result).is_err() {
+                    break;
+                }
+            }
+        }).expect("could not start the password thread");
+        Self {jobs, results: Mutex::new(results), pending: 0, creating: HashSet::new()}
+    }
+
+    pub fn submit(&mut self, job: PasswordJob) {
+        if let PasswordJob::Hash {username, ..} = &job {
+            self.creating.insert(username.to_lowercase());
+        }
+        if self.jobs.send(job).is_ok() {
+            self.pending += 1;
+        }
+    }
+
+    // Whatever has finished since the last call, without waiting on the rest.
+    pub fn finished(&mut self) -> Vec<PasswordResult> {
+        let done: Vec<PasswordResult> = self.results.lock().unwrap().try_iter().collect();
+        for result in done.iter() {
+            if let PasswordResult::Hashed {username, ..} = result {
+                self.creating.remove(&username.to_lowercase());
+            }
+        }
+        self.pending -= done.len();
+        done
+    }
+
+    pub fn is_creating(&self, key: &str) -> bool {
+        self.creating.contains(key)
+    }
+
+    // Whether jobs are still out that finished hasn't returned.
+    pub fn is_busy(&self) -> bool {
+        self.pending > 0
+    }
+}
+
+// Creates a user entity for each account. One whose username is already taken is skipped.
+// Returns how many were added.
+pub fn spawn_accounts(world: &mut World, accounts: Vec<UserComponent>) -> usize {
+    let mut added = 0;
+    for account in accounts {
+        let key = account.username.to_lowercase();
+        if <&UserComponent>::query().iter(world).any(|u| u.username.to_lowercase() == key) {
+            continue;
+        }
+        world.push((account,));
+        added += 1;
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_salted_hashes() {
+        let first = hash_password("hunter2");
+        let second = hash_password("hunter2");
+        assert!(!first.contains("hunter2"));
+        assert_ne!(first, second);
+        assert!(verify_password(&first, "hunter2"));
+        assert!(verify_password(&second, "hunter2"));
+        assert!(!verify_password(&first, "hunter3"));
+        assert!(!verify_password("hunter2", "hunter2"));
+    }
+
+    #[test]
+    fn an_unknown_username_costs_a_full_hash_and_never_matches() {
+        let parts: Vec<&str> = DUMMY_HASH.split('$').collect();
+        assert_eq!(parts[1], PBKDF2_ITERATIONS.to_string());
+        assert_eq!(base64::decode(parts[2]).unwrap().len(), 16);
+        assert_eq!(base64::decode(parts[3]).unwrap().len(), 32);
+
+        let mut worker = PasswordWorker::spawn();
+        let conn = World::default().push(());
+        worker.submit(PasswordJob::Verify {conn, username: "nobody".to_string(), user: None, password: String::new()});
+        let mut done = Vec::new();
+        while worker.is_busy() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            done.extend(worker.finished());
+        }
+        assert!(matches!(done.as_slice(), [PasswordResult::Verified {user: None, ..}]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_accounts_file_holds_no_plaintext_password() {
+        use crate::config::GameConfig;
+        use crate::testing::{temp_path, Harness};
+
+        let path = temp_path("accounts.json");
+        let game = GameConfig {accounts_file: Some(path.clone()), ..Default::default()};
+        let mut h = Harness::with_game(game);
+        h.login("alice");
+        h.tick(2);
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(saved.contains("pbkdf2-sha256$"));
+        assert!(!saved.contains(": \"password\""), "{}", saved);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_corrupt_accounts_file_is_reported_and_kept() {
+        use crate::config::GameConfig;
+        use crate::testing::{temp_path, Harness};
+
+        let path = temp_path("accounts.json");
+        std::fs::write(&path, "[{\"username\": ").unwrap();
+        let game = GameConfig {accounts_file: Some(path.clone()), ..Default::default()};
+        let mut h = Harness::with_game(game);
+        let aside = path.with_extension("corrupt");
+        assert_eq!(std::fs::read_to_string(&aside).unwrap(), "[{\"username\": ");
+
+        h.login("alice");
+        h.tick(2);
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(aside).unwrap();
+        assert!(saved.contains("alice"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn aliases_are_saved_with_the_account() {
+        use crate::config::GameConfig;
+        use crate::testing::{temp_path, Harness};
+
+        let path = temp_path("accounts.json");
+        let game = GameConfig {accounts_file: Some(path.clone()), ..Default::default()};
+
+        let mut h = Harness::with_game(game.clone());
+        let mut client = h.login("alice");
+        assert!(h.command(&mut client, "alias k=kill $1").contains("Alias 'k' set."));
+        h.tick(2);
+        drop(h);
+
+        let mut h = Harness::with_game(game);
+        let mut client = h.connect();
+        h.send(&mut client, "connect alice=password");
+        assert!(h.command(&mut client, "alias k").contains("k = kill $1"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
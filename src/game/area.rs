@@ -0,0 +1,215 @@
+use legion::*;
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs::read_to_string;
+use std::path::Path;
+use crate::game::objects::{ModuleComponent, MudProtoTypeComponent, MudObjectType, DescriptionComponent,
+                           ExitsComponent, ItemStatsComponent, ContentsComponent};
+use crate::game::resources::{Modules, ObjTypeIndex};
+
+// An area file: one module and the prototypes that belong to it. For example:
+//   {"name": "limbo", "display_name": "Limbo",
+//    "prototypes": [
+//      {"id": "void", "type": "Room", "name": "The Void", "description": "Nothing.", "exits": {"north": "hall"}},
+//      {"id": "hall", "type": "Room", "name": "A Hall", "exits": {"south": "void"}},
+//      {"id": "rock", "type": "Item", "name": "a rock", "stats": {"weight": 2}}
+//    ]}
+// Exits name other rooms of the same area by id.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AreaFile {
+    pub name: String,
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub protected: bool,
+    #[serde(default)]
+    pub prototypes: Vec<PrototypeDef>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PrototypeDef {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub objtype: MudObjectType,
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub exits: HashMap<String, String>,
+    #[serde(default)]
+    pub stats: HashMap<String, i64>
+}
+
+#[derive(Debug)]
+pub enum AreaError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    // Everything wrong with the area's contents, found in one pass so they can all be fixed.
+    Invalid(String, Vec<String>)
+}
+
+impl Display for AreaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AreaError::Io(e) => write!(f, "could not read area: {}", e),
+            AreaError::Parse(e) => write!(f, "could not parse area: {}", e),
+            AreaError::Invalid(name, problems) => write!(f, "area {} is invalid: {}", name, problems.join("; "))
+        }
+    }
+}
+
+impl std::error::Error for AreaError {}
+
+impl From<std::io::Error> for AreaError {
+    fn from(e: std::io::Error) -> Self {
+        AreaError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for AreaError {
+    fn from(e: serde_json::Error) -> Self {
+        AreaError::Parse(e)
+    }
+}
+
+impl AreaFile {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AreaError> {
+        let text = read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    // Every problem that would leave the area half-linked.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut types: HashMap<&str, MudObjectType> = HashMap::new();
+        for proto in &self.prototypes {
+            if types.insert(proto.id.as_str(), proto.objtype).is_some() {
+                problems.push(format!("{} is defined more than once", proto.id));
+            }
+        }
+
+        for proto in &self.prototypes {
+            if !proto.exits.is_empty() && proto.objtype != MudObjectType::Room {
+                problems.push(format!("{} has exits but is not a room", proto.id));
+            }
+            for (dir, target) in &proto.exits {
+                match types.get(target.as_str()) {
+                    Some(MudObjectType::Room) => {},
+                    Some(_) => problems.push(format!("exit {} of {} leads to {}, which is not a room", dir, proto.id, target)),
+                    None => problems.push(format!("exit {} of {} leads to {}, which does not exist", dir, proto.id, target))
+                }
+            }
+        }
+        problems
+    }
+}
+
+// Reads an area file and creates its module and prototypes in world. Nothing is created
+// unless the whole area is valid. Returns the module's entity.
+pub fn load_area(world: &mut World, modules: &mut Modules, index: &mut ObjTypeIndex, path: impl AsRef<Path>) -> Result<Entity, AreaError> {
+    let area = AreaFile::from_file(path)?;
+    spawn_area(world, modules, index, &area)
+}
+
+pub fn spawn_area(world: &mut World, modules: &mut Modules, index: &mut ObjTypeIndex, area: &AreaFile) -> Result<Entity, AreaError> {
+    let problems = area.validate();
+    if !problems.is_empty() {
+        return Err(AreaError::Invalid(area.name.clone(), problems));
+    }
+
+    let module = world.push((ModuleComponent {
+        display_name: area.display_name.clone().unwrap_or_else(|| area.name.clone()),
+        sys_name: area.name.clone(),
+        prototypes: Default::default(),
+        objects: Default::default(),
+        protected: area.protected
+    },));
+
+    // Entities first, so exits can point at rooms defined later in the file.
+    let mut ids: HashMap<String, Entity> = HashMap::new();
+    for proto in &area.prototypes {
+        let ent = world.push(());
+        ids.insert(proto.id.clone(), ent);
+    }
+
+    let mut prototypes = HashSet::new();
+    for proto in &area.prototypes {
+        let ent = ids[&proto.id];
+        if let Some(mut entry) = world.entry(ent) {
+            entry.add_component(MudProtoTypeComponent {
+                objid: format!("{}/{}", area.name, proto.id),
+                name: proto.name.clone(),
+                entity: ent,
+                objtype: proto.objtype,
+                module
+            });
+            if let Some(desc) = &proto.description {
+                entry.add_component(DescriptionComponent(desc.clone()));
+            }
+            if proto.objtype == MudObjectType::Room {
+                let exits = proto.exits.iter()
+                    .map(|(dir, target)| (dir.to_lowercase(), ids[target]))
+                    .collect();
+                entry.add_component(ExitsComponent(exits));
+                entry.add_component(ContentsComponent::default());
+            }
+            if !proto.stats.is_empty() {
+                entry.add_component(ItemStatsComponent(proto.stats.clone()));
+            }
+        }
+        index.0.entry(proto.objtype).or_default().insert(ent);
+        prototypes.insert(ent);
+    }
+
+    if let Some(mut entry) = world.entry(module) {
+        if let Ok(m) = entry.get_component_mut::<ModuleComponent>() {
+            m.prototypes = prototypes;
+        }
+    }
+    modules.0.insert(module);
+    Ok(module)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::{temp_path, TWO_ROOMS};
+
+    fn room(world: &World, module: Entity, id: &str) -> Entity {
+        let m = world.entry_ref(module).unwrap();
+        let protos = &m.get_component::<ModuleComponent>().unwrap().prototypes;
+        *protos.iter()
+            .find(|e| world.entry_ref(**e).unwrap().get_component::<MudProtoTypeComponent>().unwrap().objid == format!("limbo/{}", id))
+            .unwrap()
+    }
+
+    #[test]
+    fn a_two_room_area_loads_with_its_exits_linked() {
+        let path = temp_path("limbo.json");
+        std::fs::write(&path, TWO_ROOMS).unwrap();
+        let (mut world, mut modules, mut index) = (World::default(), Modules::default(), ObjTypeIndex::default());
+        let module = load_area(&mut world, &mut modules, &mut index, &path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(modules.0.contains(&module));
+        let (void, hall) = (room(&world, module, "void"), room(&world, module, "hall"));
+        assert_eq!(index.0[&MudObjectType::Room], [void, hall].iter().copied().collect());
+        let exits = |ent| world.entry_ref(ent).unwrap().get_component::<ExitsComponent>().unwrap().0.clone();
+        assert_eq!(exits(void).get("north"), Some(&hall));
+        assert_eq!(exits(hall).get("south"), Some(&void));
+    }
+
+    #[test]
+    fn a_dangling_exit_is_reported_and_nothing_is_created() {
+        let area: AreaFile = serde_json::from_str(&TWO_ROOMS.replace("\"void\"}", "\"cellar\"}")).unwrap();
+        let (mut world, mut modules, mut index) = (World::default(), Modules::default(), ObjTypeIndex::default());
+        match spawn_area(&mut world, &mut modules, &mut index, &area) {
+            Err(AreaError::Invalid(name, problems)) => {
+                assert_eq!(name, "limbo");
+                assert_eq!(problems, vec!["exit south of hall leads to cellar, which does not exist".to_string()]);
+            },
+            other => panic!("expected an invalid area, got {:?}", other)
+        }
+        assert!(modules.0.is_empty());
+        assert_eq!(world.len(), 0);
+    }
+}
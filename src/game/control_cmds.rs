@@ -0,0 +1,256 @@
+use legion::*;
+use legion::world::SubWorld;
+use serde_json::{json, Value};
+use std::time::Instant;
+use crate::game::objects::{MudSession, UserComponent};
+use crate::net::{ProtocolComponent, ProtocolOutEvent};
+use crate::mudstring::text::Text;
+use crate::game::game_cmds::{announcement, broadcast};
+use crate::game::snapshot::{save_snapshot, snapshot_sessions};
+use crate::config::GameConfig;
+
+
+// What a control command gets to work with. Control connections have no session or user;
+// they act on the whole game.
+pub struct ControlContext<'a, 'w> {
+    pub world: &'a mut SubWorld<'w>,
+    pub game: &'a GameConfig,
+    pub now: Instant,
+    // Set by commands that want the engine to re-read its config after this tick.
+    pub reload_requested: bool
+}
+
+pub struct ControlCommands {
+    pub cmds: Vec<ControlCmd>
+}
+
+impl Default for ControlCommands {
+    fn default() -> Self {
+        let cmds = vec![
+            ControlCmd{name: "who".to_string(), func: control_who_command,
+                help: "lists every session".to_string(), syntax: "who".to_string()},
+            ControlCmd{name: "kick".to_string(), func: control_kick_command,
+                help: "disconnects every connection of an account".to_string(), syntax: "kick <account>".to_string()},
+            ControlCmd{name: "broadcast".to_string(), func: control_broadcast_command,
+                help: "sends an announcement to every session, and with /all to the login screen too".to_string(),
+                syntax: "broadcast [/all] <message>".to_string()},
+            ControlCmd{name: "reload".to_string(), func: control_reload_command,
+                help: "re-reads the config file".to_string(), syntax: "reload".to_string()},
+            ControlCmd{name: "savesessions".to_string(), func: control_savesessions_command,
+                help: "writes every session to game.session_snapshot, to be restored at the next start".to_string(),
+                syntax: "savesessions".to_string()},
+            ControlCmd{name: "help".to_string(), func: control_help_command,
+                help: "lists the control commands".to_string(), syntax: "help".to_string()}
+        ];
+
+        Self {
+            cmds
+        }
+    }
+}
+
+impl ControlCommands {
+    // Runs one line and returns the response to send back: {"ok": true, "result": ...} or
+    // {"ok": false, "error": "..."}.
+    pub fn execute(&self, ctx: &mut ControlContext, command: &str) -> Value {
+        let split: Vec<&str> = command.splitn(2, ' ').collect();
+        let comm = split[0].trim();
+        let args = split.get(1).map(|a| a.trim()).unwrap_or("");
+
+        let result = match self.cmds.iter().find(|c| c.name.eq_ignore_ascii_case(comm)) {
+            Some(cmd) => (cmd.func)(ctx, args.to_string(), &self.cmds),
+            None => Err(format!("unknown command: {}", comm))
+        };
+        response(result)
+    }
+}
+
+pub fn response(result: Result<Value, String>) -> Value {
+    match result {
+        Ok(value) => json!({"ok": true, "result": value}),
+        Err(e) => json!({"ok": false, "error": e})
+    }
+}
+
+pub struct ControlCmd {
+    pub name: String,
+    pub func: fn(&mut ControlContext, String, &[ControlCmd]) -> Result<Value, String>,
+    pub help: String,
+    pub syntax: String
+}
+
+fn account_name(world: &SubWorld, user: Entity) -> Option<String> {
+    world.entry_ref(user).ok()
+        .and_then(|e| e.get_component::<UserComponent>().ok().map(|u| u.username.clone()))
+}
+
+pub fn control_who_command(ctx: &mut ControlContext, _args: String, _cmds: &[ControlCmd]) -> Result<Value, String> {
+    let sessions: Vec<(Entity, Instant, usize)> = <&MudSession>::query().iter(ctx.world)
+        .map(|msess| (msess.user, msess.last_input, msess.connections.len()))
+        .collect();
+
+    let mut out = Vec::new();
+    for (user, last_input, conns) in sessions {
+        out.push(json!({
+            "account": account_name(ctx.world, user),
+            "idle_secs": ctx.now.saturating_duration_since(last_input).as_secs(),
+            "connections": conns
+        }));
+    }
+    Ok(Value::Array(out))
+}
+
+pub fn control_kick_command(ctx: &mut ControlContext, args: String, _cmds: &[ControlCmd]) -> Result<Value, String> {
+    if args.is_empty() {
+        return Err("syntax: kick <account>".to_string());
+    }
+    let sessions: Vec<(Entity, Vec<Entity>)> = <&MudSession>::query().iter(ctx.world)
+        .map(|msess| (msess.user, msess.connections.iter().cloned().collect()))
+        .collect();
+
+    let mut kicked = 0;
+    for (user, conns) in sessions {
+        if !account_name(ctx.world, user).map(|n| n.eq_ignore_ascii_case(&args)).unwrap_or(false) {
+            continue;
+        }
+        for conn in conns {
+            if let Ok(mut entry) = ctx.world.entry_mut(conn) {
+                if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+                    prot.out_buffer.push_back(ProtocolOutEvent::Disconnect(Text::from("You have been disconnected by an administrator.")));
+                    kicked += 1;
+                }
+            }
+        }
+    }
+    if kicked == 0 {
+        return Err(format!("{} is not online", args));
+    }
+    Ok(json!({"connections": kicked}))
+}
+
+pub fn control_broadcast_command(ctx: &mut ControlContext, args: String, _cmds: &[ControlCmd]) -> Result<Value, String> {
+    let (all, message) = match args.strip_prefix("/all") {
+        Some(rest) => (true, rest.trim()),
+        None => (false, args.trim())
+    };
+    if message.is_empty() {
+        return Err("syntax: broadcast [/all] <message>".to_string());
+    }
+    let sent = broadcast(ctx.world, &announcement(message), all);
+    Ok(json!({"recipients": sent}))
+}
+
+pub fn control_reload_command(ctx: &mut ControlContext, _args: String, _cmds: &[ControlCmd]) -> Result<Value, String> {
+    ctx.reload_requested = true;
+    Ok(Value::Null)
+}
+
+pub fn control_savesessions_command(ctx: &mut ControlContext, _args: String, _cmds: &[ControlCmd]) -> Result<Value, String> {
+    let path = ctx.game.session_snapshot.as_ref().ok_or_else(|| "no session_snapshot configured".to_string())?;
+    let snapshots = snapshot_sessions(ctx.world);
+    save_snapshot(path, &snapshots).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    Ok(json!({"sessions": snapshots.len()}))
+}
+
+pub fn control_help_command(_ctx: &mut ControlContext, _args: String, cmds: &[ControlCmd]) -> Result<Value, String> {
+    let mut out: Vec<Value> = cmds.iter()
+        .map(|c| json!({"name": c.name, "syntax": c.syntax, "help": c.help}))
+        .collect();
+    out.insert(0, json!({"name": "auth", "syntax": "auth <token>", "help": "authenticates this connection"}));
+    Ok(Value::Array(out))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use crate::config::{GameConfig, NetConfig};
+    use crate::net::{ConnType, Protocol};
+    use crate::testing::Harness;
+
+    // Sends a line over the control port and returns every reply to it.
+    fn ask(h: &mut Harness, stream: &mut TcpStream, line: &str) -> String {
+        stream.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        h.tick(8);
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&out).to_string()
+    }
+
+    fn control_harness() -> (Harness, TcpStream, TcpStream) {
+        control_harness_with(None)
+    }
+
+    fn control_harness_with(game: Option<GameConfig>) -> (Harness, TcpStream, TcpStream) {
+        let net: NetConfig = serde_json::from_str(r#"{"control": {"addr": "127.0.0.1:0", "token": "sesame"}}"#).unwrap();
+        let mut h = Harness::with_config(Some(net), game);
+        h.engine.register_listener("127.0.0.1:0".parse().unwrap(), Protocol::Control, ConnType::Plain).unwrap();
+        let addr = h.engine.listeners_snapshot()[0].addr.unwrap();
+        let open = || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+            stream
+        };
+        let (first, second) = (open(), open());
+        h.tick(3);
+        (h, first, second)
+    }
+
+    #[test]
+    fn only_authenticated_control_connections_run_commands() {
+        let (mut h, mut operator, mut stranger) = control_harness();
+        let _alice = h.login("alice");
+
+        assert_eq!(ask(&mut h, &mut operator, "auth sesame").trim(), r#"{"ok":true,"result":null}"#);
+        let who = ask(&mut h, &mut operator, "who");
+        assert!(who.contains(r#""ok":true"#), "{}", who);
+        assert!(who.contains(r#""account":"alice""#), "{}", who);
+
+        let refused = ask(&mut h, &mut stranger, "who");
+        assert!(refused.contains(r#""error":"not authenticated""#), "{}", refused);
+        assert!(!refused.contains("alice"));
+        h.tick(3);
+        let mut buf = [0u8; 16];
+        assert_eq!(stranger.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn the_control_port_stays_open_while_draining() {
+        let (mut h, _first, _second) = control_harness();
+        h.engine.set_draining(true);
+        let addr = h.engine.listeners_snapshot()[0].addr.unwrap();
+        let mut operator = TcpStream::connect(addr).unwrap();
+        operator.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        h.tick(3);
+
+        assert_eq!(ask(&mut h, &mut operator, "auth sesame").trim(), r#"{"ok":true,"result":null}"#);
+        assert!(ask(&mut h, &mut operator, "who").contains(r#""ok":true"#));
+    }
+
+    #[test]
+    fn savesessions_writes_the_snapshot() {
+        use crate::game::snapshot::load_snapshot;
+        use crate::testing::temp_path;
+
+        let (mut h, mut operator, _) = control_harness();
+        ask(&mut h, &mut operator, "auth sesame");
+        assert!(ask(&mut h, &mut operator, "savesessions").contains(r#""error":"no session_snapshot configured""#));
+
+        let path = temp_path("sessions.json");
+        let (mut h, mut operator, _) = control_harness_with(Some(GameConfig {session_snapshot: Some(path.clone()), ..Default::default()}));
+        let _alice = h.login("alice");
+        ask(&mut h, &mut operator, "auth sesame");
+        let reply = ask(&mut h, &mut operator, "savesessions");
+        assert_eq!(reply.trim(), r#"{"ok":true,"result":{"sessions":1}}"#);
+        let saved = load_snapshot(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(saved[0].user.username, "alice");
+    }
+}
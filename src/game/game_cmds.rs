@@ -0,0 +1,1265 @@
+use legion::*;
+use legion::world::SubWorld;
+use legion::systems::CommandBuffer;
+use crate::game::objects::{MudSession, UserComponent, VitalsComponent, ExitsComponent,
+                           DescriptionComponent, ModuleComponent, AccountChanged};
+use crate::game::location::{expand_direction, is_direction, location_of, contents_of, move_object, name_of, send_to_room};
+use crate::game::input::{expand_aliases, substitute_variables, suggest_command, not_found_message};
+use crate::game::prompt::PromptTemplate;
+use crate::game::process::ProcessStep;
+use crate::game::timefmt::{format_timestamp, parse_utc_offset};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::mudstring::style::Style;
+use crate::mudstring::color::Color;
+use unicode_width::UnicodeWidthStr;
+use crate::net::{ProtocolOutEvent, ProtocolComponent, ProtocolCapabilities, ConnectionComponent, ClientProfile};
+use crate::mudstring::text::{Text, Segment};
+use crate::mudstring::colortest::color_test;
+
+
+// Everything a game command gets to work with while it runs inside execute_process.
+pub struct GameContext<'a, 'w> {
+    pub world: &'a mut SubWorld<'w>,
+    pub cmd: &'a mut CommandBuffer,
+    pub session: Entity,
+    pub user: Option<Entity>,
+    // The engine clock's time when the process started running, and the wall-clock time
+    // it gave for then.
+    pub now: Instant,
+    pub utc_now: DateTime<Utc>,
+    // Whether the server has pacing configured, so 'pace on' can do anything.
+    pub pacing_available: bool,
+    // Work the command left to be done in steps. See continue_with.
+    pub continuation: Option<Box<dyn ProcessStep>>
+}
+
+impl<'a, 'w> GameContext<'a, 'w> {
+    pub fn with_session<R>(&mut self, func: impl FnOnce(&mut MudSession) -> R) -> Option<R> {
+        let mut entry = self.world.entry_mut(self.session).ok()?;
+        let msess = entry.get_component_mut::<MudSession>().ok()?;
+        Some(func(msess))
+    }
+
+    pub fn with_user<R>(&mut self, func: impl FnOnce(&mut UserComponent) -> R) -> Option<R> {
+        let mut entry = self.world.entry_mut(self.user?).ok()?;
+        let user = entry.get_component_mut::<UserComponent>().ok()?;
+        Some(func(user))
+    }
+
+    // Finishes the command in steps instead of all at once, so it can't hold up the tick.
+    // The prompt is sent once the last step is done.
+    pub fn continue_with(&mut self, step: Box<dyn ProcessStep>) {
+        self.continuation = Some(step);
+    }
+
+    // Like with_user, for changes that should be saved with the account.
+    pub fn update_user<R>(&mut self, func: impl FnOnce(&mut UserComponent) -> R) -> Option<R> {
+        let user = self.user?;
+        let out = self.with_user(func)?;
+        self.cmd.add_component(user, AccountChanged);
+        Some(out)
+    }
+
+    pub fn send_line(&mut self, text: Text) {
+        self.with_session(|msess| msess.out_events.push_back(ProtocolOutEvent::Line(text)));
+    }
+
+    // Command lag: the session's next typed commands wait until lag has passed.
+    pub fn add_lag(&mut self, lag: Duration) {
+        let now = self.now;
+        self.with_session(|msess| msess.add_lag(now, lag));
+    }
+
+    pub fn is_admin(&mut self) -> bool {
+        self.with_user(|user| user.admin).unwrap_or(false)
+    }
+
+    // Whether an area by that name was loaded with protected set.
+    pub fn module_protected(&self, name: &str) -> bool {
+        <&ModuleComponent>::query().iter(self.world).any(|m| m.protected && m.sys_name.eq_ignore_ascii_case(name))
+    }
+
+    // Whether the session's user may run cmd: anyone, unless it's from a protected module.
+    pub fn may_use(&mut self, cmd: &GameCmd) -> bool {
+        match &cmd.module {
+            Some(module) if self.module_protected(module) => self.is_admin(),
+            _ => true
+        }
+    }
+
+    // Capabilities of one of the session's connections, for output that must be tailored to
+    // the client rather than left to rendering.
+    pub fn capabilities(&mut self) -> Option<ProtocolCapabilities> {
+        let conns: Vec<Entity> = self.with_session(|msess| msess.connections.iter().cloned().collect())?;
+        conns.into_iter().find_map(|conn| {
+            let entry = self.world.entry_ref(conn).ok()?;
+            entry.get_component::<ProtocolComponent>().ok().map(|prot| prot.capabilities.clone())
+        })
+    }
+
+    // Forces profile onto every connection of the session until it ends. Returns how many.
+    pub fn set_client_profile(&mut self, profile: ClientProfile) -> usize {
+        let conns: Vec<Entity> = self.with_session(|msess| msess.connections.iter().cloned().collect()).unwrap_or_default();
+        let mut set = 0;
+        for conn in conns {
+            if let Ok(mut entry) = self.world.entry_mut(conn) {
+                if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+                    prot.profile = Some(profile);
+                    profile.apply(&mut prot.capabilities);
+                    set += 1;
+                }
+            }
+        }
+        set
+    }
+
+    // Turns output pacing on or off for every connection of the session. Returns how many
+    // there were.
+    pub fn set_pacing(&mut self, on: bool) -> usize {
+        let conns: Vec<Entity> = self.with_session(|msess| msess.connections.iter().cloned().collect()).unwrap_or_default();
+        let mut set = 0;
+        for conn in conns {
+            if let Ok(mut entry) = self.world.entry_mut(conn) {
+                if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+                    prot.wants_pacing = on;
+                    set += 1;
+                }
+            }
+        }
+        set
+    }
+
+    // Board posts, tells, channel lines and the like should show times through this, so
+    // each player sees them in their own timezone.
+    pub fn format_timestamp(&mut self, ts: DateTime<Utc>) -> String {
+        let timezone = self.with_user(|user| user.timezone.clone()).flatten();
+        format_timestamp(ts, timezone.as_deref())
+    }
+
+    // Renders the player's prompt template, or default_template if they have none, against
+    // their current state and queues it. Sessions whose connections are gone get nothing.
+    pub fn send_prompt(&mut self, default_template: &str) {
+        if self.with_session(|msess| msess.connections.is_empty()).unwrap_or(true) {
+            return;
+        }
+        let template = self.with_user(|user| user.prompt.clone()).flatten()
+            .unwrap_or_else(|| default_template.to_string());
+        let template = PromptTemplate::parse(&template);
+        let values = self.prompt_values();
+        let rendered = template.render(&values);
+        self.with_session(|msess| msess.out_events.push_back(ProtocolOutEvent::Prompt(Text::from(rendered.as_ref()))));
+    }
+
+    fn prompt_values(&mut self) -> HashMap<char, String> {
+        let mut values = HashMap::new();
+        if let Some(user) = self.with_user(|user| user.clone()) {
+            values.insert('n', user.username.clone());
+            let now = self.utc_now;
+            let time = match user.timezone.as_deref().and_then(parse_utc_offset) {
+                Some(offset) => now.with_timezone(&offset).format("%H:%M").to_string(),
+                None => now.format("%H:%M").to_string()
+            };
+            values.insert('t', time);
+        }
+
+        let puppet = self.with_session(|msess| msess.puppet);
+        let vitals = puppet.and_then(|p| self.world.entry_ref(p).ok())
+            .and_then(|entry| entry.get_component::<VitalsComponent>().ok().cloned());
+        if let Some(v) = vitals {
+            for (code, value) in [('h', v.hp), ('H', v.max_hp), ('m', v.mana), ('M', v.max_mana),
+                                  ('v', v.moves), ('V', v.max_moves)].iter() {
+                values.insert(*code, value.to_string());
+            }
+        }
+        values
+    }
+
+    // Hands any output still queued on the session straight to its connections, then
+    // detaches them and ends each with the farewell.
+    pub fn close_connections(&mut self, farewell: Text) {
+        let (conns, pending) = self.with_session(|msess| {
+            (msess.connections.drain().collect::<Vec<Entity>>(), msess.out_events.drain(..).collect::<Vec<ProtocolOutEvent>>())
+        }).unwrap_or_default();
+
+        for conn in conns {
+            if let Ok(mut entry) = self.world.entry_mut(conn) {
+                if let Ok(prot) = entry.get_component_mut::<ProtocolComponent>() {
+                    prot.out_buffer.extend(pending.iter().cloned());
+                    prot.out_buffer.push_back(ProtocolOutEvent::Disconnect(farewell.clone()));
+                    prot.session = None;
+                    prot.user = None;
+                }
+            }
+        }
+    }
+}
+
+
+// The namespace of the built-in commands, so 'core:look' still works if a module adds its own.
+pub const CORE_MODULE: &str = "core";
+
+pub struct GameCommands {
+    pub cmds: Vec<GameCmd>,
+    // Called with the full input line when nothing in cmds matches.
+    pub fallback: fn(&mut GameContext, String, &GameCommands),
+    // Kept in step with GameConfig.suggest_commands.
+    pub suggest: bool
+}
+
+impl Default for GameCommands {
+    fn default() -> Self {
+        let cmds = vec![
+            GameCmd{name: "look".to_string(), aliases: vec!["l".to_string()],
+                func: game_look_command, help: "shows the room you're in: what it looks like, where you can go from here, and who and what is with you".to_string(),
+                syntax: "look".to_string(),
+                shorthelp: "look".to_string(), module: None},
+
+            GameCmd{name: "say".to_string(), aliases: Default::default(),
+                func: game_say_command, help: "says something to everyone in the room".to_string(),
+                syntax: "say <message>".to_string(),
+                shorthelp: "say <message>".to_string(), module: None},
+
+            GameCmd{name: "emote".to_string(), aliases: vec!["pose".to_string()],
+                func: game_emote_command, help: "shows everyone in the room an action, after your name. 'emote waves' shows 'Alice waves.'".to_string(),
+                syntax: "emote <action>".to_string(),
+                shorthelp: "emote <action>".to_string(), module: None},
+
+            GameCmd{name: "go".to_string(), aliases: Default::default(),
+                func: game_go_command, help: "walks through one of the room's exits. Typing the exit's name, like 'north' or 'n', does the same".to_string(),
+                syntax: "go <exit>".to_string(),
+                shorthelp: "go <exit>".to_string(), module: None},
+
+            GameCmd{name: "alias".to_string(), aliases: Default::default(),
+                func: game_alias_command, help: "lists, shows, or defines your command aliases. $1-$9 and $* in the expansion are replaced by the alias' arguments".to_string(),
+                syntax: "alias [<name>[=<expansion>]]".to_string(),
+                shorthelp: "alias [<name>[=<expansion>]]".to_string(), module: None},
+
+            GameCmd{name: "unalias".to_string(), aliases: Default::default(),
+                func: game_unalias_command, help: "removes one of your command aliases".to_string(),
+                syntax: "unalias <name>".to_string(),
+                shorthelp: "unalias <name>".to_string(), module: None},
+
+            GameCmd{name: "var".to_string(), aliases: Default::default(),
+                func: game_var_command, help: "lists, shows, or sets your variables. When substitution is on, $name in your commands is replaced by its value, and $$ gives a literal $".to_string(),
+                syntax: "var [<name>[=<value>]]".to_string(),
+                shorthelp: "var [<name>[=<value>]]".to_string(), module: None},
+
+            GameCmd{name: "unvar".to_string(), aliases: Default::default(),
+                func: game_unvar_command, help: "removes one of your variables".to_string(),
+                syntax: "unvar <name>".to_string(),
+                shorthelp: "unvar <name>".to_string(), module: None},
+
+            GameCmd{name: "varsub".to_string(), aliases: Default::default(),
+                func: game_varsub_command, help: "turns variable substitution in your commands on or off".to_string(),
+                syntax: "varsub <on|off>".to_string(),
+                shorthelp: "varsub <on|off>".to_string(), module: None},
+
+            GameCmd{name: "timezone".to_string(), aliases: Default::default(),
+                func: game_timezone_command, help: "shows or sets the timezone timestamps are shown in, as UTC or an offset like +02:00".to_string(),
+                syntax: "timezone [<offset>]".to_string(),
+                shorthelp: "timezone [<offset>]".to_string(), module: None},
+
+            GameCmd{name: "prompt".to_string(), aliases: Default::default(),
+                func: game_prompt_command, help: "shows or sets your prompt. %h/%H, %m/%M and %v/%V are your current and max hp, mana and moves, %n your name, %t the time and %% a literal %. 'prompt default' goes back to the game's prompt".to_string(),
+                syntax: "prompt [<template>|default]".to_string(),
+                shorthelp: "prompt [<template>|default]".to_string(), module: None},
+
+            GameCmd{name: "colortest".to_string(), aliases: Default::default(),
+                func: game_colortest_command, help: "shows the colors your client can display".to_string(),
+                syntax: "colortest".to_string(),
+                shorthelp: "colortest".to_string(), module: None},
+
+            GameCmd{name: "sessions".to_string(), aliases: Default::default(),
+                func: game_sessions_command, help: "(admin) lists everyone's sessions, or shows the connections of one".to_string(),
+                syntax: "sessions [<number>]".to_string(),
+                shorthelp: "sessions [<number>]".to_string(), module: None},
+
+            GameCmd{name: "broadcast".to_string(), aliases: vec!["wall".to_string()],
+                func: game_broadcast_command, help: "(admin) announces something to everyone playing. With /all, people still at the login screen see it too".to_string(),
+                syntax: "broadcast [/all] <message>".to_string(),
+                shorthelp: "broadcast [/all] <message>".to_string(), module: None},
+
+            GameCmd{name: "set".to_string(), aliases: Default::default(),
+                func: game_set_command, help: "(admin) makes your connection behave as another kind of client until you log out, for testing output. Profiles are ansi, xterm256, truecolor, plain and screenreader".to_string(),
+                syntax: "set client <profile>".to_string(),
+                shorthelp: "set client <profile>".to_string(), module: None},
+
+            GameCmd{name: CLEAR_QUEUE_COMMAND.to_string(), aliases: Default::default(),
+                func: game_clearqueue_command, help: "drops the commands you typed that are still waiting for lag to pass. It never waits itself".to_string(),
+                syntax: CLEAR_QUEUE_COMMAND.to_string(),
+                shorthelp: CLEAR_QUEUE_COMMAND.to_string(), module: None},
+
+            GameCmd{name: "pace".to_string(), aliases: Default::default(),
+                func: game_pace_command, help: "spreads bursts of output over several ticks, for slow links and screen readers that fall behind. Only works where the game has pacing configured".to_string(),
+                syntax: "pace on|off".to_string(),
+                shorthelp: "pace on|off".to_string(), module: None},
+
+            GameCmd{name: "quit".to_string(), aliases: vec!["logout".to_string()],
+                func: game_quit_command, help: "ends your session and disconnects you".to_string(),
+                syntax: "quit".to_string(),
+                shorthelp: "quit".to_string(), module: None},
+
+            GameCmd{name: "help".to_string(), aliases: Default::default(),
+                func: game_help_command, help: "displays help".to_string(),
+                syntax: "help [<topic>]".to_string(),
+                shorthelp: "help [<topic>]".to_string(), module: None}
+        ];
+
+        Self {
+            cmds,
+            fallback: game_not_found_command,
+            suggest: true
+        }
+    }
+}
+
+impl GameCommands {
+    pub fn execute(&self, ctx: &mut GameContext, command: String) {
+        let command = ctx.with_user(|user| {
+            if user.substitute_variables {
+                substitute_variables(&user.variables, &command)
+            } else {
+                command.clone()
+            }
+        }).unwrap_or(command);
+
+        let aliases = ctx.with_user(|user| user.aliases.clone()).unwrap_or_default();
+        let command = match expand_aliases(&aliases, &command) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                ctx.send_line(Text::from(e.as_ref()));
+                return;
+            }
+        };
+
+        let split: Vec<&str> = command.splitn(2, ' ').collect();
+        let comm = split[0].trim();
+        let args = if split.len() == 2 {
+            split[1].trim()
+        } else {
+            ""
+        };
+        for cmd in self.cmds.iter() {
+            if cmd.matches(comm) {
+                if !ctx.may_use(cmd) {
+                    ctx.send_line(Text::from("You don't have permission to do that."));
+                    return;
+                }
+                (cmd.func)(ctx, args.to_string(), &self.cmds);
+                return;
+            }
+        }
+        if try_move(ctx, &command) {
+            return;
+        }
+        (self.fallback)(ctx, command, self);
+    }
+
+    // Adds a command that belongs to module. It can always be typed as module:name, and by
+    // its bare name unless a command registered earlier already answers to that. Commands of
+    // a protected module are refused to anyone but admins.
+    pub fn register(&mut self, module: &str, mut cmd: GameCmd) {
+        cmd.module = Some(module.to_string());
+        self.cmds.push(cmd);
+    }
+
+    pub fn set_fallback(&mut self, func: fn(&mut GameContext, String, &GameCommands)) {
+        self.fallback = func;
+    }
+}
+
+
+pub struct GameCmd {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub func: fn(&mut GameContext, String, &[GameCmd]),
+    pub help: String,
+    pub syntax: String,
+    pub shorthelp: String,
+    // The module that registered it. None for the built-ins, which are in CORE_MODULE.
+    pub module: Option<String>
+}
+
+impl GameCmd {
+    pub fn namespace(&self) -> &str {
+        self.module.as_deref().unwrap_or(CORE_MODULE)
+    }
+
+    // The name as help lists it: bare for the built-ins, module:name for the rest.
+    pub fn qualified_name(&self) -> String {
+        match &self.module {
+            Some(module) => format!("{}:{}", module, self.name),
+            None => self.name.clone()
+        }
+    }
+
+    // Like name_match, but also takes module:name, which only matches in that module.
+    pub fn matches(&self, word: &str) -> bool {
+        match word.split_once(':') {
+            Some((module, name)) => self.namespace().eq_ignore_ascii_case(module) && self.name_match(name),
+            None => self.name_match(word)
+        }
+    }
+
+    pub fn name_match(&self, command: impl AsRef<str>) -> bool {
+        let upper = command.as_ref().to_uppercase();
+
+        if self.name.to_uppercase() == upper {
+            true
+        } else {
+            self.aliases.iter().any(|ali| ali.to_uppercase() == upper)
+        }
+    }
+}
+
+pub fn game_not_found_command(ctx: &mut GameContext, command: String, cmds: &GameCommands) {
+    let suggestion = if cmds.suggest {
+        // Never point someone at a command they aren't allowed to run.
+        let usable: Vec<&GameCmd> = cmds.cmds.iter().filter(|c| ctx.may_use(c)).collect();
+        suggest_command(&command, usable.iter().flat_map(|c| std::iter::once(&c.name).chain(c.aliases.iter())).map(|n| n.as_str()))
+    } else {
+        None
+    };
+    let line = not_found_message(&command, suggestion);
+    ctx.send_line(Text::from(line.as_ref()));
+}
+
+// Moves the session's puppet through the exit named by word. Returns false if word isn't an
+// exit here or a direction, so the caller can treat it as something else.
+fn try_move(ctx: &mut GameContext, word: &str) -> bool {
+    let dir = expand_direction(word);
+    let puppet = match ctx.with_session(|msess| msess.puppet) {
+        Some(puppet) => puppet,
+        None => return false
+    };
+    let here = location_of(ctx.world, puppet);
+    let dest = here.and_then(|room| ctx.world.entry_ref(room).ok())
+        .and_then(|entry| entry.get_component::<ExitsComponent>().ok().and_then(|exits| exits.0.get(&dir).cloned()));
+
+    let (here, dest) = match (here, dest) {
+        (Some(here), Some(dest)) => (here, dest),
+        _ => {
+            if !is_direction(&dir) {
+                return false;
+            }
+            ctx.send_line(Text::from("You can't go that way."));
+            return true;
+        }
+    };
+
+    if !move_object(ctx.world, puppet, dest) {
+        ctx.send_line(Text::from("Something stops you from going that way."));
+        return true;
+    }
+    let name = name_of(ctx.world, puppet);
+    send_to_room(ctx.world, here, &[puppet], &Text::from(format!("{} leaves {}.", name, dir).as_ref()));
+    send_to_room(ctx.world, dest, &[puppet], &Text::from(format!("{} arrives.", name).as_ref()));
+    look_at_room(ctx, dest, puppet);
+    true
+}
+
+// The room the session's puppet is in, with the puppet. Tells the player when there is none.
+fn puppet_room(ctx: &mut GameContext) -> Option<(Entity, Entity)> {
+    let puppet = ctx.with_session(|msess| msess.puppet)?;
+    let room = location_of(ctx.world, puppet);
+    if room.is_none() {
+        ctx.send_line(Text::from("You aren't anywhere."));
+    }
+    room.map(|room| (puppet, room))
+}
+
+pub fn game_say_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if args.is_empty() {
+        ctx.send_line(Text::from("Say what?"));
+        return;
+    }
+    if let Some((puppet, room)) = puppet_room(ctx) {
+        let name = name_of(ctx.world, puppet);
+        send_to_room(ctx.world, room, &[puppet], &Text::from(format!("{} says, \"{}\"", name, args).as_ref()));
+        ctx.send_line(Text::from(format!("You say, \"{}\"", args).as_ref()));
+    }
+}
+
+// Everyone, the actor included, sees the same line: there's no first person form of an
+// arbitrary action.
+pub fn game_emote_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if args.is_empty() {
+        ctx.send_line(Text::from("Emote what?"));
+        return;
+    }
+    if let Some((puppet, room)) = puppet_room(ctx) {
+        let name = name_of(ctx.world, puppet);
+        let mut line = format!("{} {}", name, args);
+        if !line.ends_with(|c: char| c.is_ascii_punctuation()) {
+            line.push('.');
+        }
+        send_to_room(ctx.world, room, &[puppet], &Text::from(line.as_ref()));
+        ctx.send_line(Text::from(line.as_ref()));
+    }
+}
+
+// Greedy word wrap by display width. Words longer than width get a line to themselves.
+fn wrap_words(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.width() + 1 + word.width() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+// Sends viewer's session the room's name, description wrapped to the client's width, its
+// exits, and everything in it but the viewer. Color is left to rendering, so clients
+// without it just get the text.
+pub fn look_at_room(ctx: &mut GameContext, room: Entity, viewer: Entity) {
+    let width = ctx.capabilities().map(|caps| caps.width as usize).unwrap_or(78).max(20);
+    let (description, exits) = match ctx.world.entry_ref(room) {
+        Ok(entry) => {
+            let description = entry.get_component::<DescriptionComponent>().ok().map(|d| d.0.clone());
+            let mut exits: Vec<String> = entry.get_component::<ExitsComponent>().ok()
+                .map(|e| e.0.keys().cloned().collect())
+                .unwrap_or_default();
+            exits.sort();
+            (description, exits)
+        },
+        Err(_) => return
+    };
+
+    let title = Style {bold: true, color: Some(Color::from(14u8)), ..Default::default()};
+    ctx.send_line(Text::from(vec![Segment {text: name_of(ctx.world, room), style: Some(title)}]));
+    for line in description.map(|d| wrap_words(&d, width)).unwrap_or_default() {
+        ctx.send_line(Text::from(line.as_ref()));
+    }
+
+    let exit_style = Style {color: Some(Color::from(2u8)), ..Default::default()};
+    let exits = if exits.is_empty() { "none".to_string() } else { exits.join(", ") };
+    ctx.send_line(Text::from(vec![
+        Segment {text: "Exits: ".to_string(), style: None},
+        Segment {text: exits, style: Some(exit_style)}
+    ]));
+
+    let mut present: Vec<String> = contents_of(ctx.world, room).into_iter()
+        .filter(|obj| *obj != viewer)
+        .map(|obj| name_of(ctx.world, obj))
+        .collect();
+    present.sort();
+    for name in present {
+        ctx.send_line(Text::from(format!("{} is here.", name).as_ref()));
+    }
+}
+
+pub fn game_look_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+    if let Some((puppet, room)) = puppet_room(ctx) {
+        look_at_room(ctx, room, puppet);
+    }
+}
+
+pub fn game_go_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if args.is_empty() {
+        ctx.send_line(Text::from("Go where?"));
+    } else if !try_move(ctx, &args) {
+        ctx.send_line(Text::from("You can't go that way."));
+    }
+}
+
+pub fn game_alias_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if args.is_empty() {
+        let listing = ctx.with_user(|user| {
+            let mut names: Vec<&String> = user.aliases.keys().collect();
+            names.sort();
+            names.iter().map(|n| format!("{} = {}", n, user.aliases[*n])).collect::<Vec<String>>()
+        }).unwrap_or_default();
+        if listing.is_empty() {
+            ctx.send_line(Text::from("You have no aliases defined."));
+        } else {
+            ctx.send_line(Text::from(listing.join("\n").as_ref()));
+        }
+        return;
+    }
+
+    let split: Vec<&str> = args.splitn(2, '=').collect();
+    let name = split[0].trim().to_lowercase();
+    if name.is_empty() || name.contains(' ') {
+        ctx.send_line(Text::from("SYNTAX: alias [<name>[=<expansion>]]"));
+        return;
+    }
+
+    if split.len() == 1 {
+        let found = ctx.with_user(|user| user.aliases.get(&name).cloned()).flatten();
+        match found {
+            Some(body) => ctx.send_line(Text::from(format!("{} = {}", name, body).as_ref())),
+            None => ctx.send_line(Text::from(format!("You have no alias named '{}'.", name).as_ref()))
+        }
+        return;
+    }
+
+    let body = split[1].trim().to_string();
+    if body.is_empty() {
+        ctx.send_line(Text::from("SYNTAX: alias [<name>[=<expansion>]]"));
+        return;
+    }
+
+    let result: Option<Result<(), String>> = ctx.update_user(|user| {
+        let mut trial = user.aliases.clone();
+        trial.insert(name.clone(), body.clone());
+        expand_aliases(&trial, &name)?;
+        user.aliases = trial;
+        Ok(())
+    });
+    match result {
+        Some(Ok(())) => ctx.send_line(Text::from(format!("Alias '{}' set.", name).as_ref())),
+        Some(Err(e)) => ctx.send_line(Text::from(format!("Alias refused: {}", e).as_ref())),
+        None => ctx.send_line(Text::from("You have no account to store aliases on."))
+    }
+}
+
+pub fn game_unalias_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    let name = args.trim().to_lowercase();
+    if name.is_empty() {
+        ctx.send_line(Text::from("SYNTAX: unalias <name>"));
+        return;
+    }
+    let removed = ctx.update_user(|user| user.aliases.remove(&name)).flatten();
+    if removed.is_some() {
+        ctx.send_line(Text::from(format!("Alias '{}' removed.", name).as_ref()));
+    } else {
+        ctx.send_line(Text::from(format!("You have no alias named '{}'.", name).as_ref()));
+    }
+}
+
+pub fn game_var_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if args.is_empty() {
+        let listing = ctx.with_user(|user| {
+            let mut names: Vec<&String> = user.variables.keys().collect();
+            names.sort();
+            names.iter().map(|n| format!("${} = {}", n, user.variables[*n])).collect::<Vec<String>>()
+        }).unwrap_or_default();
+        if listing.is_empty() {
+            ctx.send_line(Text::from("You have no variables defined."));
+        } else {
+            ctx.send_line(Text::from(listing.join("\n").as_ref()));
+        }
+        return;
+    }
+
+    let split: Vec<&str> = args.splitn(2, '=').collect();
+    let name = split[0].trim().trim_start_matches('$').to_lowercase();
+    let valid = name.chars().next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false)
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        ctx.send_line(Text::from("Variable names must start with a letter and contain only letters, digits, and _."));
+        return;
+    }
+
+    if split.len() == 1 {
+        let found = ctx.with_user(|user| user.variables.get(&name).cloned()).flatten();
+        match found {
+            Some(value) => ctx.send_line(Text::from(format!("${} = {}", name, value).as_ref())),
+            None => ctx.send_line(Text::from(format!("You have no variable named '{}'.", name).as_ref()))
+        }
+        return;
+    }
+
+    let value = split[1].trim().to_string();
+    let stored = ctx.update_user(|user| user.variables.insert(name.clone(), value));
+    if stored.is_some() {
+        ctx.send_line(Text::from(format!("Variable '{}' set.", name).as_ref()));
+    } else {
+        ctx.send_line(Text::from("You have no account to store variables on."));
+    }
+}
+
+pub fn game_unvar_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    let name = args.trim().trim_start_matches('$').to_lowercase();
+    if name.is_empty() {
+        ctx.send_line(Text::from("SYNTAX: unvar <name>"));
+        return;
+    }
+    let removed = ctx.update_user(|user| user.variables.remove(&name)).flatten();
+    if removed.is_some() {
+        ctx.send_line(Text::from(format!("Variable '{}' removed.", name).as_ref()));
+    } else {
+        ctx.send_line(Text::from(format!("You have no variable named '{}'.", name).as_ref()));
+    }
+}
+
+pub fn game_varsub_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    let enable = match args.trim().to_lowercase().as_str() {
+        "on" => true,
+        "off" => false,
+        _ => {
+            ctx.send_line(Text::from("SYNTAX: varsub <on|off>"));
+            return;
+        }
+    };
+    if ctx.update_user(|user| user.substitute_variables = enable).is_some() {
+        ctx.send_line(Text::from(format!("Variable substitution is now {}.", args.trim().to_lowercase()).as_ref()));
+    } else {
+        ctx.send_line(Text::from("You have no account to store that setting on."));
+    }
+}
+
+pub fn game_timezone_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    let args = args.trim();
+    if args.is_empty() {
+        let now = ctx.format_timestamp(ctx.utc_now);
+        ctx.send_line(Text::from(format!("Your time is now {}.", now).as_ref()));
+        return;
+    }
+    if parse_utc_offset(args).is_none() {
+        ctx.send_line(Text::from("Timezones are UTC or an offset like +02:00 or -0530."));
+        return;
+    }
+    let timezone = if args.eq_ignore_ascii_case("utc") { None } else { Some(args.to_string()) };
+    if ctx.update_user(|user| user.timezone = timezone).is_some() {
+        let now = ctx.format_timestamp(ctx.utc_now);
+        ctx.send_line(Text::from(format!("Timezone set. Your time is now {}.", now).as_ref()));
+    } else {
+        ctx.send_line(Text::from("You have no account to store that setting on."));
+    }
+}
+
+pub fn game_prompt_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if args.is_empty() {
+        let current = ctx.with_user(|user| user.prompt.clone()).flatten();
+        let msg = match current {
+            Some(p) => format!("Your prompt is: {}", p),
+            None => "You are using the default prompt.".to_string()
+        };
+        ctx.send_line(Text::from(msg.as_ref()));
+        return;
+    }
+    let template = if args.eq_ignore_ascii_case("default") { None } else { Some(args) };
+    if ctx.update_user(|user| user.prompt = template).is_some() {
+        ctx.send_line(Text::from("Prompt set."));
+    } else {
+        ctx.send_line(Text::from("You have no account to store that setting on."));
+    }
+}
+
+pub fn game_colortest_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+    let color = ctx.capabilities().and_then(|c| c.color);
+    for line in color_test(color) {
+        ctx.send_line(line);
+    }
+}
+
+fn format_idle(idle: Duration) -> String {
+    let secs = idle.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn header(text: String) -> Text {
+    let style = Style {bold: true, ..Default::default()};
+    Text::from(vec![Segment {text, style: Some(style)}])
+}
+
+pub fn game_sessions_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if !ctx.is_admin() {
+        ctx.send_line(Text::from("You don't have permission to do that."));
+        return;
+    }
+
+    let mut sessions: Vec<(Entity, Entity, Entity, Instant, Instant, Vec<Entity>)> = <(Entity, &MudSession)>::query()
+        .iter(ctx.world)
+        .map(|(ent, msess)| (*ent, msess.user, msess.puppet, msess.created, msess.last_input, msess.connections.iter().cloned().collect()))
+        .collect();
+    sessions.sort_by_key(|s| s.3);
+
+    let args = args.trim();
+    if args.is_empty() {
+        if sessions.is_empty() {
+            ctx.send_line(Text::from("There are no sessions."));
+            return;
+        }
+        ctx.send_line(header(format!("{:>3}  {:<20} {:>5} {:>8}  {}", "#", "Account", "Conns", "Idle", "Location")));
+        for (i, (_, user, puppet, _, last_input, conns)) in sessions.iter().enumerate() {
+            let account = ctx.world.entry_ref(*user).ok()
+                .and_then(|e| e.get_component::<UserComponent>().ok().map(|u| u.username.clone()))
+                .unwrap_or_else(|| "-".to_string());
+            let location = location_of(ctx.world, *puppet)
+                .map(|room| name_of(ctx.world, room))
+                .unwrap_or_else(|| "-".to_string());
+            let account = Text::from(account.as_ref()).truncate_display(20, Some("...")).plain;
+            let idle = format_idle(ctx.now.saturating_duration_since(*last_input));
+            ctx.send_line(Text::from(format!("{:>3}  {:<20} {:>5} {:>8}  {}", i + 1, account, conns.len(), idle, location).as_ref()));
+        }
+        return;
+    }
+
+    let conns = match args.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|n| sessions.get(n)) {
+        Some(s) => s.5.clone(),
+        None => {
+            ctx.send_line(Text::from(format!("There is no session {}.", args).as_ref()));
+            return;
+        }
+    };
+    ctx.send_line(header(format!("{:<24} {:<10} {:<20} {:<10} {}", "Address", "Protocol", "Client", "Color", "Echo")));
+    for conn in conns {
+        let line = match ctx.world.entry_ref(conn) {
+            Ok(entry) => {
+                let addr = entry.get_component::<ConnectionComponent>().map(|c| c.addr.to_string()).unwrap_or_default();
+                match entry.get_component::<ProtocolComponent>() {
+                    Ok(prot) => {
+                        let caps = &prot.capabilities;
+                        format!("{:<24} {:<10} {:<20} {:<10} {}", addr, caps.protocol.name(),
+                                format!("{} {}", caps.client_name, caps.client_version),
+                                caps.color.map(|c| format!("{:?}", c)).unwrap_or_else(|| "none".to_string()),
+                                prot.echo_state())
+                    },
+                    Err(_) => addr
+                }
+            },
+            Err(_) => continue
+        };
+        ctx.send_line(Text::from(line.as_ref()));
+    }
+}
+
+// The text of a server-wide announcement.
+pub fn announcement(message: &str) -> Text {
+    let tag = Style {bold: true, color: Some(Color::from(11u8)), ..Default::default()};
+    Text::from(vec![Segment {text: "[Announcement]".to_string(), style: Some(tag)}]) + Text::from(format!(" {}", message).as_ref())
+}
+
+// Queues text for every session, and with include_unauthenticated also for every player
+// connection that hasn't logged in yet. Each recipient renders it for their own client.
+// Returns how many sessions and connections it went to.
+pub fn broadcast(world: &mut SubWorld, text: &Text, include_unauthenticated: bool) -> usize {
+    let mut sent = 0;
+    for msess in <&mut MudSession>::query().iter_mut(world) {
+        msess.out_events.push_back(ProtocolOutEvent::Line(text.clone()));
+        sent += 1;
+    }
+    if include_unauthenticated {
+        for prot in <&mut ProtocolComponent>::query().iter_mut(world) {
+            if prot.session.is_none() && !prot.is_control() && !prot.closing && !prot.disconnecting {
+                prot.out_buffer.push_back(ProtocolOutEvent::Line(text.clone()));
+                sent += 1;
+            }
+        }
+    }
+    sent
+}
+
+pub fn game_broadcast_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if !ctx.is_admin() {
+        ctx.send_line(Text::from("You don't have permission to do that."));
+        return;
+    }
+    let args = args.trim();
+    let (all, message) = match args.split_once(char::is_whitespace) {
+        Some((flag, rest)) if flag.eq_ignore_ascii_case("/all") => (true, rest.trim()),
+        None if args.eq_ignore_ascii_case("/all") => (true, ""),
+        _ => (false, args)
+    };
+    if message.is_empty() {
+        ctx.send_line(Text::from("SYNTAX: broadcast [/all] <message>"));
+        return;
+    }
+    broadcast(ctx.world, &announcement(message), all);
+}
+
+pub fn game_set_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    if !ctx.is_admin() {
+        ctx.send_line(Text::from("You don't have permission to do that."));
+        return;
+    }
+    let mut words = args.split_whitespace();
+    let profile = match (words.next(), words.next(), words.next()) {
+        (Some(what), Some(name), None) if what.eq_ignore_ascii_case("client") => ClientProfile::from_name(name),
+        _ => {
+            ctx.send_line(Text::from("SYNTAX: set client <profile>"));
+            return;
+        }
+    };
+    match profile {
+        Some(profile) => {
+            let set = ctx.set_client_profile(profile);
+            ctx.send_line(Text::from(format!("Client profile set to {} on {} connection{}.", profile.name(), set,
+                                             if set == 1 { "" } else { "s" }).as_ref()));
+        },
+        None => ctx.send_line(Text::from(format!("Profiles are: {}", ClientProfile::NAMES.join(", ")).as_ref()))
+    }
+}
+
+pub fn game_pace_command(ctx: &mut GameContext, args: String, _cmds: &[GameCmd]) {
+    let on = match args.trim().to_lowercase().as_str() {
+        "on" => true,
+        "off" => false,
+        _ => {
+            ctx.send_line(Text::from("SYNTAX: pace on|off"));
+            return;
+        }
+    };
+    if on && !ctx.pacing_available {
+        ctx.send_line(Text::from("Output pacing isn't available on this server."));
+        return;
+    }
+    let msg = match ctx.set_pacing(on) {
+        0 => "You have no connections to pace.",
+        _ if on => "Output pacing is on.",
+        _ => "Output pacing is off."
+    };
+    ctx.send_line(Text::from(msg));
+}
+
+// Typed while lagged, this empties the session's command queue instead of joining it.
+pub const CLEAR_QUEUE_COMMAND: &str = "clearqueue";
+
+// Whether line runs clearqueue, which session_in_events never queues and execute_process
+// never holds back for lag.
+pub fn is_clear_queue(line: &str) -> bool {
+    line.trim().eq_ignore_ascii_case(CLEAR_QUEUE_COMMAND)
+}
+
+pub fn game_clearqueue_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+    let dropped = ctx.with_session(|msess| {
+        let dropped = msess.command_queue.len();
+        msess.command_queue.clear();
+        dropped
+    }).unwrap_or(0);
+    ctx.send_line(Text::from(format!("Cleared {} queued command(s).", dropped).as_str()));
+}
+
+pub fn game_quit_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+    ctx.close_connections(Text::from("Goodbye!"));
+}
+
+pub fn game_help_command(ctx: &mut GameContext, args: String, cmds: &[GameCmd]) {
+    if args.is_empty() {
+        let mut out = String::new();
+        for cmd in cmds {
+            out += format!("{} | {} | {}\n", cmd.qualified_name(), cmd.syntax, cmd.shorthelp).as_str();
+        }
+        ctx.send_line(Text::from(out.as_ref()));
+    } else if let Some(res) = cmds.iter().find(|x| x.matches(&args)) {
+        ctx.send_line(Text::from(res.help.as_ref()));
+    } else {
+        ctx.send_line(Text::from("Sorry, no help found for that! try help without arguments"));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::GameConfig;
+    use crate::game::objects::{ContentsComponent, LocationComponent, MudProtoTypeComponent};
+    use crate::testing::{temp_path, Harness, TWO_ROOMS};
+
+    // Everyone logs in to The Void, which leads north to A Hall.
+    fn rooms_harness() -> Harness {
+        let area = temp_path("area.json");
+        std::fs::write(&area, TWO_ROOMS).unwrap();
+        let game = GameConfig {areas: vec![area.clone()], start_room: Some("limbo/void".to_string()), ..Default::default()};
+        let h = Harness::with_game(game);
+        std::fs::remove_file(area).unwrap();
+        h
+    }
+
+    fn proto(h: &Harness, objid: &str) -> Entity {
+        <&MudProtoTypeComponent>::query().iter(&h.engine.world)
+            .find(|p| p.objid == objid)
+            .map(|p| p.entity)
+            .unwrap()
+    }
+
+    fn location(h: &Harness, username: &str) -> Option<Entity> {
+        <(&UserComponent, &LocationComponent)>::query().iter(&h.engine.world)
+            .find(|(u, _)| u.username == username)
+            .map(|(_, loc)| loc.entity)
+    }
+
+    fn echo_fallback(ctx: &mut GameContext, command: String, _cmds: &GameCommands) {
+        ctx.send_line(Text::from(format!("fallback: {}", command).as_ref()));
+    }
+
+    #[test]
+    fn registered_fallback_receives_unmatched_input() {
+        let mut h = Harness::new();
+        h.engine.resources.get_mut::<GameCommands>().unwrap().set_fallback(echo_fallback);
+        let mut client = h.login("alice");
+        assert!(h.command(&mut client, "smile warmly").contains("fallback: smile warmly"));
+        assert!(!h.command(&mut client, "look").contains("fallback"));
+    }
+
+    #[test]
+    fn only_connections_that_asked_for_pacing_are_paced() {
+        use crate::config::NetConfig;
+
+        let net: NetConfig = serde_json::from_str(r#"{"pacing": {"lines_per_tick": 2}}"#).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        let mut client = h.login("alice");
+        let burst = |h: &mut Harness, client: &mut crate::testing::Client| {
+            for prot in <&mut ProtocolComponent>::query().iter_mut(&mut h.engine.world) {
+                prot.out_buffer.extend((0..10).map(|i| ProtocolOutEvent::Line(Text::from(format!("hit {}", i).as_ref()))));
+            }
+            // One tick to send, one for it to reach the socket.
+            h.tick(2);
+            client.text().matches("hit ").count()
+        };
+        assert_eq!(burst(&mut h, &mut client), 10);
+
+        assert!(h.command(&mut client, "pace on").contains("Output pacing is on."));
+        assert_eq!(burst(&mut h, &mut client), 2);
+        h.tick(6);
+        assert_eq!(client.text().matches("hit ").count(), 8);
+
+        h.command(&mut client, "pace off");
+        assert_eq!(burst(&mut h, &mut client), 10);
+    }
+
+    #[test]
+    fn pace_says_so_when_the_server_has_no_pacing() {
+        let mut h = Harness::new();
+        let mut client = h.login("alice");
+        let text = h.command(&mut client, "pace on");
+        assert!(text.contains("Output pacing isn't available on this server."), "{:?}", text);
+        assert!(<&ProtocolComponent>::query().iter(&h.engine.world).all(|prot| !prot.wants_pacing));
+    }
+
+    #[test]
+    fn a_mistyped_game_command_suggests_the_real_one_unless_turned_off() {
+        let mut h = Harness::new();
+        let mut client = h.login("alice");
+        assert!(h.command(&mut client, "lok").contains("Did you mean 'look'?"));
+        let text = h.command(&mut client, "frobnicate");
+        assert!(text.contains("Sorry, frobnicate that isn't a command. Type 'help' for help."), "{:?}", text);
+
+        let mut h = Harness::with_game(GameConfig {suggest_commands: false, ..Default::default()});
+        let mut client = h.login("alice");
+        assert!(!h.command(&mut client, "lok").contains("Did you mean"));
+    }
+
+    fn zap_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+        ctx.send_line(Text::from("Zap!"));
+    }
+
+    #[test]
+    fn a_protected_modules_commands_are_for_admins_only() {
+        let area = temp_path("area.json");
+        std::fs::write(&area, r#"{"name": "wizard", "protected": true}"#).unwrap();
+        let mut h = Harness::with_game(GameConfig {areas: vec![area.clone()], ..Default::default()});
+        std::fs::remove_file(area).unwrap();
+        h.engine.resources.get_mut::<GameCommands>().unwrap().register("wizard", GameCmd {
+            name: "zap".to_string(), aliases: Vec::new(), func: zap_command, help: "zaps".to_string(),
+            syntax: "zap".to_string(), shorthelp: "zap".to_string(), module: None
+        });
+        let mut alice = h.login("alice");
+        let mut bob = h.login("bob");
+        h.make_admin("alice");
+
+        for line in ["zap", "wizard:zap"] {
+            let text = h.command(&mut bob, line);
+            assert!(text.contains("You don't have permission to do that."), "{:?}", text);
+            assert!(!text.contains("Zap!"));
+            assert!(h.command(&mut alice, line).contains("Zap!"));
+        }
+        // Nor is it suggested to anyone who couldn't run it.
+        assert!(!h.command(&mut bob, "zapp").contains("Did you mean 'zap'?"));
+        assert!(h.command(&mut alice, "zapp").contains("Did you mean 'zap'?"));
+    }
+
+    #[test]
+    fn quit_detaches_the_session_and_closes() {
+        let mut h = Harness::new();
+        let mut client = h.login("alice");
+        assert!(h.command(&mut client, "quit").contains("Goodbye!"));
+        h.tick(3);
+        assert!(client.is_closed());
+        assert!(<&MudSession>::query().iter(&h.engine.world).all(|msess| msess.connections.is_empty()));
+    }
+
+    #[test]
+    fn quit_at_the_login_screen_closes_immediately() {
+        let mut h = Harness::new();
+        let mut client = h.connect();
+        assert!(h.command(&mut client, "quit").contains("Goodbye!"));
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn moving_through_an_exit_changes_rooms_and_tells_onlookers() {
+        let mut h = rooms_harness();
+        let mut alice = h.login("alice");
+        let mut bob = h.login("bob");
+        bob.text();
+
+        let text = h.command(&mut alice, "n");
+        assert!(text.contains("A Hall"), "{:?}", text);
+        assert_eq!(location(&h, "alice"), Some(proto(&h, "limbo/hall")));
+        assert!(bob.text().contains("alice leaves north."));
+        let hall = <(&MudProtoTypeComponent, &ContentsComponent)>::query().iter(&h.engine.world)
+            .find(|(p, _)| p.objid == "limbo/hall")
+            .map(|(_, c)| c.0.clone())
+            .unwrap();
+        assert_eq!(hall.len(), 1);
+
+        assert!(h.command(&mut alice, "go south").contains("The Void"));
+        assert!(bob.text().contains("alice arrives."));
+    }
+
+    #[test]
+    fn exits_still_work_with_a_replaced_fallback() {
+        let mut h = rooms_harness();
+        h.engine.resources.get_mut::<GameCommands>().unwrap().set_fallback(echo_fallback);
+        let mut alice = h.login("alice");
+        let text = h.command(&mut alice, "north");
+        assert!(text.contains("A Hall") && !text.contains("fallback"), "{:?}", text);
+        assert_eq!(location(&h, "alice"), Some(proto(&h, "limbo/hall")));
+        assert!(h.command(&mut alice, "smile").contains("fallback: smile"));
+    }
+
+    #[test]
+    fn moving_where_there_is_no_exit_is_refused() {
+        let mut h = rooms_harness();
+        let mut alice = h.login("alice");
+        let void = proto(&h, "limbo/void");
+        assert!(h.command(&mut alice, "west").contains("You can't go that way."));
+        assert!(h.command(&mut alice, "go portal").contains("You can't go that way."));
+        assert_eq!(location(&h, "alice"), Some(void));
+    }
+
+    #[test]
+    fn look_lists_the_exits_and_everything_else_in_the_room() {
+        let mut h = rooms_harness();
+        let mut alice = h.login("alice");
+        let _bob = h.login("bob");
+        let (void, rock, lamp) = (proto(&h, "limbo/void"), proto(&h, "limbo/rock"), proto(&h, "limbo/lamp"));
+        // The item prototypes stand in for items; look only needs them in the room's contents.
+        let mut entry = h.engine.world.entry(void).unwrap();
+        let contents = entry.get_component_mut::<ContentsComponent>().unwrap();
+        contents.0.insert(rock);
+        contents.0.insert(lamp);
+
+        let text = h.command(&mut alice, "look");
+        let lines: Vec<&str> = text.lines().map(|l| l.trim_end()).filter(|l| !l.is_empty()).collect();
+        assert_eq!(&lines[..6], &["The Void", "Nothing at all.", "Exits: north",
+            "a brass lamp is here.", "a rock is here.", "bob is here."], "{:?}", text);
+        assert!(!text.contains("alice is here."));
+    }
+
+    #[test]
+    fn say_and_emote_reach_only_the_room() {
+        let mut h = rooms_harness();
+        let mut alice = h.login("alice");
+        let mut bob = h.login("bob");
+        let mut carol = h.login("carol");
+        h.send(&mut carol, "north");
+        bob.text();
+        carol.text();
+
+        let text = h.command(&mut alice, "say Hello there.");
+        assert!(text.contains("You say, \"Hello there.\""), "{:?}", text);
+        assert!(!text.contains("alice says"));
+        let heard = bob.text();
+        assert!(heard.contains("alice says, \"Hello there.\""), "{:?}", heard);
+        assert!(!heard.contains("You say"));
+
+        assert!(h.command(&mut alice, "emote waves").contains("alice waves."));
+        assert!(bob.text().contains("alice waves."));
+        assert!(!carol.text().contains("alice"));
+    }
+
+    #[test]
+    fn sessions_lists_connection_counts_and_rooms() {
+        let area = temp_path("area.json");
+        std::fs::write(&area, r#"{"name": "limbo", "prototypes": [{"id": "void", "type": "Room", "name": "The Void"}]}"#).unwrap();
+        let game = GameConfig {areas: vec![area.clone()], start_room: Some("limbo/void".to_string()), ..Default::default()};
+        let mut h = Harness::with_game(game);
+        std::fs::remove_file(area).unwrap();
+        let mut alice = h.login("alice");
+        let mut second = h.connect();
+        h.send(&mut second, "connect alice=password");
+        let mut bob = h.login("bob");
+        h.make_admin("alice");
+
+        let listing = h.command(&mut alice, "sessions");
+        let row = |name: &str| listing.lines().find(|l| l.contains(name)).unwrap_or_else(|| panic!("{:?}", listing)).to_string();
+        let conns = |name: &str| row(name).split_whitespace().nth(2).unwrap().to_string();
+        assert_eq!(conns("alice"), "2");
+        assert_eq!(conns("bob"), "1");
+        assert!(row("alice").ends_with("The Void"), "{:?}", listing);
+        assert!(row("bob").ends_with("The Void"));
+
+        assert!(h.command(&mut bob, "sessions").contains("permission"));
+    }
+
+    #[test]
+    fn broadcast_reaches_everyone_rendered_for_their_client() {
+        use crate::mudstring::color::ColorSystem;
+
+        let mut h = Harness::new();
+        let mut alice = h.login("alice");
+        let mut carol = h.login("carol");
+        let mut guest = h.connect();
+        h.make_admin("alice");
+        h.set_color("carol", Some(ColorSystem::EightBit));
+
+        h.send(&mut alice, "wall Rebooting soon.");
+        let plain = alice.read();
+        let colored = carol.read();
+        assert!(plain.contains("[Announcement] Rebooting soon."), "{:?}", plain);
+        assert!(!plain.contains('\x1b'));
+        assert!(colored.contains("Rebooting soon."));
+        assert!(colored.contains('\x1b'), "{:?}", colored);
+        assert!(!guest.text().contains("Rebooting"));
+
+        h.send(&mut alice, "broadcast /all Back in five.");
+        assert!(guest.text().contains("[Announcement] Back in five."));
+        // Only a whole /all word widens it.
+        h.send(&mut alice, "broadcast /allies are here.");
+        assert!(carol.text().contains("/allies are here."));
+        assert!(!guest.text().contains("allies"));
+        assert!(h.command(&mut carol, "wall hi").contains("permission"));
+    }
+
+    #[test]
+    fn set_client_plain_makes_later_output_color_free() {
+        let mut h = Harness::new();
+        let mut alice = h.login("alice");
+        h.make_admin("alice");
+        assert!(h.command(&mut alice, "set client truecolor").contains("Client profile set to truecolor on 1 connection."));
+        h.send(&mut alice, "wall Colorful.");
+        assert!(alice.read().contains("\x1b["));
+
+        assert!(h.command(&mut alice, "set client plain").contains("Client profile set to plain"));
+        h.send(&mut alice, "wall Plain.");
+        let text = alice.read();
+        assert!(text.contains("Plain."));
+        assert!(!text.contains('\x1b'), "{:?}", text);
+    }
+
+    #[test]
+    fn prompt_and_timezone_times_come_from_the_engine_clock() {
+        use chrono::TimeZone;
+
+        let mut h = Harness::new();
+        h.clock.set_utc(Utc.with_ymd_and_hms(2026, 1, 1, 12, 34, 0).unwrap());
+        let mut client = h.login("alice");
+        assert!(h.command(&mut client, "timezone").contains("Your time is now 2026-01-01 12:34 UTC."));
+        assert!(h.command(&mut client, "timezone +02:00").contains("2026-01-01 14:34 +02:00"));
+
+        h.send(&mut client, "prompt [%t %n %z]");
+        assert!(h.command(&mut client, "look").contains("[14:34 alice %z]"));
+        h.clock.advance(Duration::from_secs(60));
+        assert!(h.command(&mut client, "look").contains("[14:35 alice %z]"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet};
+
+// How many aliases may chain into one another before we give up.
+pub const MAX_ALIAS_DEPTH: usize = 10;
+
+fn split_command(command: &str) -> (&str, &str) {
+    let split: Vec<&str> = command.trim().splitn(2, ' ').collect();
+    if split.len() == 2 {
+        (split[0], split[1].trim())
+    } else {
+        (split[0], "")
+    }
+}
+
+// Fills $1-$9 and $* in an alias body from the arguments it was called with. If the body
+// doesn't use any of them, the arguments are appended instead, so 'k' = 'kill' works with
+// 'k orc'.
+pub fn substitute_alias_args(body: &str, args: &str) -> String {
+    let words: Vec<&str> = args.split_whitespace().collect();
+    let mut out = String::with_capacity(body.len() + args.len());
+    let mut used = false;
+    let mut chars = body.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('*') => {
+                chars.next();
+                out.push_str(args);
+                used = true;
+            },
+            Some(d) if ('1'..='9').contains(d) => {
+                let idx = d.to_digit(10).unwrap() as usize - 1;
+                chars.next();
+                if let Some(w) = words.get(idx) {
+                    out.push_str(w);
+                }
+                used = true;
+            },
+            _ => {
+                out.push(c);
+            }
+        }
+    }
+
+    if !used && !args.is_empty() {
+        out.push(' ');
+        out.push_str(args);
+    }
+    out
+}
+
+// Expands the first word of command through the alias map until it no longer names an
+// alias. An alias that expands to its own name stops there, so 'look' = 'look brief' is fine,
+// but any longer cycle is refused.
+pub fn expand_aliases(aliases: &HashMap<String, String>, command: &str) -> Result<String, String> {
+    let mut current = command.trim().to_string();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    loop {
+        let (name, args) = split_command(&current);
+        let key = name.to_lowercase();
+        let body = match aliases.get(&key) {
+            Some(b) => b,
+            None => return Ok(current)
+        };
+        if seen.contains(&key) {
+            return Err(format!("Alias '{}' is recursive.", key));
+        }
+        if seen.len() >= MAX_ALIAS_DEPTH {
+            return Err(format!("Alias '{}' nests too deeply.", key));
+        }
+        seen.insert(key.clone());
+
+        let expanded = substitute_alias_args(body, args);
+        if split_command(&expanded).0.to_lowercase() == key {
+            return Ok(expanded);
+        }
+        current = expanded;
+    }
+}
+
+// Replaces $name with the player's variable of that name. Unknown variables are left as they
+// were typed, and $$ produces a literal $.
+pub fn substitute_variables(variables: &HashMap<String, String>, command: &str) -> String {
+    let mut out = String::with_capacity(command.len());
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        if chars.peek() == Some(&'$') {
+            chars.next();
+            out.push('$');
+            continue;
+        }
+
+        let mut name = String::new();
+        while let Some(n) = chars.peek() {
+            let valid = if name.is_empty() {
+                n.is_ascii_alphabetic() || *n == '_'
+            } else {
+                n.is_ascii_alphanumeric() || *n == '_'
+            };
+            if !valid {
+                break;
+            }
+            name.push(*n);
+            chars.next();
+        }
+
+        match variables.get(&name.to_lowercase()) {
+            Some(value) if !name.is_empty() => out.push_str(value),
+            _ => {
+                out.push('$');
+                out.push_str(&name);
+            }
+        }
+    }
+    out
+}
+
+// The commands carried by an OOB message, if its package is one of packages. Positional
+// arguments and a "command" keyword are each taken as input, one command per line.
+pub fn oob_commands(packages: &[String], package: &str, args: &[String], kwargs: &HashMap<String, String>) -> Vec<String> {
+    if !packages.iter().any(|p| p.eq_ignore_ascii_case(package)) {
+        return Vec::new();
+    }
+    args.iter()
+        .chain(kwargs.get("command"))
+        .flat_map(|body| body.lines())
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+// Edit distance by characters, counting a swap of two neighbours as one edit, since that's
+// the most common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut before: Vec<usize> = Vec::new();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            row[j] = (prev[j] + 1).min(row[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut prev, row);
+    }
+    prev[b.len()]
+}
+
+// The name closest to the first word of command, if one is close enough that it was likely
+// a typo: one edit for short words, two from six letters on. Ties go to the earlier name.
+pub fn suggest_command<'a>(command: &str, names: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let word = split_command(command).0.to_lowercase();
+    let len = word.chars().count();
+    if len == 0 {
+        return None;
+    }
+    let allowed = if len >= 6 { 2 } else { 1 };
+    let mut best: Option<(usize, &str)> = None;
+    for name in names {
+        let dist = edit_distance(&word, &name.to_lowercase());
+        if dist <= allowed && best.map(|(d, _)| dist < d).unwrap_or(true) {
+            best = Some((dist, name));
+        }
+    }
+    best.map(|(_, name)| name)
+}
+
+// What both command sets say when nothing matched command.
+pub fn not_found_message(command: &str, suggestion: Option<&str>) -> String {
+    match suggestion {
+        Some(name) => format!("Sorry, {} that isn't a command. Did you mean '{}'?", command, name),
+        None => format!("Sorry, {} that isn't a command. Type 'help' for help.", command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn defined_alias_expands() {
+        let map = table(&[("k", "kill"), ("l", "look")]);
+        assert_eq!(expand_aliases(&map, "k orc").unwrap(), "kill orc");
+        assert_eq!(expand_aliases(&map, "L").unwrap(), "look");
+        assert_eq!(expand_aliases(&map, "say hi").unwrap(), "say hi");
+    }
+
+    #[test]
+    fn recursive_alias_is_refused() {
+        let map = table(&[("a", "b"), ("b", "c"), ("c", "a")]);
+        assert_eq!(expand_aliases(&map, "a"), Err("Alias 'a' is recursive.".to_string()));
+        // Expanding to its own name is how an alias adds default arguments, not a loop.
+        let map = table(&[("look", "look brief")]);
+        assert_eq!(expand_aliases(&map, "look").unwrap(), "look brief");
+    }
+
+    #[test]
+    fn alias_parameters_substitute() {
+        assert_eq!(substitute_alias_args("give $2 to $1", "bob sword"), "give sword to bob");
+        assert_eq!(substitute_alias_args("say $*", "hello there"), "say hello there");
+        assert_eq!(substitute_alias_args("kill", "orc"), "kill orc");
+        assert_eq!(substitute_alias_args("get $3", "a b"), "get ");
+    }
+
+    #[test]
+    fn variables_substitute() {
+        let vars = table(&[("target", "orc"), ("weapon", "axe")]);
+        assert_eq!(substitute_variables(&vars, "kill $target with $WEAPON"), "kill orc with axe");
+        assert_eq!(substitute_variables(&vars, "say $unknown $"), "say $unknown $");
+    }
+
+    #[test]
+    fn doubled_dollar_is_a_literal() {
+        let vars = table(&[("gold", "10")]);
+        assert_eq!(substitute_variables(&vars, "say it costs $$gold"), "say it costs $gold");
+        assert_eq!(substitute_variables(&vars, "say $gold$$"), "say 10$");
+    }
+}
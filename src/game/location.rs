@@ -0,0 +1,120 @@
+use legion::*;
+use legion::world::SubWorld;
+use crate::game::objects::{ContentsComponent, LocationComponent, LocationType, MudObjectComponent, MudObjectSession,
+                           MudProtoTypeComponent, MudSession, UserComponent};
+use crate::mudstring::text::Text;
+use crate::net::ProtocolOutEvent;
+
+// Short forms players may type for the usual directions.
+pub const DIRECTIONS: &[(&str, &str)] = &[
+    ("n", "north"), ("s", "south"), ("e", "east"), ("w", "west"), ("u", "up"), ("d", "down"),
+    ("ne", "northeast"), ("nw", "northwest"), ("se", "southeast"), ("sw", "southwest")
+];
+
+// "n" becomes "north"; anything else is just lowercased, so custom exits like "portal" work.
+pub fn expand_direction(word: &str) -> String {
+    let word = word.trim().to_lowercase();
+    DIRECTIONS.iter()
+        .find(|(short, _)| *short == word)
+        .map(|(_, long)| long.to_string())
+        .unwrap_or(word)
+}
+
+pub fn is_direction(word: &str) -> bool {
+    let word = expand_direction(word);
+    DIRECTIONS.iter().any(|(_, long)| *long == word)
+}
+
+pub fn location_of(world: &SubWorld, obj: Entity) -> Option<Entity> {
+    let entry = world.entry_ref(obj).ok()?;
+    entry.get_component::<LocationComponent>().ok().map(|loc| loc.entity)
+}
+
+pub fn contents_of(world: &SubWorld, container: Entity) -> Vec<Entity> {
+    world.entry_ref(container).ok()
+        .and_then(|entry| entry.get_component::<ContentsComponent>().ok().map(|c| c.0.iter().cloned().collect()))
+        .unwrap_or_default()
+}
+
+// Takes obj out of its location's contents. Its LocationComponent is kept, so it can be put
+// back where it was.
+pub fn remove_from_location(world: &mut SubWorld, obj: Entity) {
+    if let Some(old) = location_of(world, obj) {
+        if let Ok(mut entry) = world.entry_mut(old) {
+            if let Ok(contents) = entry.get_component_mut::<ContentsComponent>() {
+                contents.0.remove(&obj);
+            }
+        }
+    }
+}
+
+// Adds obj to container's contents without touching its LocationComponent.
+pub fn add_to_contents(world: &mut SubWorld, obj: Entity, container: Entity) -> bool {
+    if let Ok(mut entry) = world.entry_mut(container) {
+        if let Ok(contents) = entry.get_component_mut::<ContentsComponent>() {
+            contents.0.insert(obj);
+            return true;
+        }
+    }
+    false
+}
+
+// Moves obj into the room dest, keeping its LocationComponent and both rooms' contents in
+// step. obj must already have a location, and dest must be able to hold things.
+pub fn move_object(world: &mut SubWorld, obj: Entity, dest: Entity) -> bool {
+    let dest_ok = world.entry_ref(dest).ok()
+        .map(|entry| entry.get_component::<ContentsComponent>().is_ok())
+        .unwrap_or(false);
+    if !dest_ok || location_of(world, obj).is_none() {
+        return false;
+    }
+
+    remove_from_location(world, obj);
+    if let Ok(mut entry) = world.entry_mut(obj) {
+        if let Ok(loc) = entry.get_component_mut::<LocationComponent>() {
+            loc.ltype = LocationType::Room;
+            loc.entity = dest;
+        }
+    }
+    add_to_contents(world, obj, dest)
+}
+
+// What to call an object when telling others about it.
+pub fn name_of(world: &SubWorld, obj: Entity) -> String {
+    let entry = match world.entry_ref(obj) {
+        Ok(entry) => entry,
+        Err(_) => return "something".to_string()
+    };
+    if let Ok(user) = entry.get_component::<UserComponent>() {
+        return user.username.clone();
+    }
+    if let Ok(o) = entry.get_component::<MudObjectComponent>() {
+        return o.name.clone();
+    }
+    if let Ok(p) = entry.get_component::<MudProtoTypeComponent>() {
+        return p.name.clone();
+    }
+    "something".to_string()
+}
+
+// Queues text for whoever is playing obj, if anyone is.
+pub fn send_to_object(world: &mut SubWorld, obj: Entity, text: Text) {
+    let session = world.entry_ref(obj).ok()
+        .and_then(|entry| entry.get_component::<MudObjectSession>().ok().map(|s| s.session));
+    if let Some(session) = session {
+        if let Ok(mut entry) = world.entry_mut(session) {
+            if let Ok(msess) = entry.get_component_mut::<MudSession>() {
+                msess.out_events.push_back(ProtocolOutEvent::Line(text));
+            }
+        }
+    }
+}
+
+// Tells everyone in room except the objects in exclude.
+pub fn send_to_room(world: &mut SubWorld, room: Entity, exclude: &[Entity], text: &Text) {
+    for obj in contents_of(world, room) {
+        if !exclude.contains(&obj) {
+            send_to_object(world, obj, text.clone());
+        }
+    }
+}
@@ -1,12 +1,20 @@
 use legion::*;
 use crate::game::objects::MudSession;
-use crate::net::{ProtocolComponent, ProtocolOutEvent, ProtocolEvent};
+use crate::net::{ProtocolComponent, ProtocolOutEvent, ProtocolEvent, CredentialsEvent};
+use crate::net::suspicion::Offense;
 use crate::game::resources::{PendingUserCreations, PendingUserLogins};
 use crate::mudstring::text::{Text};
+use crate::mudstring::colortest::color_test;
+use crate::game::input::{suggest_command, not_found_message};
+use std::time::{Duration, Instant};
 
 
 pub struct LoginCommands {
-    pub cmds: Vec<LoginCmd>
+    pub cmds: Vec<LoginCmd>,
+    // Called with the full input line when nothing in cmds matches.
+    pub fallback: fn(&mut ProtocolComponent, String, &LoginCommands),
+    // Kept in step with GameConfig.suggest_commands.
+    pub suggest: bool
 }
 
 impl Default for LoginCommands {
@@ -14,29 +22,52 @@ impl Default for LoginCommands {
         let mut cmds = Vec::new();
 
         cmds.push(LoginCmd{name: "connect".to_string(), aliases: Default::default(),
-            func: login_login_command, help: "does a login".to_string(),
+            func: login_login_command, help: "does a login. Leave off =<password> to be asked for it without it showing".to_string(),
             syntax: "connect <username>=<password>".to_string(),
-            shorthelp: "connect <username>=<password>".to_string()});
+            shorthelp: "connect <username>=<password>".to_string(), rate_limited: true});
 
         cmds.push(LoginCmd{name: "create".to_string(), aliases: Default::default(),
-            func: login_create_command, help: "creates an account".to_string(),
+            func: login_create_command, help: "creates an account. Leave off =<password> to be asked for it without it showing".to_string(),
             syntax: "create <username>=<password>".to_string(),
-            shorthelp: "create <username>=<password>".to_string()});
+            shorthelp: "create <username>=<password>".to_string(), rate_limited: true});
+
+        cmds.push(LoginCmd{name: "colortest".to_string(), aliases: Default::default(),
+            func: login_colortest_command, help: "shows the colors your client can display".to_string(),
+            syntax: "colortest".to_string(),
+            shorthelp: "colortest".to_string(), rate_limited: false});
+
+        cmds.push(LoginCmd{name: "quit".to_string(), aliases: vec!["logout".to_string()],
+            func: login_quit_command, help: "disconnects you".to_string(),
+            syntax: "quit".to_string(),
+            shorthelp: "quit".to_string(), rate_limited: false});
 
         cmds.push(LoginCmd{name: "help".to_string(), aliases: Default::default(),
             func: login_help_command, help: "displays help".to_string(),
             syntax: "help [<topic>]".to_string(),
-            shorthelp: "help [<topic>]".to_string()});
+            shorthelp: "help [<topic>]".to_string(), rate_limited: false});
 
 
         Self {
-            cmds
+            cmds,
+            fallback: login_not_found_command,
+            suggest: true
         }
     }
 }
 
 impl LoginCommands {
-    pub fn execute(&mut self, prot: &mut ProtocolComponent, command: String) {
+    // Commands marked rate_limited may only be used once per cooldown on each connection.
+    pub fn execute(&mut self, prot: &mut ProtocolComponent, command: String, now: Instant, cooldown: Duration) {
+        // The line after a bare "connect <username>" is the password, not a command.
+        if let Some((username, event)) = prot.awaiting_password.take() {
+            prot.out_buffer.push_back(ProtocolOutEvent::PasswordMode(false));
+            // The client didn't echo the enter either, so end the prompt's line for it.
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from("")));
+            if !command.is_empty() {
+                prot.in_buffer.push_back(event(username, command));
+            }
+            return;
+        }
         let split: Vec<&str> = command.splitn(2, ' ').collect();
         let comm = split[0].trim();
         let args = if split.len() == 2 {
@@ -46,12 +77,26 @@ impl LoginCommands {
         };
         for cmd in self.cmds.iter() {
             if cmd.name_match(comm) {
-
+                if cmd.rate_limited {
+                    let too_soon = prot.last_login_attempt
+                        .map(|last| now.saturating_duration_since(last) < cooldown)
+                        .unwrap_or(false);
+                    if too_soon {
+                        prot.suspicion.flag(Offense::RateLimited);
+                        prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from("Too fast! Wait a moment before trying that again.")));
+                        return;
+                    }
+                    prot.last_login_attempt = Some(now);
+                }
                 (cmd.func)(prot, args.to_string(), &self.cmds);
                 return;
             }
         }
-        prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(format!("Sorry, {} that isn't a command. Type 'help' for help.", command).as_ref())));
+        (self.fallback)(prot, command, self);
+    }
+
+    pub fn set_fallback(&mut self, func: fn(&mut ProtocolComponent, String, &LoginCommands)) {
+        self.fallback = func;
     }
 }
 
@@ -63,6 +108,8 @@ pub struct LoginCmd {
     pub help: String,
     pub syntax: String,
     pub shorthelp: String,
+    // Login attempts, which bots hammer. See LoginCommands::execute.
+    pub rate_limited: bool
 }
 
 impl LoginCmd {
@@ -92,33 +139,54 @@ impl LoginCmd {
     }
 }
 
-pub fn login_create_command(prot: &mut ProtocolComponent, args: String, cmds: &Vec<LoginCmd>) {
-    let args: Vec<&str> = args.splitn(2, '=').collect();
-    if args.len() == 2 {
-        let username = args[0].trim();
-        let password = args[1].trim();
-        if (password.len() == 0) | (username.len() == 0) {
-            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from("SYNTAX: create <username>=<password>")));
-        } else {
-            prot.in_buffer.push_back(ProtocolEvent::CreateUser(username.to_string(), password.to_string()));
-        }
+pub fn login_not_found_command(prot: &mut ProtocolComponent, command: String, cmds: &LoginCommands) {
+    let suggestion = if cmds.suggest {
+        suggest_command(&command, cmds.cmds.iter().flat_map(|c| std::iter::once(&c.name).chain(c.aliases.iter())).map(|n| n.as_str()))
     } else {
-        prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from("SYNTAX: create <username>=<password>")));
-    }
+        None
+    };
+    let line = not_found_message(&command, suggestion);
+    prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(line.as_ref())));
+}
+
+pub fn login_create_command(prot: &mut ProtocolComponent, args: String, cmds: &Vec<LoginCmd>) {
+    take_credentials(prot, &args, "SYNTAX: create <username>=<password>", ProtocolEvent::CreateUser);
 }
 
 pub fn login_login_command(prot: &mut ProtocolComponent, args: String, cmds: &Vec<LoginCmd>) {
+    take_credentials(prot, &args, "SYNTAX: connect <username>=<password>", ProtocolEvent::Login);
+}
+
+// Takes "<username>=<password>" at once, or a username alone, in which case the password is
+// asked for with echo off and the next line finishes it. See LoginCommands::execute.
+fn take_credentials(prot: &mut ProtocolComponent, args: &str, syntax: &str, event: CredentialsEvent) {
     let args: Vec<&str> = args.splitn(2, '=').collect();
-    if args.len() == 2 {
-        let username = args[0].trim();
-        let password = args[1].trim();
-        if (password.len() == 0) | (username.len() == 0) {
-            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from("SYNTAX: connect <username>=<password>")));
-        } else {
-            prot.in_buffer.push_back(ProtocolEvent::Login(username.to_string(), password.to_string()));
+    let username = args[0].trim();
+    match args.get(1).map(|p| p.trim()) {
+        _ if username.is_empty() => {
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(syntax)));
+        },
+        Some("") => {
+            prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(syntax)));
+        },
+        Some(password) => {
+            prot.in_buffer.push_back(event(username.to_string(), password.to_string()));
+        },
+        None => {
+            prot.awaiting_password = Some((username.to_string(), event));
+            prot.out_buffer.push_back(ProtocolOutEvent::Prompt(Text::from("Password: ")));
+            prot.out_buffer.push_back(ProtocolOutEvent::PasswordMode(true));
         }
-    } else {
-        prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from("SYNTAX: connect <username>=<password>")));
+    }
+}
+
+pub fn login_quit_command(prot: &mut ProtocolComponent, _args: String, _cmds: &Vec<LoginCmd>) {
+    prot.out_buffer.push_back(ProtocolOutEvent::Disconnect(Text::from("Goodbye!")));
+}
+
+pub fn login_colortest_command(prot: &mut ProtocolComponent, _args: String, _cmds: &Vec<LoginCmd>) {
+    for line in color_test(prot.capabilities.color) {
+        prot.out_buffer.push_back(ProtocolOutEvent::Line(line));
     }
 }
 
@@ -140,4 +208,126 @@ pub fn login_help_command(prot: &mut ProtocolComponent, args: String, cmds: &Vec
         }
         return
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::resources::TelnetOptions;
+
+    fn connection() -> ProtocolComponent {
+        ProtocolComponent::telnet(TelnetOptions::default().0, Instant::now())
+    }
+
+    fn lines(prot: &mut ProtocolComponent) -> Vec<String> {
+        prot.out_buffer.drain(..).filter_map(|ev| match ev {
+            ProtocolOutEvent::Line(text) => Some(text.plain),
+            _ => None
+        }).collect()
+    }
+
+    fn echo_fallback(prot: &mut ProtocolComponent, command: String, _cmds: &LoginCommands) {
+        prot.out_buffer.push_back(ProtocolOutEvent::Line(Text::from(format!("fallback: {}", command).as_ref())));
+    }
+
+    #[test]
+    fn registered_fallback_receives_unmatched_input() {
+        let mut cmds = LoginCommands::default();
+        cmds.set_fallback(echo_fallback);
+        let mut prot = connection();
+        cmds.execute(&mut prot, "wave at everyone".to_string(), Instant::now(), Duration::from_secs(2));
+        assert_eq!(lines(&mut prot), vec!["fallback: wave at everyone"]);
+
+        cmds.execute(&mut prot, "help".to_string(), Instant::now(), Duration::from_secs(2));
+        assert!(!lines(&mut prot)[0].starts_with("fallback"));
+    }
+
+    #[test]
+    fn create_again_within_the_cooldown_is_refused() {
+        let mut cmds = LoginCommands::default();
+        let mut prot = connection();
+        let start = Instant::now();
+        let cooldown = Duration::from_secs(2);
+        let too_fast = |prot: &mut ProtocolComponent| lines(prot).iter().any(|l| l.starts_with("Too fast!"));
+
+        cmds.execute(&mut prot, "create alice=secret".to_string(), start, cooldown);
+        assert!(!too_fast(&mut prot));
+        cmds.execute(&mut prot, "create bob=secret".to_string(), start + Duration::from_millis(500), cooldown);
+        assert!(too_fast(&mut prot));
+        // connect shares the cooldown, and help isn't limited at all.
+        cmds.execute(&mut prot, "connect alice=secret".to_string(), start + Duration::from_secs(1), cooldown);
+        assert!(too_fast(&mut prot));
+        cmds.execute(&mut prot, "help".to_string(), start + Duration::from_secs(1), cooldown);
+        assert!(!too_fast(&mut prot));
+
+        cmds.execute(&mut prot, "create bob=secret".to_string(), start + Duration::from_secs(3), cooldown);
+        assert!(!too_fast(&mut prot));
+        // Each refusal counts against the connection.
+        assert_eq!(prot.suspicion.score, 2 * Offense::RateLimited.weight());
+        assert!(prot.suspicion.describe().contains("tried to log in too fast"));
+    }
+
+    #[test]
+    fn a_near_miss_suggests_the_command_and_anything_else_gets_the_generic_message() {
+        let mut cmds = LoginCommands::default();
+        let mut prot = connection();
+        cmds.execute(&mut prot, "conenct alice=secret".to_string(), Instant::now(), Duration::from_secs(2));
+        assert_eq!(lines(&mut prot), vec!["Sorry, conenct alice=secret that isn't a command. Did you mean 'connect'?"]);
+
+        cmds.execute(&mut prot, "xyzzy".to_string(), Instant::now(), Duration::from_secs(2));
+        assert_eq!(lines(&mut prot), vec!["Sorry, xyzzy that isn't a command. Type 'help' for help."]);
+
+        cmds.suggest = false;
+        cmds.execute(&mut prot, "conenct".to_string(), Instant::now(), Duration::from_secs(2));
+        assert_eq!(lines(&mut prot), vec!["Sorry, conenct that isn't a command. Type 'help' for help."]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn multi_line_help_ends_every_line_with_the_configured_terminator() {
+        use crate::config::NetConfig;
+        use crate::testing::Harness;
+
+        for (ending, bare) in [("crlf", "\r\n"), ("lf", "\n")] {
+            let net: NetConfig = serde_json::from_str(&format!(r#"{{"line_ending": "{}"}}"#, ending)).unwrap();
+            let mut h = Harness::with_config(Some(net), None);
+            let mut client = h.connect();
+            h.send(&mut client, "help");
+            let text = client.read();
+            let lines: Vec<&str> = text.split_terminator(bare).collect();
+            assert!(text.ends_with(bare));
+            assert_eq!(lines.len(), LoginCommands::default().cmds.len(), "{:?}", text);
+            assert!(lines.iter().all(|line| !line.contains('\n') && !line.contains('\r')), "{}: {:?}", ending, text);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn passwords_are_not_echoed_back() {
+        use crate::config::NetConfig;
+        use crate::net::telnet::codes;
+        use crate::testing::Harness;
+
+        let net: NetConfig = serde_json::from_str(r#"{"server_echo": true}"#).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        let mut client = h.connect();
+        client.write(&[codes::IAC, codes::DO, codes::ECHO]);
+        h.tick(3);
+
+        let created = h.command(&mut client, "create alice=hunter2");
+        assert!(created.contains("create alice=********"), "{:?}", created);
+        assert!(created.contains("Welcome, alice!"));
+        assert!(!created.contains("hunter2"));
+        // Logged in, so an '=' is just an '='.
+        let said = h.command(&mut client, "say 1+1=2");
+        assert!(said.contains("say 1+1=2"), "{:?}", said);
+        h.send(&mut client, "quit");
+
+        let mut client = h.connect();
+        client.write(&[codes::IAC, codes::DO, codes::ECHO]);
+        h.tick(3);
+        assert!(h.command(&mut client, "connect alice").contains("Password:"));
+        let answered = h.command(&mut client, "hunter2");
+        assert!(answered.contains("Welcome, alice!"), "{:?}", answered);
+        assert!(!answered.contains("hunter2"));
+    }
+}
@@ -1,4 +1,13 @@
 pub mod objects;
 pub mod resources;
 pub mod process;
-pub mod login_cmds;
\ No newline at end of file
+pub mod login_cmds;
+pub mod game_cmds;
+pub mod input;
+pub mod timefmt;
+pub mod control_cmds;
+pub mod prompt;
+pub mod area;
+pub mod location;
+pub mod snapshot;
+pub mod accounts;
@@ -13,9 +13,77 @@ pub struct MudSession {
     pub player: Entity,
     pub puppet: Entity,
     pub created: Instant,
+    // When the player last sent a command, for idle times.
+    pub last_input: Instant,
     pub in_events: VecDeque<ProtocolEvent>,
     pub out_events: VecDeque<ProtocolOutEvent>,
-    pub connections: HashSet<Entity>
+    pub connections: HashSet<Entity>,
+    // Processes spawned for this session that haven't run yet.
+    pub processes: usize,
+    // Command lag: until then, typed commands wait in command_queue.
+    pub busy_until: Option<Instant>,
+    pub command_queue: VecDeque<String>,
+    // Its user is throttle exempt, so the process cap and queue limit don't apply.
+    pub exempt: bool
+}
+
+impl MudSession {
+    pub fn is_lagged(&self, now: Instant) -> bool {
+        self.busy_until.map(|t| t > now).unwrap_or(false)
+    }
+
+    // Extends the session's lag by lag, starting now if it has none.
+    pub fn add_lag(&mut self, now: Instant, lag: Duration) {
+        let from = self.busy_until.filter(|t| *t > now).unwrap_or(now);
+        self.busy_until = Some(from + lag);
+    }
+}
+
+// Account data for a user entity. Everything here is persisted with the account.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserComponent {
+    pub username: String,
+    // A hash from accounts::hash_password, never the password itself.
+    #[serde(default)]
+    pub password: String,
+    pub aliases: HashMap<String, String>,
+    pub variables: HashMap<String, String>,
+    // Whether $name in typed commands is replaced from variables.
+    pub substitute_variables: bool,
+    // UTC offset like "+02:00" used when showing timestamps. None means UTC.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    // Gets access to operator commands.
+    #[serde(default)]
+    pub admin: bool,
+    // Prompt template, see game::prompt. None uses the game's default.
+    #[serde(default)]
+    pub prompt: Option<String>,
+    // Lets an admin past flood protection and the session process and queue limits, for bulk
+    // work. Has no effect unless admin is set too.
+    #[serde(default)]
+    pub throttle_exempt: bool
+}
+
+impl UserComponent {
+    pub fn is_throttle_exempt(&self) -> bool {
+        self.admin && self.throttle_exempt
+    }
+}
+
+// Marks a user whose account changed since the accounts file was last written.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccountChanged;
+
+// Hit points, mana and moves of a character, as shown by %h, %m and %v in prompts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VitalsComponent {
+    pub hp: i64,
+    pub max_hp: i64,
+    pub mana: i64,
+    pub max_mana: i64,
+    pub moves: i64,
+    pub max_moves: i64
 }
 
 #[derive(Debug)]
@@ -27,7 +95,7 @@ pub struct ModuleComponent {
     pub protected: bool
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub enum MudObjectType {
     Alliance,
     Board,
@@ -55,6 +123,23 @@ pub struct MudProtoTypeComponent {
     pub module: Entity,
 }
 
+// What an object looks like up close.
+#[derive(Debug, Clone, Default)]
+pub struct DescriptionComponent(pub String);
+
+// A room's exits: direction, lowercased, to the room it leads to.
+#[derive(Debug, Clone, Default)]
+pub struct ExitsComponent(pub HashMap<String, Entity>);
+
+// What a room holds, kept in step with the LocationComponents pointing at it by
+// game::location::move_object.
+#[derive(Debug, Clone, Default)]
+pub struct ContentsComponent(pub HashSet<Entity>);
+
+// Numbers an item prototype starts with, like weight or damage.
+#[derive(Debug, Clone, Default)]
+pub struct ItemStatsComponent(pub HashMap<String, i64>);
+
 #[derive(Debug)]
 pub struct MudObjectSession {
     pub session: Entity
@@ -1,30 +1,140 @@
+use std::fmt::Debug;
 use std::time::{Instant, Duration};
 use legion::*;
 use crate::game::objects::{MudSession};
+use crate::game::game_cmds::GameContext;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    Done,
+    // There's more to do. The step is called again, on a later tick if the budget ran out.
+    Yield
+}
+
+// The rest of a command too slow to finish in one go, like a big search. A command hands one
+// to GameContext::continue_with and execute_process calls it a step at a time, for as long
+// as the tick's ProcessBudget allows.
+pub trait ProcessStep: Debug + Send + Sync {
+    fn step(&mut self, ctx: &mut GameContext) -> StepResult;
+}
 
 #[derive(Debug)]
 pub struct ProcessComponent {
     pub created: Instant,
     pub id: usize,
+    pub session: Option<Entity>,
     pub enactor_user: Option<Entity>,
     pub enactor_obj: Option<Entity>,
     pub executor: Option<Entity>,
     pub wait_for: Option<Duration>,
     pub command: String,
-    pub split_actions: bool
+    pub split_actions: bool,
+    // Set once the command has run and left work for later.
+    pub steps: Option<Box<dyn ProcessStep>>
 }
 
 impl ProcessComponent {
-    pub fn from_command(sess: &MudSession, id: usize, command: String) -> Self {
+    pub fn from_command(session: Entity, sess: &MudSession, id: usize, command: String, created: Instant) -> Self {
         Self {
-            created: Instant::now(),
+            created,
             id,
+            session: Some(session),
             enactor_user: Some(sess.user),
             enactor_obj: Some(sess.puppet),
             executor: Some(sess.puppet),
             wait_for: None,
             command,
-            split_actions: false
+            split_actions: false,
+            steps: None
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+    use crate::engine::clock::MockClock;
+    use crate::game::game_cmds::{GameCmd, GameCommands};
+    use crate::mudstring::text::Text;
+    use crate::testing::{Client, Harness};
+
+    // The harness clock, so steps can take up simulated time.
+    static CLOCK: OnceLock<MockClock> = OnceLock::new();
+
+    #[derive(Debug)]
+    struct SlowSearch {
+        done: usize
+    }
+
+    impl ProcessStep for SlowSearch {
+        fn step(&mut self, ctx: &mut GameContext) -> StepResult {
+            // Each step uses 2ms of the 5ms budget, so about three fit in a tick.
+            CLOCK.get().unwrap().advance(Duration::from_millis(2));
+            self.done += 1;
+            ctx.send_line(Text::from(format!("searched {}", self.done).as_ref()));
+            if self.done == 9 { StepResult::Done } else { StepResult::Yield }
         }
     }
-}
\ No newline at end of file
+
+    fn search_command(ctx: &mut GameContext, _args: String, _cmds: &[GameCmd]) {
+        ctx.continue_with(Box::new(SlowSearch {done: 0}));
+    }
+
+    #[test]
+    fn long_command_is_split_across_ticks() {
+        let mut h = Harness::new();
+        CLOCK.set(h.clock.clone()).unwrap();
+        h.engine.resources.get_mut::<GameCommands>().unwrap().register("test", GameCmd {
+            name: "search".to_string(), aliases: Default::default(), func: search_command,
+            help: String::new(), syntax: "search".to_string(), shorthelp: String::new(), module: None
+        });
+        let mut searcher = h.login("alice");
+        searcher.write(b"search\r\n");
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut newcomer = Client {stream};
+
+        let mut output = String::new();
+        let mut ticks_with_results = 0;
+        let mut newcomer_served = false;
+        for _ in 0..50 {
+            h.tick(1);
+            let out = searcher.text();
+            if out.contains("searched") {
+                ticks_with_results += 1;
+            }
+            output += &out;
+            if output.contains("searched 9") {
+                break;
+            }
+            newcomer_served |= !newcomer.read_bytes().is_empty();
+        }
+        assert!(output.contains("searched 9"));
+        assert!(ticks_with_results >= 3, "all the steps ran within {} ticks", ticks_with_results);
+        // Connections were still accepted and written to while the search went on.
+        assert!(newcomer_served);
+        // The prompt waits for the last step.
+        assert_eq!(output.matches('>').count(), 1);
+        assert!(output.trim_end().ends_with('>'));
+    }
+
+    #[test]
+    fn a_finished_process_leaves_the_index() {
+        use crate::engine::resources::ProcessBudget;
+        use crate::game::resources::ProcessIndex;
+
+        let mut h = Harness::new();
+        let mut client = h.login("alice");
+        // Held with no time to run, the command waits in the index.
+        h.engine.resources.get_mut::<ProcessBudget>().unwrap().limit = Duration::ZERO;
+        client.write(b"look\r\n");
+        h.tick(4);
+        assert_eq!(h.engine.resources.get::<ProcessIndex>().unwrap().0.len(), 1);
+
+        h.engine.resources.get_mut::<ProcessBudget>().unwrap().limit = Duration::from_millis(5);
+        h.tick(2);
+        assert!(h.engine.resources.get::<ProcessIndex>().unwrap().0.is_empty());
+        assert_eq!(<&ProcessComponent>::query().iter(&h.engine.world).count(), 0);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+// Codes the engine fills in. A template may use any of these; anything else after a % is
+// left in the output as typed.
+//   %h %H  hit points, current and max     %m %M  mana, current and max
+//   %v %V  moves, current and max          %n     account name
+//   %t     time of day, in the player's timezone
+//   %%     a literal %
+pub const PROMPT_CODES: &[char] = &['h', 'H', 'm', 'M', 'v', 'V', 'n', 't'];
+
+pub const DEFAULT_PROMPT: &str = "> ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptPart {
+    Literal(String),
+    Code(char)
+}
+
+// A prompt like "<%h/%Hhp %m/%Mmp> ", parsed once and rendered every time a prompt is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    pub parts: Vec<PromptPart>
+}
+
+impl PromptTemplate {
+    pub fn parse(template: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                literal.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => literal.push('%'),
+                Some(code) if PROMPT_CODES.contains(&code) => {
+                    if !literal.is_empty() {
+                        parts.push(PromptPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(PromptPart::Code(code));
+                },
+                Some(other) => {
+                    literal.push('%');
+                    literal.push(other);
+                },
+                // A trailing % stays as it is.
+                None => literal.push('%')
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(PromptPart::Literal(literal));
+        }
+        Self {
+            parts
+        }
+    }
+
+    // Codes with no value in values (say, %h for a session with no character yet) come out
+    // empty rather than as the raw code.
+    pub fn render(&self, values: &HashMap<char, String>) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                PromptPart::Literal(s) => out.push_str(s),
+                PromptPart::Code(c) => {
+                    if let Some(v) = values.get(c) {
+                        out.push_str(v);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        Self::parse(DEFAULT_PROMPT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_codes_are_filled_and_unknown_ones_kept() {
+        let template = PromptTemplate::parse("<%h/%Hhp %z 100%%> ");
+        let values: HashMap<char, String> = [('h', "42".to_string()), ('H', "50".to_string())].iter().cloned().collect();
+        assert_eq!(template.render(&values), "<42/50hp %z 100%> ");
+        assert_eq!(PromptTemplate::parse("%m%").render(&HashMap::new()), "%");
+    }
+}
@@ -1,6 +1,13 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use legion::Entity;
 use std::time::{Instant, Duration};
+use chrono::{DateTime, Utc};
+use std::path::Path;
+use unicode_width::UnicodeWidthStr;
+use crate::config::GameConfig;
+use crate::mudstring::ansi::strip_control;
+use crate::net::ProtocolCapabilities;
 use super::objects::{MudObjectType};
 
 #[derive(Default)]
@@ -13,9 +20,161 @@ pub struct Modules(pub HashSet<Entity>);
 pub struct ObjTypeIndex(pub HashMap<MudObjectType, HashSet<Entity>>);
 #[derive(Default)]
 pub struct ProcessCounter(pub usize);
+
+impl ProcessCounter {
+    // The next free process id. The counter wraps instead of overflowing: a 32-bit usize runs
+    // out after about 50 days at a thousand commands a second. 0 is never handed out, and
+    // neither is an id still in index, so a wrapped counter can't clobber a waiting process.
+    pub fn next(&mut self, index: &ProcessIndex) -> usize {
+        loop {
+            self.0 = self.0.wrapping_add(1);
+            if self.0 != 0 && !index.0.contains_key(&self.0) {
+                return self.0;
+            }
+        }
+    }
+}
+
+// Processes that haven't finished yet, by id. execute_process takes them out when they're done.
 #[derive(Default)]
 pub struct ProcessIndex(pub HashMap<usize, Entity>);
 #[derive(Default)]
 pub struct PendingUserCreations(pub VecDeque<(Entity, String, String)>);
 #[derive(Default)]
-pub struct PendingUserLogins(pub VecDeque<(Entity, String, String)>);
\ No newline at end of file
+pub struct PendingUserLogins(pub VecDeque<(Entity, String, String)>);
+
+// When each address created its recent accounts, for GameConfig.max_accounts_per_ip. IPv6
+// addresses are counted by their /64, since anyone given one can pick any address in it.
+#[derive(Default)]
+pub struct AccountCreations(pub HashMap<IpAddr, VecDeque<Instant>>);
+
+impl AccountCreations {
+    // Records a creation from ip and returns true, unless it has already made limit of them
+    // in the window before now.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant, limit: usize, window: Duration) -> bool {
+        self.0.retain(|_, times| {
+            while times.front().map(|t| now.saturating_duration_since(*t) >= window).unwrap_or(false) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = self.0.entry(network_of(ip)).or_default();
+        if times.len() >= limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+// The address a limit should count ip under: IPv4 as is, IPv6 by its /64.
+fn network_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6((u128::from(v6) & !0u128 << 64).into())
+        },
+        v4 => v4
+    }
+}
+
+// When the server came up by the engine clock, reported as UPTIME over MSSP.
+pub struct BootTime(pub DateTime<Utc>);
+
+// The connect banner, read from the files named in GameConfig.
+#[derive(Default)]
+pub struct Banner {
+    pub ansi: Option<String>,
+    pub plain: Option<String>
+}
+
+impl Banner {
+    pub fn load(game: &GameConfig) -> Self {
+        Self {
+            ansi: game.banner_ansi.as_deref().and_then(read_banner),
+            plain: game.banner_plain.as_deref().and_then(read_banner)
+        }
+    }
+
+    // The version this client should see, or None if there is no banner or the client's
+    // terminal is too narrow for it.
+    pub fn for_client(&self, capabilities: &ProtocolCapabilities) -> Option<String> {
+        let wants_ansi = capabilities.color.is_some() && !capabilities.screen_reader && !capabilities.html;
+        let art = if wants_ansi {
+            self.ansi.clone().or_else(|| self.plain.clone())?
+        } else {
+            self.plain.clone().or_else(|| self.ansi.as_deref().map(strip_control))?
+        };
+        let width = strip_control(&art).lines().map(|l| l.width()).max().unwrap_or(0);
+        if width > capabilities.width as usize {
+            return None;
+        }
+        Some(art)
+    }
+}
+
+fn read_banner(path: &Path) -> Option<String> {
+    match std::fs::read(path) {
+        // Plenty of ANSI art is saved as CP437; keep whatever decodes.
+        Ok(data) => Some(String::from_utf8_lossy(&data).replace("\r\n", "\n").trim_end().to_string()),
+        Err(e) => {
+            println!("Could not read banner {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mudstring::color::ColorSystem;
+
+    fn banner() -> Banner {
+        Banner {
+            ansi: Some("\x1b[31m(\\/) CRAB (\\/)\x1b[0m".to_string()),
+            plain: Some("(\\/) crab (\\/)".to_string())
+        }
+    }
+
+    #[test]
+    fn account_creations_are_limited_per_address_and_ipv6_per_64() {
+        let (mut recent, now, window) = (AccountCreations::default(), Instant::now(), Duration::from_secs(60));
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(recent.allow(ip("2001:db8::1"), now, 2, window));
+        assert!(recent.allow(ip("2001:db8::ffff:2"), now, 2, window));
+        assert!(!recent.allow(ip("2001:db8::dead:beef"), now, 2, window));
+        assert!(recent.allow(ip("2001:db8:0:1::1"), now, 2, window));
+
+        assert!(recent.allow(ip("192.0.2.1"), now, 1, window));
+        assert!(!recent.allow(ip("::ffff:192.0.2.1"), now, 1, window));
+        assert!(recent.allow(ip("192.0.2.2"), now, 1, window));
+        assert!(recent.allow(ip("192.0.2.1"), now + window, 1, window));
+    }
+
+    #[test]
+    fn process_ids_wrap_past_zero_and_ids_in_use() {
+        let mut index = ProcessIndex::default();
+        index.0.insert(1, legion::World::default().push(()));
+        let mut counter = ProcessCounter(usize::MAX);
+        assert_eq!(counter.next(&index), 2);
+        assert_eq!(counter.next(&index), 3);
+    }
+
+    #[test]
+    fn each_client_gets_the_banner_it_can_show() {
+        let mut caps = ProtocolCapabilities::telnet();
+        assert_eq!(banner().for_client(&caps).as_deref(), Some("(\\/) crab (\\/)"));
+
+        caps.color = Some(ColorSystem::EightBit);
+        assert_eq!(banner().for_client(&caps).as_deref(), Some("\x1b[31m(\\/) CRAB (\\/)\x1b[0m"));
+
+        caps.width = 10;
+        assert_eq!(banner().for_client(&caps), None);
+    }
+
+    #[test]
+    fn without_a_plain_file_the_art_is_stripped() {
+        let only_ansi = Banner {plain: None, ..banner()};
+        assert_eq!(only_ansi.for_client(&ProtocolCapabilities::telnet()).as_deref(), Some("(\\/) CRAB (\\/)"));
+    }
+}
@@ -0,0 +1,160 @@
+use legion::*;
+use serde_derive::{Serialize, Deserialize};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use crate::game::objects::{LocationComponent, LocationType, MudObjectComponent, MudProtoTypeComponent, MudSession,
+                           UserComponent};
+
+// What a session needs to pick up where it left off after a restart: the account with its
+// aliases and variables, and the room its puppet was in. Connections, processes, lag and
+// queued commands don't survive; players reconnect and log in as usual.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionSnapshot {
+    pub user: UserComponent,
+    // The room's objid, like "limbo/void". Rooms are found by it again, since entities
+    // don't survive a restart either.
+    pub location: Option<String>
+}
+
+fn objid_of(world: &impl EntityStore, ent: Entity) -> Option<String> {
+    let entry = world.entry_ref(ent).ok()?;
+    if let Ok(o) = entry.get_component::<MudObjectComponent>() {
+        return Some(o.objid.clone());
+    }
+    entry.get_component::<MudProtoTypeComponent>().ok().map(|p| p.objid.clone())
+}
+
+pub fn snapshot_sessions(world: &impl EntityStore) -> Vec<SessionSnapshot> {
+    let sessions: Vec<(Entity, Entity)> = <&MudSession>::query().iter(world)
+        .map(|msess| (msess.user, msess.puppet))
+        .collect();
+    let mut out = Vec::new();
+    for (user, puppet) in sessions {
+        let account = match world.entry_ref(user).ok().and_then(|e| e.get_component::<UserComponent>().ok().cloned()) {
+            Some(account) => account,
+            None => continue
+        };
+        let room = world.entry_ref(puppet).ok()
+            .and_then(|e| e.get_component::<LocationComponent>().ok().map(|loc| loc.entity));
+        out.push(SessionSnapshot {
+            user: account,
+            location: room.and_then(|room| objid_of(world, room))
+        });
+    }
+    out
+}
+
+// Recreates the accounts in snapshots, each placed in its old room if that still exists.
+// place_puppet puts them into the room's contents when they log back in. Accounts that
+// already exist, such as ones loaded from accounts_file, keep their data and only get their
+// room back. Returns how many were restored.
+pub fn restore_sessions(world: &mut World, snapshots: &[SessionSnapshot]) -> usize {
+    let mut restored = 0;
+    for snap in snapshots {
+        let key = snap.user.username.to_lowercase();
+        let existing = <(Entity, &UserComponent)>::query().iter(world)
+            .find(|(_, u)| u.username.to_lowercase() == key)
+            .map(|(ent, _)| *ent);
+        let room = snap.location.as_ref().and_then(|objid| <&MudProtoTypeComponent>::query().iter(world)
+            .find(|p| &p.objid == objid)
+            .map(|p| p.entity));
+        let user = existing.unwrap_or_else(|| world.push((snap.user.clone(),)));
+        if let (Some(room), Some(mut entry)) = (room, world.entry(user)) {
+            entry.add_component(LocationComponent {ltype: LocationType::Room, entity: room});
+        }
+        restored += 1;
+    }
+    restored
+}
+
+pub fn save_snapshot(path: impl AsRef<Path>, snapshots: &[SessionSnapshot]) -> std::io::Result<()> {
+    write(path, serde_json::to_string_pretty(snapshots)?)
+}
+
+pub fn load_snapshot(path: impl AsRef<Path>) -> std::io::Result<Vec<SessionSnapshot>> {
+    Ok(serde_json::from_str(&read_to_string(path)?)?)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::GameConfig;
+    use crate::testing::{temp_path, Harness, TWO_ROOMS};
+
+    fn account(world: &World, username: &str) -> Option<(UserComponent, Option<String>)> {
+        let (ent, user) = <(Entity, &UserComponent)>::query().iter(world)
+            .find(|(_, u)| u.username == username)
+            .map(|(ent, u)| (*ent, u.clone()))?;
+        let room = world.entry_ref(ent).ok()
+            .and_then(|e| e.get_component::<LocationComponent>().ok().map(|loc| loc.entity));
+        Some((user, room.and_then(|room| objid_of(world, room))))
+    }
+
+    #[test]
+    fn a_session_saved_at_shutdown_is_restored_into_a_fresh_world_once() {
+        let area = temp_path("area.json");
+        let snapshot = temp_path("sessions.json");
+        std::fs::write(&area, TWO_ROOMS).unwrap();
+        let game = GameConfig {areas: vec![area.clone()], start_room: Some("limbo/void".to_string()),
+                               session_snapshot: Some(snapshot.clone()), ..Default::default()};
+
+        let mut h = Harness::with_game(game.clone());
+        let mut alice = h.login("alice");
+        for user in <&mut UserComponent>::query().iter_mut(&mut h.engine.world) {
+            user.aliases.insert("k".to_string(), "kill".to_string());
+            user.variables.insert("foe".to_string(), "orc".to_string());
+            user.timezone = Some("+02:00".to_string());
+        }
+        h.send(&mut alice, "north");
+        h.engine.shutdown();
+        assert_eq!(load_snapshot(&snapshot).unwrap().len(), 1);
+        let (saved, room) = account(&h.engine.world, "alice").unwrap();
+        assert_eq!(room.as_deref(), Some("limbo/hall"));
+        alice.read();
+        drop(alice);
+
+        let mut h = Harness::with_game(game);
+        std::fs::remove_file(area).unwrap();
+        let restored_path = std::path::PathBuf::from(format!("{}.restored", snapshot.display()));
+        assert!(!snapshot.exists());
+        std::fs::remove_file(restored_path).unwrap();
+        let (restored, room) = account(&h.engine.world, "alice").unwrap();
+        assert_eq!(room.as_deref(), Some("limbo/hall"));
+        assert_eq!(restored.password, saved.password);
+        assert_eq!(restored.aliases, saved.aliases);
+        assert_eq!(restored.variables, saved.variables);
+        assert_eq!(restored.timezone, saved.timezone);
+
+        let mut alice = h.connect();
+        h.send(&mut alice, "connect alice=password");
+        let text = h.command(&mut alice, "look");
+        assert!(text.contains("A Hall"), "{:?}", text);
+    }
+
+    #[test]
+    fn accounts_loaded_from_the_accounts_file_get_their_rooms_back() {
+        let area = temp_path("area.json");
+        let accounts = temp_path("accounts.json");
+        let snapshot = temp_path("sessions.json");
+        std::fs::write(&area, TWO_ROOMS).unwrap();
+        let game = GameConfig {areas: vec![area.clone()], start_room: Some("limbo/void".to_string()),
+                               accounts_file: Some(accounts.clone()),
+                               session_snapshot: Some(snapshot.clone()), ..Default::default()};
+
+        let mut h = Harness::with_game(game.clone());
+        let mut alice = h.login("alice");
+        h.send(&mut alice, "north");
+        h.engine.shutdown();
+        assert!(accounts.exists());
+        alice.read();
+        drop(alice);
+
+        let h = Harness::with_game(game);
+        std::fs::remove_file(area).unwrap();
+        std::fs::remove_file(accounts).unwrap();
+        std::fs::remove_file(format!("{}.restored", snapshot.display())).unwrap();
+        assert_eq!(<&UserComponent>::query().iter(&h.engine.world).filter(|u| u.username == "alice").count(), 1);
+        let (_, room) = account(&h.engine.world, "alice").unwrap();
+        assert_eq!(room.as_deref(), Some("limbo/hall"));
+    }
+}
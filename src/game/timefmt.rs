@@ -0,0 +1,63 @@
+use chrono::{DateTime, FixedOffset, Utc};
+
+// Parses a timezone as typed by a player: "UTC", "Z", or an offset like "+2", "-05:30" or
+// "+0930".
+pub fn parse_utc_offset(src: &str) -> Option<FixedOffset> {
+    let src = src.trim();
+    if src.eq_ignore_ascii_case("utc") || src.eq_ignore_ascii_case("z") {
+        return FixedOffset::east_opt(0);
+    }
+
+    let sign = match src.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None
+    };
+    let digits: String = src[1..].chars().filter(|c| *c != ':').collect();
+    if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = if digits.len() <= 2 {
+        (digits.parse::<i32>().ok()?, 0)
+    } else {
+        let split = digits.len() - 2;
+        (digits[..split].parse::<i32>().ok()?, digits[split..].parse::<i32>().ok()?)
+    };
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+// Renders ts for a player in the given timezone, falling back to UTC when there is none or it
+// doesn't parse.
+pub fn format_timestamp(ts: DateTime<Utc>, timezone: Option<&str>) -> String {
+    match timezone.and_then(parse_utc_offset) {
+        Some(offset) if offset.local_minus_utc() != 0 => {
+            ts.with_timezone(&offset).format("%Y-%m-%d %H:%M %:z").to_string()
+        },
+        _ => ts.format("%Y-%m-%d %H:%M UTC").to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn one_instant_renders_per_timezone() {
+        let ts = Utc.with_ymd_and_hms(2021, 3, 14, 23, 30, 0).unwrap();
+        assert_eq!(format_timestamp(ts, None), "2021-03-14 23:30 UTC");
+        assert_eq!(format_timestamp(ts, Some("+2")), "2021-03-15 01:30 +02:00");
+        assert_eq!(format_timestamp(ts, Some("-05:30")), "2021-03-14 18:00 -05:30");
+        assert_eq!(format_timestamp(ts, Some("nowhere")), "2021-03-14 23:30 UTC");
+    }
+
+    #[test]
+    fn offsets_parse_in_every_spelling() {
+        assert_eq!(parse_utc_offset("+0930"), FixedOffset::east_opt(9 * 3600 + 30 * 60));
+        assert_eq!(parse_utc_offset("z"), FixedOffset::east_opt(0));
+        assert_eq!(parse_utc_offset("+15"), None);
+    }
+}
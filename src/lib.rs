@@ -2,4 +2,6 @@ pub mod config;
 pub mod engine;
 pub mod net;
 pub mod game;
-pub mod mudstring;
\ No newline at end of file
+pub mod mudstring;
+#[cfg(all(test, unix))]
+mod testing;
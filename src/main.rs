@@ -1,13 +1,26 @@
 use mudcrab::engine::Engine;
-use mudcrab::config::Config;
+use mudcrab::config::{Config, config_source_path};
 use serde::{Deserialize, Serialize};
 use serde_json::Result;
 
 fn main() {
-    let conf = Config::from_file(String::from("config.json")).unwrap();
+    let arg = std::env::args().nth(1);
+    let conf = Config::load(arg.clone()).unwrap();
 
     let mut eng = Engine::new(conf);
+    eng.set_config_path(config_source_path(arg.as_deref()));
     eng.setup();
+
+    // Signals only set flags. SIGHUP reopens the logs and reloads the config between ticks;
+    // SIGTERM and SIGINT end the loop so the listeners are closed on the way out.
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        signal_hook::flag::register(SIGHUP, eng.reload_handle()).unwrap();
+        signal_hook::flag::register(SIGTERM, eng.stop_handle()).unwrap();
+        signal_hook::flag::register(SIGINT, eng.stop_handle()).unwrap();
+    }
+
     eng.run();
     println!("Hello, Config: {:?}", eng.config);
 }
@@ -0,0 +1,67 @@
+// Removes every CSI escape sequence except SGR (color/style) ones, so cursor movement,
+// screen clears and the like never reach a client that can't cope with them.
+pub fn strip_cursor_control(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut chars = src.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.peek() == Some(&'[') {
+            let mut seq = String::from("\x1b[");
+            chars.next();
+            for n in chars.by_ref() {
+                seq.push(n);
+                // Any byte in 0x40..=0x7E ends a CSI sequence.
+                if ('\x40'..='\x7e').contains(&n) {
+                    break;
+                }
+            }
+            if seq.ends_with('m') {
+                out.push_str(&seq);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Removes every escape sequence and control character but newlines and tabs, leaving plain
+// text that is safe for log files and the server console. Player input goes through this
+// before it is logged.
+pub fn strip_control(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut chars = src.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI runs until a final byte in 0x40..=0x7E.
+                Some('[') => {
+                    for n in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&n) {
+                            break;
+                        }
+                    }
+                },
+                // OSC, DCS and friends run until BEL or ST (ESC \).
+                Some(']') | Some('P') | Some('X') | Some('^') | Some('_') => {
+                    while let Some(n) = chars.next() {
+                        if n == '\x07' {
+                            break;
+                        }
+                        if n == '\x1b' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                    }
+                },
+                // Anything else is a two-character sequence.
+                _ => {}
+            },
+            '\n' | '\t' => out.push(c),
+            c if c.is_control() => {},
+            c => out.push(c)
+        }
+    }
+    out
+}
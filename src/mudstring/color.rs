@@ -1,4 +1,3 @@
-use colori::{RgbColor, HslColor};
 use serde_derive::{Serialize, Deserialize};
 
 #[derive(Clone, Debug, PartialEq, Eq, Copy)]
@@ -18,7 +17,52 @@ impl ColorTriplet {
     }
 
     pub fn to_hex(&self) -> String {
-        format!("#{:x}{:x}{:x}", self.red, self.green, self.blue)
+        format!("#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+// xterm's defaults for the 16 standard colors.
+const STANDARD_PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255)
+];
+
+// The standard color closest to an RGB value.
+fn nearest_standard(red: u8, green: u8, blue: u8) -> u8 {
+    let dist = |(r, g, b): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(r, red) + d(g, green) + d(b, blue)
+    };
+    (0..16u8).min_by_key(|n| dist(STANDARD_PALETTE[*n as usize])).unwrap_or(0)
+}
+
+// The step of the 256-color cube closest to one channel. The cube's levels are 0, 95, 135,
+// 175, 215 and 255, not evenly spaced.
+fn cube_level(v: u8) -> u8 {
+    if v < 48 {
+        0
+    } else if v < 115 {
+        1
+    } else {
+        (v - 35) / 40
+    }
+}
+
+// RGB for an entry of the 256-color palette: the standard colors, a 6x6x6 cube, then 24 grays.
+pub fn eight_bit_rgb(num: u8) -> (u8, u8, u8) {
+    match num {
+        0..=15 => STANDARD_PALETTE[num as usize],
+        16..=231 => {
+            let n = num - 16;
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            (level(n / 36), level((n / 6) % 6), level(n % 6))
+        },
+        _ => {
+            let gray = 8 + (num - 232) * 10;
+            (gray, gray, gray)
+        }
     }
 }
 
@@ -138,6 +182,22 @@ impl Color {
         Self::from(ColorTriplet::new(red, green, blue))
     }
 
+    // CSS value for this color, or None for the terminal default, which the page's own
+    // stylesheet decides.
+    pub fn to_css(&self) -> Option<String> {
+        let (r, g, b) = self.rgb()?;
+        Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+    }
+
+    // What the color looks like, or None for the terminal default.
+    pub fn rgb(&self) -> Option<(u8, u8, u8)> {
+        match self.ctype {
+            ColorType::Default | ColorType::Windows => None,
+            ColorType::Standard | ColorType::EightBit => Some(eight_bit_rgb(self.number.unwrap_or(0))),
+            ColorType::TrueColor => self.triplet.map(|t| (t.red, t.green, t.blue))
+        }
+    }
+
     pub fn get_ansi_codes(&self, foreground: bool) -> String {
         match self.ctype {
             ColorType::Default => {
@@ -152,10 +212,12 @@ impl Color {
             },
             ColorType::Standard => {
                 if let Some(n) = &self.number {
-                    if foreground {
-                        format!("{}", 30 + n)
-                    } else {
-                        format!("{}", 40 + n)
+                    // The bright half has its own codes; 30 + 12 would be a background.
+                    match (foreground, *n >= 8) {
+                        (true, false) => format!("{}", 30 + n),
+                        (false, false) => format!("{}", 40 + n),
+                        (true, true) => format!("{}", 90 + n - 8),
+                        (false, true) => format!("{}", 100 + n - 8)
                     }
                 } else {
                     if foreground {
@@ -173,61 +235,39 @@ impl Color {
                 }
             },
             ColorType::TrueColor => {
-                if let Some(t) = &self.triplet {
-                    if foreground {
-                        format!("38;2;{};{};{}", t.red, t.green, t.blue)
-                    } else {
-                        format!("48;2;{};{};{}", t.red, t.green, t.blue)
-                    }
-                } else {
-                    if foreground {
-                        format!("38;2;{};{};{}", 0, 0, 0)
-                    } else {
-                        format!("48;2;{};{};{}", 0, 0, 0)
-                    }
+                // Without a triplet there's nothing to send; the style leaves the color out.
+                match &self.triplet {
+                    Some(t) if foreground => format!("38;2;{};{};{}", t.red, t.green, t.blue),
+                    Some(t) => format!("48;2;{};{};{}", t.red, t.green, t.blue),
+                    None => String::new()
                 }
             }
         }
     }
 
+    // The closest color downto can show. Colors it can already show are returned as they are.
     pub fn downgrade(&self, downto: ColorSystem) -> Self {
-        let down_val = downto as u8;
-        let sys_val = self.system() as u8;
-
-        if (sys_val == 0) | (down_val == sys_val) {
-            return self.clone()
-        }
+        let (red, green, blue) = match self.rgb() {
+            Some(rgb) if self.system() as u8 > downto as u8 => rgb,
+            _ => return self.clone()
+        };
 
-        if (sys_val == 3) & (down_val == 2) {
-            // Convert from TRUECOLOR to EIGHTBIT
-            let trip = self.triplet.unwrap();
-            let mut rgb = RgbColor(trip.red, trip.green, trip.blue);
-            let hsl = rgb.into_hsl();
-
-            if hsl.saturation() < 0.1 {
-                let mut color_number : u8 = 0;
-                let gray = (hsl.lightness() * 25.0).round() as i32;
-                if gray == 0 {
-                    color_number = 16;
-                } else if gray == 25 {
-                    color_number = 231;
-                } else {
-                    color_number = 231 + gray as u8;
-                }
-                return Color::from(color_number);
-            }
-            let color_number = (16 + 36 * (trip.red * 5) + 6 * (trip.green * 5) + (trip.blue * 5));
-            return Color::from(color_number as u8);
+        if downto == ColorSystem::Standard {
+            return Color::from(nearest_standard(red, green, blue));
         }
 
-        else if down_val == 1 {
-            if sys_val == 3 {
-
-            } else {
-
-            }
+        // Near-grays go to the gray ramp, which has finer steps than the cube's diagonal. Its
+        // 24 grays run from 8 to 238, with the cube's black and white at either end.
+        let (max, min) = (red.max(green).max(blue), red.min(green).min(blue));
+        if max - min < 16 {
+            let avg = (red as u16 + green as u16 + blue as u16) / 3;
+            let color_number = match avg {
+                0..=3 => 16,
+                247..=255 => 231,
+                _ => 232 + ((avg.saturating_sub(3)) / 10).min(23) as u8
+            };
+            return Color::from(color_number);
         }
-        return self.clone()
+        Color::from(16 + 36 * cube_level(red) + 6 * cube_level(green) + cube_level(blue))
     }
-
 }
\ No newline at end of file
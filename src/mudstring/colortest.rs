@@ -0,0 +1,79 @@
+use crate::mudstring::{
+    color::{Color, ColorSystem},
+    style::Style,
+    text::{Segment, Text}
+};
+
+fn swatch(label: String, color: Color) -> Segment {
+    Segment {
+        text: label,
+        style: Some(Style {bgcolor: Some(color), ..Default::default()})
+    }
+}
+
+// Lines showing every color tier up to what system supports, with a note in place of each
+// tier it doesn't.
+pub fn color_test(system: Option<ColorSystem>) -> Vec<Text> {
+    let mut out = Vec::new();
+    let system = match system {
+        Some(s) => s,
+        None => {
+            out.push(Text::from("Your client does not report color support."));
+            return out;
+        }
+    };
+    let eightbit = matches!(system, ColorSystem::EightBit | ColorSystem::TrueColor);
+    let truecolor = system == ColorSystem::TrueColor;
+
+    out.push(Text::from("Standard colors:"));
+    out.push(Text::from((0..16u8).map(|n| swatch(format!(" {:>2} ", n), Color::from(n))).collect::<Vec<Segment>>()));
+
+    if eightbit {
+        out.push(Text::from("256-color cube:"));
+        for row in 0..6u8 {
+            let start = 16 + row * 36;
+            out.push(Text::from((start..start + 36).map(|n| swatch("  ".to_string(), Color::from(n))).collect::<Vec<Segment>>()));
+        }
+        out.push(Text::from((232..=255u8).map(|n| swatch("  ".to_string(), Color::from(n))).collect::<Vec<Segment>>()));
+    } else {
+        out.push(Text::from("256-color: not supported by your client."));
+    }
+
+    if truecolor {
+        out.push(Text::from("Truecolor gradient:"));
+        out.push(Text::from((0..64u8).map(|i| {
+            let step = i * 4;
+            swatch(" ".to_string(), Color::from_rgb(255 - step, step / 2, step))
+        }).collect::<Vec<Segment>>()));
+    } else {
+        out.push(Text::from("Truecolor: not supported by your client."));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(system: ColorSystem) -> String {
+        color_test(Some(system)).iter().map(|t| t.render(Some(system), false, false, false)).collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn ansi_clients_only_get_sixteen_colors() {
+        let out = rendered(ColorSystem::Standard);
+        assert!(out.contains("\x1b[41m"));
+        assert!(!out.contains("48;5;"));
+        assert!(!out.contains("48;2;"));
+        assert!(out.contains("256-color: not supported"));
+        assert!(out.contains("Truecolor: not supported"));
+    }
+
+    #[test]
+    fn truecolor_clients_get_every_tier() {
+        let out = rendered(ColorSystem::TrueColor);
+        assert!(out.contains("48;5;196"));
+        assert!(out.contains("48;2;"));
+        assert!(!out.contains("not supported"));
+    }
+}
@@ -1,3 +1,6 @@
 pub mod color;
 pub mod style;
-pub mod text;
\ No newline at end of file
+pub mod text;
+pub mod ansi;
+pub mod colortest;
+pub mod widget;
\ No newline at end of file
@@ -6,7 +6,7 @@ use std::collections::{HashMap};
 use xmltree::Element;
 
 use html_escape::{
-    encode_text
+    encode_text, encode_double_quoted_attribute
 };
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -105,10 +105,82 @@ impl Style {
             }
         }
 
+        out.retain(|code| !code.is_empty());
         out.join(";")
 
     }
 
+    // Inline CSS for this style, for clients that take HTML instead of ANSI. Colors are left
+    // out when system is None, as render does.
+    pub fn css(&self, system: Option<ColorSystem>) -> String {
+        let css_of = |color: &Option<Color>| system.and(color.as_ref()).and_then(|c| c.to_css());
+        let (mut fg, mut bg) = (css_of(&self.color), css_of(&self.bgcolor));
+        if self.reverse {
+            std::mem::swap(&mut fg, &mut bg);
+        }
+
+        let mut out = Vec::new();
+        if let Some(c) = fg {
+            out.push(format!("color:{}", c));
+        }
+        if let Some(c) = bg {
+            out.push(format!("background-color:{}", c));
+        }
+        if self.bold {
+            out.push("font-weight:bold".to_string());
+        }
+        if self.dim {
+            out.push("opacity:0.7".to_string());
+        }
+        if self.italic {
+            out.push("font-style:italic".to_string());
+        }
+        if self.conceal {
+            out.push("visibility:hidden".to_string());
+        }
+
+        let mut decorations = Vec::new();
+        if self.underline || self.underline2 {
+            decorations.push("underline");
+        }
+        if self.overline {
+            decorations.push("overline");
+        }
+        if self.strike {
+            decorations.push("line-through");
+        }
+        if self.blink || self.blink2 {
+            decorations.push("blink");
+        }
+        if !decorations.is_empty() {
+            out.push(format!("text-decoration:{}", decorations.join(" ")));
+        }
+        if self.underline2 {
+            out.push("text-decoration-style:double".to_string());
+        }
+        if self.frame || self.encircle {
+            out.push("outline:1px solid".to_string());
+        }
+
+        out.join(";")
+    }
+
+    pub fn render_html(&self, text: impl AsRef<str>, system: Option<ColorSystem>) -> String {
+        let mut rendered = encode_text(&text).to_string();
+        if rendered.is_empty() {
+            return rendered;
+        }
+
+        let css = self.css(system);
+        if !css.is_empty() {
+            rendered = format!("<span style=\"{}\">{}</span>", css, rendered);
+        }
+        if let Some(link) = &self.link {
+            rendered = format!("<a href=\"{}\" target=\"_blank\">{}</a>", encode_double_quoted_attribute(&link.url), rendered);
+        }
+        rendered
+    }
+
     pub fn render(&self, text: impl AsRef<str>, system: Option<ColorSystem>, legacy_windows: bool, links: bool, mxp: bool) -> String {
         let t = if mxp {
             encode_text(&text).to_string()
@@ -119,11 +191,13 @@ impl Style {
             return t
         }
 
-        let mut rendered = if let Some(sys) = system {
-            let attrs = self.ansi_codes(sys);
-            format!("\x1b[{}m{}\x1b[0m", attrs, t)
-        } else {
+        // Every span that opens an escape closes it with a reset, so colors never bleed into
+        // the next span. One with nothing to set gets neither.
+        let attrs = system.map(|sys| self.ansi_codes(sys)).unwrap_or_default();
+        let mut rendered = if attrs.is_empty() {
             t
+        } else {
+            format!("\x1b[{}m{}\x1b[0m", attrs, t)
         };
 
         if links & !legacy_windows {
@@ -134,11 +208,12 @@ impl Style {
 
         if mxp {
             if let Some(elem) = &self.element {
-                if elem.attributes.len() > 0 {
-
-                } else {
-                    rendered = format!("\x1b[4z<{}>{}\x1b]4z</{}>", elem.name, rendered, elem.name);
-                }
+                // Each tag is sent in temp-secure mode, so it's active even on an open line.
+                let mut attrs: Vec<String> = elem.attributes.iter()
+                    .map(|(k, v)| format!(" {}=\"{}\"", k, encode_double_quoted_attribute(v)))
+                    .collect();
+                attrs.sort();
+                rendered = format!("\x1b[4z<{}{}>{}\x1b[4z</{}>", elem.name, attrs.join(""), rendered, elem.name);
             }
         }
 
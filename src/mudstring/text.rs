@@ -2,6 +2,8 @@ use crate::mudstring::{
     style::{Style},
     color::{ColorSystem}
 };
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use html_escape::encode_text;
 
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct Span {
@@ -69,16 +71,238 @@ impl From<&str> for Text {
     }
 }
 
+impl std::ops::Add for Text {
+    type Output = Text;
+
+    fn add(mut self, other: Text) -> Text {
+        self.append(&other);
+        self
+    }
+}
+
+impl Extend<Text> for Text {
+    fn extend<T: IntoIterator<Item = Text>>(&mut self, iter: T) {
+        for t in iter {
+            self.append(&t);
+        }
+    }
+}
+
 impl Text {
+    // Adds other to the end, shifting its spans so each part keeps its own styling.
+    pub fn append(&mut self, other: &Text) {
+        let offset = self.plain.len();
+        self.spans.retain(|sp| sp.start < sp.end);
+        self.plain.push_str(&other.plain);
+        self.spans.extend(other.spans.iter()
+            .filter(|sp| sp.start < sp.end)
+            .map(|sp| Span {start: sp.start + offset, end: sp.end + offset, style: sp.style.clone()}));
+        if self.spans.is_empty() {
+            self.spans.push(Span {start: 0, end: self.plain.len(), style: None});
+        }
+    }
+
+    pub fn join(separator: &Text, parts: &[Text]) -> Text {
+        let mut out = Text::default();
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                out.append(separator);
+            }
+            out.append(part);
+        }
+        out
+    }
+
     pub fn render(&self, system: Option<ColorSystem>, legacy_windows: bool, links: bool, mxp: bool) -> String {
         let mut out = String::new();
         for sp in &self.spans {
             if let Some(c) = &sp.style {
                 out.push_str(&c.render(&self.plain[sp.start..sp.end], system, legacy_windows, links, mxp));
+            } else if mxp {
+                out.push_str(&encode_text(&self.plain[sp.start..sp.end]));
             } else {
                 out.push_str(&self.plain[sp.start..sp.end]);
             }
         }
         out
     }
-}
\ No newline at end of file
+
+    // The same, as HTML: text is entity-escaped and styled spans become <span style="...">.
+    pub fn render_html(&self, system: Option<ColorSystem>) -> String {
+        let mut out = String::new();
+        for sp in &self.spans {
+            if let Some(c) = &sp.style {
+                out.push_str(&c.render_html(&self.plain[sp.start..sp.end], system));
+            } else {
+                out.push_str(&encode_text(&self.plain[sp.start..sp.end]));
+            }
+        }
+        out
+    }
+
+    // Gives every occurrence of needle the given style, splitting spans where needed.
+    pub fn highlight(&self, needle: &str, style: &Style) -> Text {
+        if needle.is_empty() {
+            return self.clone();
+        }
+        let matches: Vec<(usize, usize)> = self.plain.match_indices(needle)
+            .map(|(start, m)| (start, start + m.len()))
+            .collect();
+        if matches.is_empty() {
+            return self.clone();
+        }
+
+        let mut spans = Vec::with_capacity(self.spans.len() + matches.len() * 2);
+        for sp in &self.spans {
+            let mut cuts = vec![sp.start, sp.end];
+            for (start, end) in matches.iter() {
+                cuts.extend([*start, *end].iter().filter(|c| **c > sp.start && **c < sp.end));
+            }
+            cuts.sort_unstable();
+            cuts.dedup();
+            for pair in cuts.windows(2) {
+                let inside = matches.iter().any(|(start, end)| pair[0] >= *start && pair[1] <= *end);
+                let style = if inside { Some(style.clone()) } else { sp.style.clone() };
+                spans.push(Span {start: pair[0], end: pair[1], style});
+            }
+        }
+        Self {
+            plain: self.plain.clone(),
+            spans
+        }
+    }
+
+    // Whether any part of the text is an MXP element the server put there.
+    pub fn has_mxp(&self) -> bool {
+        self.spans.iter().any(|sp| sp.style.as_ref().map(|s| s.element.is_some()).unwrap_or(false))
+    }
+
+    // Columns the text takes up on a terminal. Wide (e.g. CJK) characters count as two.
+    pub fn display_width(&self) -> usize {
+        self.plain.width()
+    }
+
+    // Cuts the text down to at most width columns without splitting a character, keeping
+    // every span's style. If anything was cut, ellipsis is appended in the style of the last
+    // kept span and counts toward the width.
+    pub fn truncate_display(&self, width: usize, ellipsis: Option<&str>) -> Text {
+        if self.display_width() <= width {
+            return self.clone();
+        }
+        let ellipsis = ellipsis.filter(|e| e.width() <= width).unwrap_or("");
+        let budget = width - ellipsis.width();
+
+        let mut used = 0;
+        let mut cut = 0;
+        for (idx, c) in self.plain.char_indices() {
+            let w = c.width().unwrap_or(0);
+            if used + w > budget {
+                break;
+            }
+            used += w;
+            cut = idx + c.len_utf8();
+        }
+
+        let mut plain = self.plain[..cut].to_string();
+        let mut spans: Vec<Span> = self.spans.iter()
+            .filter(|sp| sp.start < cut)
+            .map(|sp| Span {start: sp.start, end: sp.end.min(cut), style: sp.style.clone()})
+            .collect();
+        if spans.is_empty() {
+            spans.push(Span {start: 0, end: cut, style: None});
+        }
+        plain.push_str(ellipsis);
+        if let Some(last) = spans.last_mut() {
+            last.end = plain.len();
+        }
+        Self {
+            plain,
+            spans
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mudstring::color::{Color, ColorType};
+
+    fn red(text: &str) -> Text {
+        Text::from(vec![Segment {
+            text: text.to_string(),
+            style: Some(Style {color: Some(Color::from_rgb(255, 0, 0)), ..Default::default()})
+        }])
+    }
+
+    #[test]
+    fn truncated_color_keeps_its_reset() {
+        let mut text = red("A very long warning");
+        text.append(&Text::from(" and more"));
+        let cut = text.truncate_display(10, Some("…"));
+        assert_eq!(cut.plain, "A very lo…");
+        assert_eq!(cut.display_width(), 10);
+
+        let rendered = cut.render(Some(ColorSystem::Standard), false, false, false);
+        assert!(rendered.starts_with("\x1b["));
+        assert!(rendered.ends_with("\x1b[0m"), "{:?}", rendered);
+    }
+
+    #[test]
+    fn truncation_never_splits_a_wide_character() {
+        let cut = Text::from("日本語テキスト").truncate_display(5, None);
+        assert_eq!(cut.plain, "日本");
+        assert_eq!(Text::from("short").truncate_display(10, Some("…")).plain, "short");
+    }
+
+    fn colored(text: &str, n: u8) -> Text {
+        Text::from(vec![Segment {
+            text: text.to_string(),
+            style: Some(Style {color: Some(Color::from(n)), ..Default::default()})
+        }])
+    }
+
+    #[test]
+    fn joined_parts_keep_their_own_colors() {
+        let joined = Text::join(&Text::from(", "), &[colored("fire", 202), colored("ice", 45)]);
+        assert_eq!(joined.plain, "fire, ice");
+        assert_eq!(joined.render(Some(ColorSystem::EightBit), false, false, false),
+                   "\x1b[38;5;202mfire\x1b[0m, \x1b[38;5;45mice\x1b[0m");
+
+        let added = colored("fire", 202) + Text::from(", ") + colored("ice", 45);
+        assert_eq!(added, joined);
+    }
+
+    #[test]
+    fn html_rendering_uses_spans_and_escapes() {
+        let text = red("alert") + Text::from(" <b>1 & 2</b> ") + colored("sky", 45);
+        assert_eq!(text.render_html(Some(ColorSystem::TrueColor)),
+                   "<span style=\"color:#ff0000\">alert</span> &lt;b&gt;1 &amp; 2&lt;/b&gt; <span style=\"color:#00d7ff\">sky</span>");
+        assert_eq!(text.render_html(None), "alert &lt;b&gt;1 &amp; 2&lt;/b&gt; sky");
+
+        let bold = Style {bold: true, ..Default::default()};
+        assert_eq!(Text::from(vec![Segment {text: "a<b".to_string(), style: Some(bold)}]).render_html(None),
+                   "<span style=\"font-weight:bold\">a&lt;b</span>");
+    }
+
+    #[test]
+    fn truecolor_spans_send_their_exact_rgb_and_reset() {
+        let sky = Style {color: Some(Color::from_rgb(0x1e, 0x90, 0xff)), bgcolor: Some(Color::from_rgb(0, 0, 0x80)), ..Default::default()};
+        let text = Text::from(vec![Segment {text: "sky".to_string(), style: Some(sky)}]) + Text::from(" after");
+        assert_eq!(text.render(Some(ColorSystem::TrueColor), false, false, false),
+                   "\x1b[38;2;30;144;255;48;2;0;0;128msky\x1b[0m after");
+
+        // A truecolor color with no value isn't made up as black.
+        let unset = Color {ctype: ColorType::TrueColor, ..Default::default()};
+        let text = Text::from(vec![Segment {text: "plain".to_string(), style: Some(Style {color: Some(unset), ..Default::default()})}]);
+        for system in [ColorSystem::Standard, ColorSystem::EightBit, ColorSystem::TrueColor] {
+            assert_eq!(text.render(Some(system), false, false, false), "plain");
+        }
+    }
+
+    #[test]
+    fn bright_standard_colors_use_the_bright_codes() {
+        let bright = Style {color: Some(Color::from(9u8)), bgcolor: Some(Color::from(12u8)), ..Default::default()};
+        let text = Text::from(vec![Segment {text: "hi".to_string(), style: Some(bright)}]);
+        assert_eq!(text.render(Some(ColorSystem::Standard), false, false, false), "\x1b[91;104mhi\x1b[0m");
+    }
+}
@@ -0,0 +1,65 @@
+use crate::mudstring::{
+    color::{Color, ColorSystem},
+    style::Style,
+    text::{Segment, Text}
+};
+
+// A bar like [#####-----] for things like hit points, drawn for what the connection can show.
+// With UTF-8 the cells are blocks, █ filled and ░ empty, in filled_color and empty_color when
+// there's color too. Color without UTF-8 paints spaces in those colors instead. Without
+// either it stays plain ASCII, so everyone can still see how full it is. width counts the
+// cells, not the brackets.
+pub fn gauge(current: i64, max: i64, width: usize, filled_color: Color, empty_color: Color,
+             system: Option<ColorSystem>, utf8: bool) -> Text {
+    let filled = if max <= 0 {
+        0
+    } else {
+        let current = current.clamp(0, max) as f64;
+        ((current / max as f64) * width as f64).round() as usize
+    };
+    let cells = |ch: char, count: usize, color: Color| {
+        let style = match (system.is_some(), utf8) {
+            (true, true) => Some(Style {color: Some(color), ..Default::default()}),
+            (true, false) => Some(Style {bgcolor: Some(color), ..Default::default()}),
+            (false, _) => None
+        };
+        Segment {text: ch.to_string().repeat(count), style}
+    };
+    let (full, empty) = match (system.is_some(), utf8) {
+        (_, true) => ('█', '░'),
+        (true, false) => (' ', ' '),
+        (false, false) => ('#', '-')
+    };
+    Text::from(vec![
+        Segment {text: "[".to_string(), style: None},
+        cells(full, filled, filled_color),
+        cells(empty, width - filled, empty_color),
+        Segment {text: "]".to_string(), style: None}
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_half_full_gauge_fills_half_its_cells() {
+        let bar = |system, utf8| gauge(50, 100, 10, Color::from(46u8), Color::from(240u8), system, utf8);
+        assert_eq!(bar(None, false).plain, "[#####-----]");
+        assert_eq!(bar(None, false).render(None, false, false, false), "[#####-----]");
+        assert_eq!(bar(None, true).render(None, false, false, false), "[█████░░░░░]");
+        assert_eq!(bar(Some(ColorSystem::EightBit), true).render(Some(ColorSystem::EightBit), false, false, false),
+                   "[\x1b[38;5;46m█████\x1b[0m\x1b[38;5;240m░░░░░\x1b[0m]");
+        assert_eq!(bar(Some(ColorSystem::EightBit), false).render(Some(ColorSystem::EightBit), false, false, false),
+                   "[\x1b[48;5;46m     \x1b[0m\x1b[48;5;240m     \x1b[0m]");
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let green = Color::from(2u8);
+        let red = Color::from(1u8);
+        assert_eq!(gauge(-5, 100, 4, green.clone(), red.clone(), None, false).plain, "[----]");
+        assert_eq!(gauge(500, 100, 4, green.clone(), red.clone(), None, false).plain, "[####]");
+        assert_eq!(gauge(3, 0, 4, green, red, None, false).plain, "[----]");
+    }
+}
@@ -0,0 +1,151 @@
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use crate::config::NetConfig;
+use crate::mudstring::ansi::strip_control;
+use crate::net::{ConnectionComponent, ProtocolComponent};
+
+// One JSON object per line for every connection that comes and goes, for security audits.
+// A connect and its disconnect share a token; tokens are reused, so pair each disconnect
+// with the last connect before it.
+#[derive(Default)]
+pub struct AuditLog {
+    sink: Option<Box<dyn Write + Send + Sync>>,
+    // The file sink was opened from, so reopen can find it again after log rotation.
+    path: Option<PathBuf>
+}
+
+impl AuditLog {
+    // Appends to the file at path, creating it if need be.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {sink: Some(Box::new(file)), path: Some(path.to_path_buf())})
+    }
+
+    // Opens the file again by name, so records go to a fresh file once logrotate has moved
+    // the old one away. Logs that aren't files are left as they are.
+    pub fn reopen(&mut self) {
+        if let Some(path) = self.path.clone() {
+            *self = Self::open(&path).unwrap_or_else(|e| {
+                println!("Could not reopen audit log {}: {}", path.display(), e);
+                Self {sink: None, path: Some(path.clone())}
+            });
+        }
+    }
+
+    // The log net.audit_log asks for. If it can't be opened that's reported and nothing is
+    // audited, rather than refusing to start.
+    pub fn from_config(net: Option<&NetConfig>) -> Self {
+        match net.and_then(|n| n.audit_log.as_ref()) {
+            Some(path) => Self::open(path).unwrap_or_else(|e| {
+                println!("Could not open audit log {}: {}", path.display(), e);
+                Self::default()
+            }),
+            None => Self::default()
+        }
+    }
+
+    pub fn to_writer(writer: impl Write + Send + Sync + 'static) -> Self {
+        Self {sink: Some(Box::new(writer)), path: None}
+    }
+
+    // utc is the engine clock's time, like now.
+    pub fn connect(&mut self, conn: &ConnectionComponent, utc: DateTime<Utc>) {
+        self.record(json!({
+            "event": "connect",
+            "time": utc.to_rfc3339(),
+            "token": conn.token.0,
+            "addr": conn.addr.to_string(),
+            "listener": conn.listener,
+            "protocol": conn.protocol.name()
+        }));
+    }
+
+    // prot is gone already if the connection never got that far.
+    pub fn disconnect(&mut self, conn: &ConnectionComponent, prot: Option<&ProtocolComponent>, now: Instant, utc: DateTime<Utc>) {
+        self.record(json!({
+            "event": "disconnect",
+            "time": utc.to_rfc3339(),
+            "token": conn.token.0,
+            "addr": conn.addr.to_string(),
+            "listener": conn.listener,
+            "protocol": conn.protocol.name(),
+            "client": prot.map(|p| format!("{} {}", p.capabilities.client_name, p.capabilities.client_version)),
+            "reason": format!("{:?}", conn.status),
+            "connected_secs": prot.map(|p| now.saturating_duration_since(p.created).as_secs())
+        }));
+    }
+
+    // A session starting or stopping to skip rate limits and process caps. Everything it runs
+    // while exempt is recorded with exempt_command.
+    pub fn exemption(&mut self, account: &str, exempt: bool, utc: DateTime<Utc>) {
+        self.record(json!({
+            "event": "exemption",
+            "time": utc.to_rfc3339(),
+            "account": account,
+            "exempt": exempt
+        }));
+    }
+
+    pub fn exempt_command(&mut self, account: &str, command: &str, utc: DateTime<Utc>) {
+        self.record(json!({
+            "event": "exempt_command",
+            "time": utc.to_rfc3339(),
+            "account": account,
+            "command": strip_control(command)
+        }));
+    }
+
+    fn record(&mut self, record: serde_json::Value) {
+        if let Some(sink) = &mut self.sink {
+            if let Err(e) = writeln!(sink, "{}", record) {
+                // Better to stop than to keep failing on every connection.
+                println!("Could not write the audit log, so it's off until the next reload: {}", e);
+                self.sink = None;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::testing::{temp_path, Client, Harness};
+    use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+
+    #[test]
+    fn a_connection_that_hangs_up_leaves_a_matched_pair() {
+        let path = temp_path("audit.log");
+        let net = serde_json::from_value(serde_json::json!({"audit_log": path})).unwrap();
+        let mut h = Harness::with_config(Some(net), None);
+        h.clock.set_utc(Utc.with_ymd_and_hms(2026, 10, 17, 12, 0, 0).unwrap());
+        let stream = h.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        h.tick(3);
+        // Nothing left unread, so closing is a plain EOF rather than a reset.
+        client.read();
+        drop(client);
+        h.tick(5);
+        drop(h);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let records: Vec<serde_json::Value> = log.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(records.len(), 2, "{}", log);
+        assert_eq!(records[0]["event"], "connect");
+        assert_eq!(records[0]["protocol"], "telnet");
+        assert_eq!(records[1]["event"], "disconnect");
+        assert_eq!(records[0]["token"], records[1]["token"]);
+        assert_eq!(records[0]["addr"], records[1]["addr"]);
+        assert_eq!(records[1]["reason"], "ClientEOF");
+        assert_eq!(records[1]["protocol"], "telnet");
+        assert_eq!(records[0]["listener"], records[1]["listener"]);
+        for record in &records {
+            assert!(record["time"].as_str().unwrap().starts_with("2026-10-17T12:00:0"), "{}", record);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+// Everything the net layer can fail with, so embedders can match on it instead of digging
+// through io::Error kinds.
+#[derive(Debug)]
+pub enum NetError {
+    BindFailed(SocketAddr, std::io::Error),
+    // A Unix domain socket couldn't be bound at this path.
+    UnixBindFailed(PathBuf, std::io::Error),
+    PollFailed(std::io::Error),
+    ProtocolViolation(String),
+    TlsHandshake(String),
+    // The configured certificate or key couldn't be loaded.
+    TlsConfig(String),
+    // The peer sent more than this many bytes without completing a message.
+    BufferOverflow(usize),
+    Io(std::io::Error)
+}
+
+impl Display for NetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetError::BindFailed(addr, e) => write!(f, "could not bind {}: {}", addr, e),
+            NetError::UnixBindFailed(path, e) => write!(f, "could not bind {}: {}", path.display(), e),
+            NetError::PollFailed(e) => write!(f, "polling failed: {}", e),
+            NetError::ProtocolViolation(msg) => write!(f, "protocol violation: {}", msg),
+            NetError::TlsHandshake(msg) => write!(f, "TLS handshake failed: {}", msg),
+            NetError::TlsConfig(msg) => write!(f, "could not load TLS certificate: {}", msg),
+            NetError::BufferOverflow(len) => write!(f, "incomplete message exceeded {} bytes", len),
+            NetError::Io(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for NetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetError::BindFailed(_, e) | NetError::UnixBindFailed(_, e) | NetError::PollFailed(e) | NetError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for NetError {
+    fn from(e: std::io::Error) -> Self {
+        NetError::Io(e)
+    }
+}
+
+impl From<NetError> for std::io::Error {
+    fn from(e: NetError) -> Self {
+        match e {
+            NetError::BindFailed(_, e) | NetError::UnixBindFailed(_, e) | NetError::PollFailed(e) | NetError::Io(e) => e,
+            other => std::io::Error::new(std::io::ErrorKind::InvalidData, other.to_string())
+        }
+    }
+}
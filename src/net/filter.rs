@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use crate::mudstring::text::Text;
+use crate::mudstring::style::Style;
+use crate::net::ProtocolOutEvent;
+
+// Something outgoing lines are run through before they are rendered. Returning no lines drops
+// the input, one passes or changes it, and several split it.
+pub trait OutputFilter: Debug + Send + Sync {
+    fn filter(&mut self, text: Text) -> Vec<Text>;
+}
+
+// Drops any line containing pattern.
+#[derive(Debug, Clone)]
+pub struct GagFilter {
+    pub pattern: String
+}
+
+impl OutputFilter for GagFilter {
+    fn filter(&mut self, text: Text) -> Vec<Text> {
+        if text.plain.contains(&self.pattern) {
+            Vec::new()
+        } else {
+            vec![text]
+        }
+    }
+}
+
+// Restyles every occurrence of keyword.
+#[derive(Debug, Clone)]
+pub struct HighlightFilter {
+    pub keyword: String,
+    pub style: Style
+}
+
+impl OutputFilter for HighlightFilter {
+    fn filter(&mut self, text: Text) -> Vec<Text> {
+        vec![text.highlight(&self.keyword, &self.style)]
+    }
+}
+
+// Runs every Line through the filters in order. Other events pass untouched.
+pub fn apply_filters(filters: &mut [Box<dyn OutputFilter>], events: VecDeque<ProtocolOutEvent>) -> VecDeque<ProtocolOutEvent> {
+    if filters.is_empty() {
+        return events;
+    }
+    let mut out = VecDeque::with_capacity(events.len());
+    for ev in events {
+        match ev {
+            ProtocolOutEvent::Line(text) => {
+                let mut lines = vec![text];
+                for f in filters.iter_mut() {
+                    lines = lines.into_iter().flat_map(|l| f.filter(l)).collect();
+                }
+                out.extend(lines.into_iter().map(ProtocolOutEvent::Line));
+            },
+            other => out.push_back(other)
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mudstring::color::{Color, ColorSystem};
+
+    fn lines(events: VecDeque<ProtocolOutEvent>) -> Vec<Text> {
+        events.into_iter().filter_map(|ev| match ev {
+            ProtocolOutEvent::Line(text) => Some(text),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn gag_drops_and_highlight_colors() {
+        let red = Style {color: Some(Color::from_rgb(255, 0, 0)), ..Default::default()};
+        let mut filters: Vec<Box<dyn OutputFilter>> = vec![
+            Box::new(GagFilter {pattern: "spam".to_string()}),
+            Box::new(HighlightFilter {keyword: "dragon".to_string(), style: red})
+        ];
+        let events: VecDeque<_> = vec![
+            ProtocolOutEvent::Line(Text::from("Buy spam now!")),
+            ProtocolOutEvent::Line(Text::from("A dragon lands.")),
+            ProtocolOutEvent::Prompt(Text::from("> "))
+        ].into_iter().collect();
+
+        let out = apply_filters(&mut filters, events);
+        assert_eq!(out.len(), 2);
+        assert!(matches!(out.back(), Some(ProtocolOutEvent::Prompt(_))));
+        let kept = lines(out);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].plain, "A dragon lands.");
+        assert_eq!(kept[0].render(Some(ColorSystem::Standard), false, false, false), "A \x1b[91mdragon\x1b[0m lands.");
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use crate::config::SuspicionConfig;
+
+// How many reasons a connection remembers, for the log line when it's dealt with.
+const KEPT_REASONS: usize = 5;
+
+// Something a connection did that a well-behaved client doesn't.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Offense {
+    // Negotiation or subnegotiation that doesn't follow the protocol.
+    Malformed(String),
+    // More lines in a second than the config allows.
+    Flood(usize),
+    // A line longer than the config allows.
+    Oversized(usize),
+    // A login attempt inside the cooldown after the last one.
+    RateLimited
+}
+
+impl Offense {
+    pub fn weight(&self) -> u32 {
+        match self {
+            Offense::Malformed(_) => 2,
+            Offense::Flood(_) => 5,
+            Offense::Oversized(_) => 5,
+            Offense::RateLimited => 3
+        }
+    }
+}
+
+impl Display for Offense {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Offense::Malformed(what) => write!(f, "malformed {}", what),
+            Offense::Flood(lines) => write!(f, "flooded {} lines in a second", lines),
+            Offense::Oversized(len) => write!(f, "sent a {} byte line", len),
+            Offense::RateLimited => write!(f, "tried to log in too fast")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Fine,
+    Throttle,
+    Disconnect
+}
+
+// A connection's running suspicion score. Offenses add to it and good behavior lets it
+// fall back by one every decay_secs.
+#[derive(Debug, Default)]
+pub struct Suspicion {
+    pub score: u32,
+    pub reasons: VecDeque<String>,
+    pub throttled: bool,
+    // While throttled, input isn't processed again until then.
+    pub next_read: Option<Instant>,
+    last_decay: Option<Instant>,
+    // Start of the current one second window and the lines seen in it.
+    window: Option<(Instant, usize)>
+}
+
+impl Suspicion {
+    pub fn flag(&mut self, offense: Offense) {
+        self.score = self.score.saturating_add(offense.weight());
+        if self.reasons.len() >= KEPT_REASONS {
+            self.reasons.pop_front();
+        }
+        self.reasons.push_back(offense.to_string());
+    }
+
+    // Counts lines toward flood detection. Returns the window's total the first time it goes
+    // over the limit, so each burst is dealt with once.
+    pub fn count_lines(&mut self, lines: usize, now: Instant, limit: usize) -> Option<usize> {
+        if lines == 0 {
+            return None;
+        }
+        let (start, seen) = match self.window {
+            Some((start, seen)) if now.saturating_duration_since(start) < Duration::from_secs(1) => (start, seen),
+            _ => (now, 0)
+        };
+        let total = seen + lines;
+        self.window = Some((start, total));
+        Some(total).filter(|_| seen <= limit && total > limit)
+    }
+
+    pub fn judge(&mut self, now: Instant, config: &SuspicionConfig) -> Verdict {
+        let every = Duration::from_secs(config.decay_secs.max(1));
+        let last = *self.last_decay.get_or_insert(now);
+        let steps = (now.saturating_duration_since(last).as_secs() / every.as_secs()) as u32;
+        if steps > 0 {
+            self.score = self.score.saturating_sub(steps);
+            self.last_decay = Some(last + every * steps);
+        }
+
+        if self.score >= config.disconnect_at {
+            Verdict::Disconnect
+        } else if self.score >= config.throttle_at {
+            Verdict::Throttle
+        } else {
+            Verdict::Fine
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        self.reasons.iter().cloned().collect::<Vec<String>>().join("; ")
+    }
+}
@@ -2,10 +2,21 @@ pub const NULL: u8 = 0;
 pub const BEL: u8 = 7;
 pub const CR: u8 = 13;
 pub const LF: u8 = 10;
+pub const ECHO: u8 = 1;
 pub const SGA: u8 = 3;
+// RFC 860. Not a real option: a DO is answered with a WILL once everything before it has
+// been processed, and nothing is ever switched on.
+pub const TIMING_MARK: u8 = 6;
+// RFC 885 END-OF-RECORD, the option. Once we WILL it, prompts are marked with the EOR
+// command below so the client knows where they end.
 pub const TELOPT_EOR: u8 = 25;
+// RFC 1408 ENVIRON. Superseded by NEW-ENVIRON but some old clients only know this one.
+pub const ENVIRON: u8 = 36;
+// RFC 1572 NEW-ENVIRON
+pub const NEW_ENVIRON: u8 = 39;
 pub const NAWS: u8 = 31;
 pub const LINEMODE: u8 = 34;
+// The end-of-record command itself, only ever sent after TELOPT_EOR is agreed.
 pub const EOR: u8 = 239;
 pub const SE: u8 = 240;
 pub const NOP: u8 = 241;
@@ -22,12 +33,23 @@ pub const IAC: u8 = 255;
 // MNES: Mud New-Environ standard
 pub const MNES: u8 = 39;
 
+// ENVIRON and NEW-ENVIRON subnegotiation commands and field markers.
+pub const ENV_IS: u8 = 0;
+pub const ENV_SEND: u8 = 1;
+pub const ENV_INFO: u8 = 2;
+pub const ENV_VAR: u8 = 0;
+pub const ENV_VALUE: u8 = 1;
+pub const ENV_ESC: u8 = 2;
+pub const ENV_USERVAR: u8 = 3;
+
 // MUD eXtension Protocol
 // NOTE: Disabled due to too many issues with it.
 pub const MXP: u8 = 91;
 
 // Mud Server Status Protocol
 pub const MSSP: u8 = 70;
+pub const MSSP_VAR: u8 = 1;
+pub const MSSP_VAL: u8 = 2;
 
 // Compression
 // pub const MCCP1: u8 = 85 - this is deprecrated
@@ -40,6 +62,165 @@ pub const GMCP: u8 = 201;
 
 // MSDP - Mud Server Data Protocol
 pub const MSDP: u8 = 69;
+pub const MSDP_VAR: u8 = 1;
+pub const MSDP_VAL: u8 = 2;
+pub const MSDP_TABLE_OPEN: u8 = 3;
+pub const MSDP_TABLE_CLOSE: u8 = 4;
+pub const MSDP_ARRAY_OPEN: u8 = 5;
+pub const MSDP_ARRAY_CLOSE: u8 = 6;
 
 // MTTS - Mud Terminal Type Standard
 pub const MTTS: u8 = 24;
+
+// Named form of the option codes above, for logs and name-based config. Anything we don't
+// know keeps its number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TelnetOptionCode {
+    Echo,
+    Sga,
+    Mtts,
+    TeloptEor,
+    TimingMark,
+    Naws,
+    Linemode,
+    Environ,
+    NewEnviron,
+    Msdp,
+    Mssp,
+    Mccp2,
+    Mccp3,
+    Mxp,
+    Gmcp,
+    Unknown(u8)
+}
+
+pub const KNOWN_OPTIONS: &[TelnetOptionCode] = &[
+    TelnetOptionCode::Echo, TelnetOptionCode::Sga, TelnetOptionCode::Mtts, TelnetOptionCode::TeloptEor,
+    TelnetOptionCode::TimingMark, TelnetOptionCode::Naws, TelnetOptionCode::Linemode, TelnetOptionCode::Environ, TelnetOptionCode::NewEnviron,
+    TelnetOptionCode::Msdp, TelnetOptionCode::Mssp, TelnetOptionCode::Mccp2, TelnetOptionCode::Mccp3,
+    TelnetOptionCode::Mxp, TelnetOptionCode::Gmcp
+];
+
+impl From<u8> for TelnetOptionCode {
+    fn from(code: u8) -> Self {
+        match code {
+            ECHO => Self::Echo,
+            SGA => Self::Sga,
+            MTTS => Self::Mtts,
+            TELOPT_EOR => Self::TeloptEor,
+            TIMING_MARK => Self::TimingMark,
+            NAWS => Self::Naws,
+            LINEMODE => Self::Linemode,
+            ENVIRON => Self::Environ,
+            NEW_ENVIRON => Self::NewEnviron,
+            MSDP => Self::Msdp,
+            MSSP => Self::Mssp,
+            MCCP2 => Self::Mccp2,
+            MCCP3 => Self::Mccp3,
+            MXP => Self::Mxp,
+            GMCP => Self::Gmcp,
+            other => Self::Unknown(other)
+        }
+    }
+}
+
+impl From<TelnetOptionCode> for u8 {
+    fn from(code: TelnetOptionCode) -> u8 {
+        match code {
+            TelnetOptionCode::Echo => ECHO,
+            TelnetOptionCode::Sga => SGA,
+            TelnetOptionCode::Mtts => MTTS,
+            TelnetOptionCode::TeloptEor => TELOPT_EOR,
+            TelnetOptionCode::TimingMark => TIMING_MARK,
+            TelnetOptionCode::Naws => NAWS,
+            TelnetOptionCode::Linemode => LINEMODE,
+            TelnetOptionCode::Environ => ENVIRON,
+            TelnetOptionCode::NewEnviron => NEW_ENVIRON,
+            TelnetOptionCode::Msdp => MSDP,
+            TelnetOptionCode::Mssp => MSSP,
+            TelnetOptionCode::Mccp2 => MCCP2,
+            TelnetOptionCode::Mccp3 => MCCP3,
+            TelnetOptionCode::Mxp => MXP,
+            TelnetOptionCode::Gmcp => GMCP,
+            TelnetOptionCode::Unknown(other) => other
+        }
+    }
+}
+
+impl TelnetOptionCode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Echo => "ECHO",
+            Self::Sga => "SGA",
+            Self::Mtts => "MTTS",
+            Self::TeloptEor => "EOR",
+            Self::TimingMark => "TIMING-MARK",
+            Self::Naws => "NAWS",
+            Self::Linemode => "LINEMODE",
+            Self::Environ => "ENVIRON",
+            Self::NewEnviron => "NEW-ENVIRON",
+            Self::Msdp => "MSDP",
+            Self::Mssp => "MSSP",
+            Self::Mccp2 => "MCCP2",
+            Self::Mccp3 => "MCCP3",
+            Self::Mxp => "MXP",
+            Self::Gmcp => "GMCP",
+            Self::Unknown(_) => "UNKNOWN"
+        }
+    }
+
+    // Case-insensitive lookup by name, for config files.
+    pub fn from_name(name: &str) -> Option<Self> {
+        KNOWN_OPTIONS.iter().find(|c| c.name().eq_ignore_ascii_case(name)).cloned()
+    }
+}
+
+impl std::fmt::Display for TelnetOptionCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(code) => write!(f, "UNKNOWN({})", code),
+            other => f.write_str(other.name())
+        }
+    }
+}
+
+// Name of a negotiation command byte, for logs.
+pub fn command_name(command: u8) -> &'static str {
+    match command {
+        WILL => "WILL",
+        WONT => "WONT",
+        DO => "DO",
+        DONT => "DONT",
+        SB => "SB",
+        SE => "SE",
+        GA => "GA",
+        NOP => "NOP",
+        EOR => "EOR",
+        IAC => "IAC",
+        _ => "?"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_code_round_trips_with_its_name() {
+        for code in 0..=255u8 {
+            assert_eq!(u8::from(TelnetOptionCode::from(code)), code);
+        }
+        let expected = [
+            (ECHO, "ECHO"), (SGA, "SGA"), (MTTS, "MTTS"), (TELOPT_EOR, "EOR"), (TIMING_MARK, "TIMING-MARK"),
+            (NAWS, "NAWS"), (LINEMODE, "LINEMODE"), (ENVIRON, "ENVIRON"), (NEW_ENVIRON, "NEW-ENVIRON"),
+            (MSDP, "MSDP"), (MSSP, "MSSP"), (MCCP2, "MCCP2"), (MCCP3, "MCCP3"), (MXP, "MXP"), (GMCP, "GMCP")
+        ];
+        assert_eq!(expected.len(), KNOWN_OPTIONS.len());
+        for (code, name) in expected.iter() {
+            let named = TelnetOptionCode::from(*code);
+            assert_eq!(named.name(), *name);
+            assert_eq!(TelnetOptionCode::from_name(&name.to_lowercase()), Some(named));
+        }
+        assert_eq!(TelnetOptionCode::from(250).to_string(), "UNKNOWN(250)");
+    }
+}
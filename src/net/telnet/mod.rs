@@ -5,13 +5,19 @@ use std::{
     io::Read,
 };
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub mod codes;
-use crate::net::{ConnectionComponent, ProtocolComponent, ProtocolCapabilities, ProtocolEvent};
+pub mod mxp;
+pub mod replay;
+use crate::net::NetError;
+use crate::net::telnet::codes::TelnetOptionCode;
+use crate::net::{ProtocolCapabilities, ProtocolEvent, oob_from_json, oob_to_json, apply_window_size};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use crate::mudstring::color::{ColorSystem};
+use crate::config::{LineEnding, OobPreference};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum TelnetMessage {
     Data(Vec<u8>),
     IAC(u8),
@@ -20,75 +26,225 @@ pub enum TelnetMessage {
 }
 
 impl TelnetMessage {
+    // The message as it goes on the wire, with any 255 in data or a subnegotiation doubled so
+    // it can't be taken for an IAC. from_bytes undoes it.
     pub fn to_vec(&self) -> Vec<u8> {
         match self {
             TelnetMessage::Data(v) => {
-                return v.clone()
-            },
-            TelnetMessage::IAC(code) => {
-                return vec![codes::IAC, *code]
-            },
-            TelnetMessage::Negotiate(code, op) => {
-                return vec![codes::IAC, *code, *op]
+                let mut arr = Vec::with_capacity(v.len());
+                escape_iac(v, &mut arr);
+                arr
             },
+            TelnetMessage::IAC(code) => vec![codes::IAC, *code],
+            TelnetMessage::Negotiate(code, op) => vec![codes::IAC, *code, *op],
             TelnetMessage::SubNegotiate(code, data) => {
                 let mut arr = Vec::with_capacity(5 + data.len());
                 arr.extend_from_slice([codes::IAC, codes::SB, *code].as_ref());
-                arr.extend(data);
+                escape_iac(data, &mut arr);
                 arr.extend_from_slice([codes::IAC, codes::SE].as_ref());
-                return arr
+                arr
             }
         }
     }
 
+    // Readable form with option and command names, for logs and traces.
+    pub fn describe(&self) -> String {
+        match self {
+            TelnetMessage::Data(data) => format!("DATA ({} bytes)", data.len()),
+            TelnetMessage::IAC(code) => format!("IAC {}", codes::command_name(*code)),
+            TelnetMessage::Negotiate(command, op) => format!("{} {}", codes::command_name(*command), TelnetOptionCode::from(*op)),
+            TelnetMessage::SubNegotiate(op, data) => format!("SB {} ({} bytes)", TelnetOptionCode::from(*op), data.len())
+        }
+    }
+
+    // Decodes one message from the front of src. Returns the message and how many bytes it
+    // used, always at least one and never more than src.len(), or None if src doesn't hold a
+    // whole message yet. Any byte sequence at all is safe to feed in.
     pub fn from_bytes(src: &[u8]) -> Option<(TelnetMessage, usize)> {
         if src.is_empty() {
             return None
         }
 
         if src[0] == codes::IAC {
-            match src[1] {
+            // A lone IAC at the end of the buffer; the rest hasn't arrived.
+            let command = *src.get(1)?;
+            match command {
                 codes::IAC => {
-                    let mut out = Vec::new();
-                    out.push(codes::IAC);
-                    return Some((TelnetMessage::Data(out), 2))
+                    Some((TelnetMessage::Data(vec![codes::IAC]), 2))
                 },
                 codes::WILL | codes::WONT | codes::DO | codes::DONT => {
-                    if src.len() > 2 {
-                        let answer = TelnetMessage::Negotiate(src[1], src[2]);
-                        return Some((answer, 3))
-                    } else {
-                        // not enough bytes yet.
-                        return None
-                    }
+                    // None means not enough bytes yet.
+                    src.get(2).map(|op| (TelnetMessage::Negotiate(command, *op), 3))
                 },
                 codes::SB => {
-                    if src.len() > 4 {
-                        if let Some(ipos) = src.as_ref().windows(2).position(|b| b[0] == codes::IAC && b[1] == codes::SE) {
-                            // Split off any available up to an IAC and stuff it in the sub data buffer.
-                            let answer = TelnetMessage::SubNegotiate(src[2], src[3..ipos].to_vec());
-                            return Some((answer, ipos+2))
-                        } else {
-                            return None
+                    let op = *src.get(2)?;
+                    // Look for IAC SE after the option byte. IAC IAC inside the data is an
+                    // escaped 255, not the start of the terminator.
+                    let mut data = Vec::new();
+                    let mut i = 3;
+                    while i < src.len() {
+                        if src[i] == codes::IAC {
+                            match src.get(i + 1) {
+                                Some(&codes::SE) => return Some((TelnetMessage::SubNegotiate(op, data), i + 2)),
+                                Some(&codes::IAC) => {
+                                    data.push(codes::IAC);
+                                    i += 2;
+                                    continue;
+                                },
+                                // Any other IAC in here is malformed; keep the byte and move on.
+                                Some(_) => {},
+                                None => return None
+                            }
                         }
-                    } else {
-                        // not enough bytes to be a full sequence.
-                        return None
+                        data.push(src[i]);
+                        i += 1;
                     }
+                    None
                 },
                 _ => {
                     // anything else that doesn't match the above is a simple IAC command.
-                    return Some((TelnetMessage::IAC(src[1]), 2))
+                    Some((TelnetMessage::IAC(command), 2))
                 }
             }
+        } else if let Some(ipos) = src.iter().position(|b| b == &codes::IAC) {
+            // Split off any available up to an IAC and stuff it in the sub data buffer.
+            Some((TelnetMessage::Data(src[..ipos].to_vec()), ipos))
         } else {
-            if let Some(ipos) = src.iter().position(|b| b == &codes::IAC) {
-                // Split off any available up to an IAC and stuff it in the sub data buffer.
-                return Some((TelnetMessage::Data(src[..ipos].to_vec()), ipos))
-            } else {
-                return Some((TelnetMessage::Data(src.to_vec()), src.len()))
+            Some((TelnetMessage::Data(src.to_vec()), src.len()))
+        }
+    }
+}
+
+fn escape_iac(data: &[u8], out: &mut Vec<u8>) {
+    for b in data {
+        if *b == codes::IAC {
+            out.push(codes::IAC);
+        }
+        out.push(*b);
+    }
+}
+
+// Splits an ENVIRON or NEW-ENVIRON IS/INFO payload into (name, value) pairs. The only framing
+// difference we care about is that ENVIRON has no USERVAR marker.
+pub fn parse_environ(data: &[u8], new_environ: bool) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    if data.is_empty() || !(data[0] == codes::ENV_IS || data[0] == codes::ENV_INFO) {
+        return out;
+    }
+
+    let mut name: Option<Vec<u8>> = None;
+    let mut value: Vec<u8> = Vec::new();
+    let mut in_value = false;
+    let mut iter = data[1..].iter();
+
+    while let Some(b) = iter.next() {
+        match *b {
+            codes::ENV_VAR => {},
+            codes::ENV_USERVAR if new_environ => {},
+            codes::ENV_VALUE => {
+                in_value = true;
+                continue;
+            },
+            codes::ENV_ESC => {
+                if let Some(escaped) = iter.next() {
+                    if in_value {
+                        value.push(*escaped);
+                    } else if let Some(n) = &mut name {
+                        n.push(*escaped);
+                    }
+                }
+                continue;
+            },
+            other => {
+                if in_value {
+                    value.push(other);
+                } else if let Some(n) = &mut name {
+                    n.push(other);
+                }
+                continue;
             }
         }
+        // A VAR or USERVAR marker starts the next variable.
+        if let Some(n) = name.take() {
+            out.push((String::from_utf8_lossy(&n).to_string(), String::from_utf8_lossy(&value).to_string()));
+        }
+        name = Some(Vec::new());
+        value.clear();
+        in_value = false;
+    }
+    if let Some(n) = name {
+        out.push((String::from_utf8_lossy(&n).to_string(), String::from_utf8_lossy(&value).to_string()));
+    }
+    out
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceDirection {
+    In,
+    Out
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub at: Instant,
+    pub direction: TraceDirection,
+    pub message: TelnetMessage
+}
+
+// The largest subnegotiation payload we'll gather before giving up on the connection.
+pub const MAX_SUBNEGOTIATION: usize = 262144;
+
+// How long a WILL/DO we sent may go unanswered before we stop waiting on it.
+pub const NEGOTIATION_GRACE: Duration = Duration::from_secs(5);
+
+// Don't let a chatty client grow a trace forever.
+pub const MAX_TRACE_ENTRIES: usize = 1000;
+
+// Invisible characters taken out of typed lines unless config says otherwise: zero-width
+// space and non-joiner, word joiner, soft hyphen and the BOM/zero-width no-break space. The
+// zero-width joiner stays, since emoji sequences are built with it.
+pub const DEFAULT_STRIP_CHARS: &[char] = &['\u{200B}', '\u{200C}', '\u{2060}', '\u{00AD}', '\u{FEFF}'];
+
+// A leading byte order mark and any of strip are removed, so a command with an invisible
+// prefix still matches.
+pub fn sanitize_input(line: &str, strip: &[char]) -> String {
+    line.trim_start_matches('\u{FEFF}').chars().filter(|c| !strip.contains(c)).collect()
+}
+
+// A record of every negotiation message in and out of a connection, for debugging clients.
+// Plain data is never recorded, so passwords don't end up in here.
+#[derive(Clone, Debug)]
+pub struct NegotiationTrace {
+    pub started: Instant,
+    pub now: Instant,
+    pub entries: Vec<TraceEntry>
+}
+
+impl NegotiationTrace {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            now,
+            entries: Default::default()
+        }
+    }
+
+    pub fn record(&mut self, direction: TraceDirection, message: TelnetMessage) {
+        if self.entries.len() < MAX_TRACE_ENTRIES {
+            self.entries.push(TraceEntry {at: self.now, direction, message});
+        }
+    }
+
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for entry in self.entries.iter() {
+            let dir = match entry.direction {
+                TraceDirection::In => "IN ",
+                TraceDirection::Out => "OUT"
+            };
+            out += format!("[+{}ms] {} {}\n", entry.at.duration_since(self.started).as_millis(), dir, entry.message.describe()).as_str();
+        }
+        out
     }
 }
 
@@ -96,7 +252,9 @@ impl TelnetMessage {
 pub struct TelnetOptionPerspective {
     pub enabled: bool,
     // Negotiating is true if WE have sent a request.
-    pub negotiating: bool
+    pub negotiating: bool,
+    // When that request went out, so one the client never answers can be given up on.
+    pub requested_at: Option<Instant>
 }
 
 #[derive(Default, Clone, Debug)]
@@ -137,13 +295,39 @@ pub struct TelnetProtocol {
     pub telnet_options: Arc<HashMap<u8, TelnetOption>>,
     pub handshakes_left: TelnetHandshakes,
     pub app_buffer: BytesMut,
-    pub mtts_last: Option<String>
-    
+    pub mtts_last: Option<String>,
+    pub trace: Option<NegotiationTrace>,
+    pub line_ending: LineEnding,
+    // Removed from typed lines before they go anywhere. See DEFAULT_STRIP_CHARS.
+    pub strip_chars: Arc<Vec<char>>,
+    // While set, typed lines are never echoed back, whatever ECHO says.
+    pub password_mode: bool,
+    // Set until login, so echoing "connect name=password" doesn't show the password.
+    pub mask_credentials: bool,
+    // A subnegotiation we've seen the start of but not the end: its option and the payload
+    // so far. Filled a read at a time so a big payload is only scanned once.
+    pub sub_pending: Option<(u8, Vec<u8>)>,
+    // Engine time as of the last set_time.
+    pub now: Instant,
+    // When our last DO TIMING-MARK went out, and the round trip the last answer measured.
+    pub timing_mark_sent: Option<Instant>,
+    pub round_trip: Option<Duration>,
+    // When our last GMCP Core.Ping went out, and whether the client has answered it yet.
+    // An answer sets round_trip too.
+    pub gmcp_ping_sent: Option<Instant>,
+    pub gmcp_ping_outstanding: bool,
+    // Protocol mistakes seen since the owner last took them, for suspicion scoring.
+    pub violations: Vec<String>,
+    // See NetConfig.freeze_capabilities. frozen is set once the connection goes active.
+    pub freeze_when_active: bool,
+    pub frozen: bool,
+    // Used when the client negotiated both GMCP and MSDP. See ProtocolCapabilities::oob_channel.
+    pub oob_preference: OobPreference
 }
 
 impl TelnetProtocol {
 
-    pub fn new(telnet_options: Arc<HashMap<u8, TelnetOption>>) -> Self {
+    pub fn new(telnet_options: Arc<HashMap<u8, TelnetOption>>, now: Instant) -> Self {
         let mut op_state: HashMap<u8, TelnetOptionState> = HashMap::with_capacity(telnet_options.len());
 
         for (key, val) in telnet_options.iter() {
@@ -158,20 +342,207 @@ impl TelnetProtocol {
             handshakes_left,
             app_buffer: Default::default(),
             mtts_last: None,
+            trace: None,
+            line_ending: Default::default(),
+            strip_chars: Arc::new(DEFAULT_STRIP_CHARS.to_vec()),
+            password_mode: false,
+            mask_credentials: false,
+            sub_pending: None,
+            now,
+            timing_mark_sent: None,
+            round_trip: None,
+            gmcp_ping_sent: None,
+            gmcp_ping_outstanding: false,
+            violations: Vec::new(),
+            freeze_when_active: false,
+            frozen: false,
+            oob_preference: Default::default()
+        }
+    }
+
+    pub fn enable_trace(&mut self, now: Instant) {
+        self.trace = Some(NegotiationTrace::new(now));
+    }
+
+    // Keeps trace timestamps in step with the engine clock.
+    pub fn set_time(&mut self, now: Instant) {
+        self.now = now;
+        if let Some(trace) = &mut self.trace {
+            trace.now = now;
+        }
+    }
+
+    fn trace(&mut self, direction: TraceDirection, message: TelnetMessage) {
+        if let Some(trace) = &mut self.trace {
+            trace.record(direction, message);
+        }
+    }
+
+    // Takes the next complete message off the front of buf. Subnegotiations are gathered
+    // incrementally into sub_pending, everything else waits in buf until it's whole.
+    pub fn next_message(&mut self, buf: &mut BytesMut) -> Result<Option<TelnetMessage>, NetError> {
+        if self.sub_pending.is_none() && buf.len() >= 3 && buf[0] == codes::IAC && buf[1] == codes::SB {
+            self.sub_pending = Some((buf[2], Vec::new()));
+            buf.advance(3);
+        }
+
+        if let Some((op, data)) = &mut self.sub_pending {
+            let mut i = 0;
+            let mut done = false;
+            while i < buf.len() {
+                if buf[i] != codes::IAC {
+                    data.push(buf[i]);
+                    i += 1;
+                    continue;
+                }
+                // An IAC at the very end could be the start of IAC SE; wait for the next byte.
+                if i + 1 >= buf.len() {
+                    break;
+                }
+                match buf[i + 1] {
+                    codes::SE => {
+                        i += 2;
+                        done = true;
+                        break;
+                    },
+                    codes::IAC => data.push(codes::IAC),
+                    other => data.extend_from_slice(&[codes::IAC, other])
+                }
+                i += 2;
+            }
+            buf.advance(i);
+
+            if done {
+                let op = *op;
+                let data = std::mem::take(data);
+                self.sub_pending = None;
+                return Ok(Some(TelnetMessage::SubNegotiate(op, data)));
+            }
+            if data.len() > MAX_SUBNEGOTIATION {
+                self.sub_pending = None;
+                return Err(NetError::BufferOverflow(MAX_SUBNEGOTIATION));
+            }
+            return Ok(None);
+        }
+
+        match TelnetMessage::from_bytes(buf.as_ref()) {
+            Some((msg, len)) => {
+                buf.advance(len);
+                Ok(Some(msg))
+            },
+            None => Ok(None)
+        }
+    }
+
+    // Gives up on any of our requests the client has sat on for longer than grace, so a
+    // silent client can't hold an option (or the Active transition) hostage.
+    pub fn expire_negotiations(&mut self, now: Instant, grace: Duration) {
+        let mut expired = Vec::new();
+        for (op, state) in self.op_state.iter_mut() {
+            for (perspective, remote) in [(&mut state.local, false), (&mut state.remote, true)] {
+                if !perspective.negotiating {
+                    continue;
+                }
+                match perspective.requested_at {
+                    Some(at) if now.duration_since(at) <= grace => {},
+                    _ => {
+                        perspective.negotiating = false;
+                        perspective.requested_at = None;
+                        expired.push((*op, remote));
+                    }
+                }
+            }
+        }
+        for (op, remote) in expired {
+            if remote {
+                self.handshakes_left.remote.remove(&op);
+            } else {
+                self.handshakes_left.local.remove(&op);
+            }
         }
     }
 
+    // Where every option we know about stands with this client, ordered by code.
+    pub fn option_states(&self) -> Vec<(u8, TelnetOptionState)> {
+        let mut out: Vec<(u8, TelnetOptionState)> = self.op_state.iter().map(|(k, v)| (*k, v.clone())).collect();
+        out.sort_by_key(|(k, _)| *k);
+        out
+    }
+
+    // The flags this connection was set up with, ordered by code.
+    pub fn configured_options(&self) -> Vec<(u8, TelnetOption)> {
+        let mut out: Vec<(u8, TelnetOption)> = self.telnet_options.iter().map(|(k, v)| (*k, v.clone())).collect();
+        out.sort_by_key(|(k, _)| *k);
+        out
+    }
+
+    pub fn local_enabled(&self, op: u8) -> bool {
+        self.op_state.get(&op).map(|s| s.local.enabled).unwrap_or(false)
+    }
+
+    // Asks to turn one of our own options on or off, unless it's already that way or a
+    // request is already out.
+    pub fn request_local(&mut self, op: u8, enable: bool, writer: &mut impl Write) {
+        let allowed = self.telnet_options.get(&op).map(|o| o.allow_local).unwrap_or(false);
+        let state = match self.op_state.get_mut(&op) {
+            Some(s) if allowed => s,
+            _ => return
+        };
+        if state.local.enabled == enable || state.local.negotiating {
+            return;
+        }
+        state.local.negotiating = true;
+        state.local.requested_at = Some(self.now);
+        let command = if enable { codes::WILL } else { codes::WONT };
+        if !enable {
+            // Nothing to wait for; WONT can't be refused.
+            state.local.enabled = false;
+            state.local.negotiating = false;
+            state.local.requested_at = None;
+        }
+        self.trace(TraceDirection::Out, TelnetMessage::Negotiate(command, op));
+        self.send_data(writer, [codes::IAC, command, op]);
+    }
+
+    // Hides typed input, e.g. for a password prompt. Claiming ECHO makes the client stop
+    // echoing locally, and we don't echo while this is on.
+    pub fn set_password_mode(&mut self, on: bool, writer: &mut impl Write) {
+        self.password_mode = on;
+        let forced = self.telnet_options.get(&codes::ECHO).map(|o| o.start_local).unwrap_or(false);
+        if !forced {
+            self.request_local(codes::ECHO, on, writer);
+        }
+    }
+
+    // Every line break inside data, however it was written, becomes the configured one, so
+    // multi-line text like help listings ends its lines the same way throughout.
+    fn normalize_newlines(&self, data: &str) -> String {
+        if !data.contains('\n') {
+            return data.to_string();
+        }
+        data.replace("\r\n", "\n").replace('\n', self.line_ending.as_str())
+    }
+
     pub fn send_text(&self, mut writer: &mut impl Write, data: String) {
         // TODO: Escape IAC, handle SGA
-        self.send_data(writer, data.as_bytes());
+        self.send_data(writer, self.normalize_newlines(&data).as_bytes());
     }
 
     pub fn send_prompt(&self, mut writer: &mut impl Write, data: String) {
-
+        // TODO: Escape IAC, handle SGA
+        self.send_data(writer, self.normalize_newlines(&data).as_bytes());
+        // A client that never said DO to the EOR option would show the command as garbage.
+        if self.local_enabled(codes::TELOPT_EOR) {
+            self.send_data(writer, [codes::IAC, codes::EOR]);
+        }
     }
 
     pub fn send_line(&self, mut writer: &mut impl Write, data: String) {
         // TODO: Escape IAC, handle SGA
+        // Whatever terminator the line came with is swapped for the configured one.
+        let line = data.strip_suffix("\r\n").or_else(|| data.strip_suffix('\n')).unwrap_or(&data);
+        let mut data = self.normalize_newlines(line);
+        data.push_str(self.line_ending.as_str());
         self.send_data(writer, data.as_bytes());
     }
 
@@ -181,34 +552,99 @@ impl TelnetProtocol {
     }
 
     pub fn send_sub(&mut self, op: u8, data: impl AsRef<[u8]>, mut writer: &mut impl Write) {
-        let mut out = BytesMut::with_capacity(5 + data.as_ref().len());
-        out.extend_from_slice(&[codes::IAC, codes::SB, op]);
-        out.extend_from_slice(data.as_ref());
-        out.extend_from_slice(&[codes::IAC, codes::SE]);
+        let msg = TelnetMessage::SubNegotiate(op, data.as_ref().to_vec());
+        let out = msg.to_vec();
+        self.trace(TraceDirection::Out, msg);
         self.send_data(writer, out);
     }
 
+    // GMCP payloads are "Package.Name <json>". Keyword arguments become an object, a single
+    // positional argument a string, and several an array.
+    pub fn send_gmcp(&mut self, writer: &mut impl Write, package: &str, args: &[String], kwargs: &HashMap<String, String>) {
+        let payload = oob_to_json(args, kwargs).map(|v| v.to_string());
+        let data = match payload {
+            Some(p) => format!("{} {}", package, p),
+            None => package.to_string()
+        };
+        self.send_sub(codes::GMCP, data.as_bytes(), writer);
+    }
+
+    // MSSP_VAR name MSSP_VAL value, for every variable.
+    pub fn send_mssp(&mut self, writer: &mut impl Write, variables: &[(String, String)]) {
+        let mut out = Vec::new();
+        for (name, value) in variables {
+            out.push(codes::MSSP_VAR);
+            out.extend_from_slice(name.as_bytes());
+            out.push(codes::MSSP_VAL);
+            out.extend_from_slice(value.as_bytes());
+        }
+        self.send_sub(codes::MSSP, out, writer);
+    }
+
+    pub fn send_msdp(&mut self, writer: &mut impl Write, package: &str, args: &[String], kwargs: &HashMap<String, String>) {
+        let mut out = Vec::new();
+        out.push(codes::MSDP_VAR);
+        out.extend_from_slice(package.as_bytes());
+        out.push(codes::MSDP_VAL);
+        if !kwargs.is_empty() {
+            out.push(codes::MSDP_TABLE_OPEN);
+            for (k, v) in kwargs.iter() {
+                out.push(codes::MSDP_VAR);
+                out.extend_from_slice(k.as_bytes());
+                out.push(codes::MSDP_VAL);
+                out.extend_from_slice(v.as_bytes());
+            }
+            out.push(codes::MSDP_TABLE_CLOSE);
+        } else if args.len() == 1 {
+            out.extend_from_slice(args[0].as_bytes());
+        } else if !args.is_empty() {
+            out.push(codes::MSDP_ARRAY_OPEN);
+            for v in args.iter() {
+                out.push(codes::MSDP_VAL);
+                out.extend_from_slice(v.as_bytes());
+            }
+            out.push(codes::MSDP_ARRAY_CLOSE);
+        }
+        self.send_sub(codes::MSDP, out, writer);
+    }
+
     pub fn start(&mut self, mut writer: &mut impl Write) {
         let mut out = BytesMut::new();
 
+        let mut sent = Vec::new();
         for (k, v) in self.telnet_options.iter() {
+            let state = self.op_state.entry(*k).or_default();
             if v.start_local {
                 out.extend_from_slice(&[codes::IAC, codes::WILL, *k]);
+                sent.push(TelnetMessage::Negotiate(codes::WILL, *k));
+                state.local.negotiating = true;
+                state.local.requested_at = Some(self.now);
+                self.handshakes_left.local.insert(*k);
             }
             if v.start_remote {
                 out.extend_from_slice(&[codes::IAC, codes::DO, *k]);
+                sent.push(TelnetMessage::Negotiate(codes::DO, *k));
+                state.remote.negotiating = true;
+                state.remote.requested_at = Some(self.now);
+                self.handshakes_left.remote.insert(*k);
             }
         }
+        for msg in sent {
+            self.trace(TraceDirection::Out, msg);
+        }
         self.send_data(writer, out);
     }
 
     pub fn process_message(&mut self, msg: TelnetMessage, mut out: &mut VecDeque<ProtocolEvent>,
                            mut writer: &mut impl Write, mut capabilities: &mut ProtocolCapabilities) {
+        if !matches!(msg, TelnetMessage::Data(_)) {
+            self.trace(TraceDirection::In, msg.clone());
+        }
         match msg {
             TelnetMessage::SubNegotiate(op, data) => self.receive_sub(op, data, out, writer, capabilities),
             TelnetMessage::Negotiate(comm, op) => self.receive_negotiate(comm, op, out, writer, capabilities),
             TelnetMessage::IAC(byte) => self.receive_command(byte, out, writer, capabilities),
-            TelnetMessage::Data(data) => self.receive_data(data, out)
+            TelnetMessage::Data(data) => self.receive_data(data, out, writer, capabilities)
         }
     }
 
@@ -222,6 +658,10 @@ impl TelnetProtocol {
             // All other sub-data is ignored.
             return;
         }
+        if self.frozen && matches!(op, codes::MTTS | codes::NEW_ENVIRON | codes::ENVIRON) {
+            println!("Ignoring {} from {}: its capabilities are frozen", TelnetOptionCode::from(op), capabilities.client_name);
+            return;
+        }
 
         match op {
             codes::NAWS => {
@@ -229,11 +669,81 @@ impl TelnetProtocol {
             },
             codes::MTTS => {
                 let _ = self.receive_mtts(data, out, writer, capabilities);
-            }
+            },
+            codes::GMCP => {
+                self.receive_gmcp(data, out, writer);
+            },
+            // Crawlers ask again with IAC SB MSSP SEND IAC SE. What they put in there varies,
+            // so any subnegotiation counts as a request.
+            codes::MSSP => {
+                out.push_back(ProtocolEvent::RequestMSSP);
+            },
+            codes::NEW_ENVIRON => {
+                self.receive_environ(data, true, capabilities);
+            },
+            // NEW-ENVIRON wins if the client speaks both.
+            codes::ENVIRON if !self.remote_enabled(codes::NEW_ENVIRON) => {
+                self.receive_environ(data, false, capabilities);
+            },
             _ => {}
         }
     }
 
+    pub fn remote_enabled(&self, op: u8) -> bool {
+        self.op_state.get(&op).map(|s| s.remote.enabled).unwrap_or(false)
+    }
+
+    // "Package.Name <json>" from the client becomes an OOB event. An object fills the keyword
+    // arguments, an array the positional ones, and anything else is a single argument.
+    // Core.Ping and Core.KeepAlive are answered here and never reach the game.
+    fn receive_gmcp(&mut self, data: Vec<u8>, out: &mut VecDeque<ProtocolEvent>, writer: &mut impl Write) {
+        let text = String::from_utf8_lossy(&data).to_string();
+        let mut split = text.splitn(2, ' ');
+        let package = split.next().unwrap_or("").trim().to_string();
+        if package.is_empty() {
+            return;
+        }
+        let body = split.next().map(|b| b.trim()).filter(|b| !b.is_empty());
+
+        if package.eq_ignore_ascii_case("Core.Ping") {
+            if self.gmcp_ping_outstanding {
+                // The answer to send_gmcp_ping.
+                self.gmcp_ping_outstanding = false;
+                if let Some(sent) = self.gmcp_ping_sent {
+                    self.round_trip = Some(self.now.saturating_duration_since(sent));
+                }
+            } else {
+                // The client measuring us: pong with whatever it sent, untouched.
+                let reply = match body {
+                    Some(b) => format!("Core.Ping {}", b),
+                    None => "Core.Ping".to_string()
+                };
+                self.send_sub(codes::GMCP, reply.as_bytes(), writer);
+            }
+            return;
+        }
+        if package.eq_ignore_ascii_case("Core.KeepAlive") {
+            return;
+        }
+
+        // Plenty of clients send bare text; take it as-is.
+        let value = body.map(|b| serde_json::from_str(b).unwrap_or_else(|_| serde_json::Value::String(b.to_string())));
+        let (args, kwargs) = oob_from_json(value);
+        out.push_back(ProtocolEvent::OOB(package, args, kwargs));
+    }
+
+    fn receive_environ(&mut self, data: Vec<u8>, new_environ: bool, capabilities: &mut ProtocolCapabilities) {
+        for (name, value) in parse_environ(&data, new_environ) {
+            match name.as_str() {
+                "CLIENT_NAME" => capabilities.client_name = value.to_uppercase(),
+                "CLIENT_VERSION" => capabilities.client_version = value.clone(),
+                "CHARSET" if value.eq_ignore_ascii_case("UTF-8") => capabilities.utf8 = true,
+                _ => {}
+            }
+            capabilities.environ.insert(name, value);
+        }
+    }
+
     fn receive_mtts(&mut self, data: Vec<u8>, mut out: &mut VecDeque<ProtocolEvent>, mut writer: &mut impl Write, mut capabilities: &mut ProtocolCapabilities) {
         let mut new_data = BytesMut::with_capacity(data.len());
         new_data.extend(data);
@@ -247,6 +757,7 @@ impl TelnetProtocol {
         }
 
         if new_data[0] != 0 {
+            self.violations.push("MTTS reply without IS".to_string());
             return;
         }
 
@@ -264,18 +775,18 @@ impl TelnetProtocol {
             }
 
             let hs1: u8 = 0;
-            let hs2: u8 = 0;
-            let hs3: u8 = 0;
+            let hs2: u8 = 1;
+            let hs3: u8 = 2;
             self.mtts_last = Some(upper.clone());
             if self.handshakes_left.mtts.contains(&hs1) {
                 self.receive_mtts_0(upper, out, writer, capabilities);
                 self.handshakes_left.mtts.remove(&hs1);
-                self.send_sub(codes::MTTS, &[1], writer);
+                self.send_sub(codes::MTTS, [1], writer);
                 return;
             } else if self.handshakes_left.mtts.contains(&hs2) {
                 self.receive_mtts_1(upper, out, writer, capabilities);
                 self.handshakes_left.mtts.remove(&hs2);
-                self.send_sub(codes::MTTS, &[1], writer);
+                self.send_sub(codes::MTTS, [1], writer);
                 return;
             } else if self.handshakes_left.mtts.contains(&hs3) {
                 self.receive_mtts_2(upper, out, writer, capabilities);
@@ -289,12 +800,10 @@ impl TelnetProtocol {
     fn receive_mtts_0(&mut self, data: String, mut out: &mut VecDeque<ProtocolEvent>, mut writer: &mut impl Write, mut capabilities: &mut ProtocolCapabilities) {
         // The first mtts receives the name of the client.
         // version might also be in here as a second word.
-        if data.contains(" ") {
-            let results: Vec<&str> = data.splitn(1, " ").collect();
-            capabilities.client_name = String::from(results[0]);
-            capabilities.client_version = String::from(results[1]);
-        } else {
-            capabilities.client_name = data;
+        let results: Vec<&str> = data.splitn(2, ' ').collect();
+        capabilities.client_name = String::from(results[0]);
+        if let Some(version) = results.get(1) {
+            capabilities.client_version = version.trim().to_string();
         }
 
         // Now that the name and version (may be UNKNOWN) are set... we can deduce capabilities.
@@ -390,25 +899,61 @@ impl TelnetProtocol {
     }
     
     fn receive_naws(&mut self, mut data: Vec<u8>, mut out: &mut VecDeque<ProtocolEvent>, mut writer: &mut impl Write, mut capabilities: &mut ProtocolCapabilities) {
+        if data.len() != 4 {
+            self.violations.push(format!("NAWS of {} bytes", data.len()));
+        }
         let mut new_data = BytesMut::with_capacity(data.len());
         new_data.extend(data);
         if new_data.len() >= 4 {
-            capabilities.width = new_data.get_u16();
-            capabilities.height = new_data.get_u16();
+            let width = new_data.get_u16();
+            let height = new_data.get_u16();
+            apply_window_size(capabilities, out, width, height);
         }
     }
     
-    fn receive_data(&mut self, data: Vec<u8>, mut out: &mut VecDeque<ProtocolEvent>) {
+    fn receive_data(&mut self, data: Vec<u8>, mut out: &mut VecDeque<ProtocolEvent>, writer: &mut impl Write,
+                    capabilities: &mut ProtocolCapabilities) {
         self.app_buffer.extend(data);
         while let Some(ipos) = self.app_buffer.as_ref().iter().position(|b| b == &codes::LF) {
             let cmd = self.app_buffer.split_to(ipos);
             if let Ok(s) = String::from_utf8(cmd.to_vec()) {
-                out.push_back(ProtocolEvent::Line(s.trim().to_string()));
+                let s = sanitize_input(&s, &self.strip_chars);
+                // With ECHO on the client shows nothing as it's typed, so it's up to us.
+                if self.local_enabled(codes::ECHO) && !self.password_mode {
+                    let shown = s.trim_end();
+                    let shown = match shown.find('=') {
+                        Some(pos) if self.mask_credentials => format!("{}********", &shown[..=pos]),
+                        _ => shown.to_string()
+                    };
+                    self.send_line(writer, shown);
+                }
+                // The reply to our <VERSION> request arrives as a line of its own.
+                match mxp::parse_version(&s) {
+                    Some(attrs) if capabilities.mxp => self.receive_mxp_version(attrs, capabilities),
+                    _ => out.push_back(ProtocolEvent::Line(s.trim().to_string()))
+                }
             }
             self.app_buffer.advance(1);
         }
     }
 
+    fn receive_mxp_version(&mut self, attrs: HashMap<String, String>, capabilities: &mut ProtocolCapabilities) {
+        if let Some(version) = attrs.get("MXP") {
+            capabilities.mxp_version = Some(version.clone());
+        }
+        // MTTS or NEW-ENVIRON usually got here first; only fill in what they didn't.
+        if capabilities.client_name == "UNKNOWN" {
+            if let Some(client) = attrs.get("CLIENT") {
+                capabilities.client_name = client.to_uppercase();
+            }
+        }
+        if capabilities.client_version == "UNKNOWN" {
+            if let Some(version) = attrs.get("VERSION") {
+                capabilities.client_version = version.clone();
+            }
+        }
+    }
+
     fn receive_negotiate(&mut self, command: u8, op: u8, mut out: &mut VecDeque<ProtocolEvent>, mut writer: &mut impl Write, mut capabilities: &mut ProtocolCapabilities) {
         let mut handshake: u8 = 0;
         let mut enable_local = false;
@@ -419,6 +964,11 @@ impl TelnetProtocol {
         let mut handshake_local: u8 = 0;
         let mut respond: u8 = 0;
 
+        if op == codes::TIMING_MARK {
+            self.receive_timing_mark(command, writer);
+            return;
+        }
+
         if let Some(state) = self.op_state.get_mut(&op) {
             // We DO have a handler for this option... that means we support it!
 
@@ -429,6 +979,7 @@ impl TelnetProtocol {
                     if !state.remote.enabled {
                         if state.remote.negotiating {
                             state.remote.negotiating = false;
+                            state.remote.requested_at = None;
                         }
                         else {
                             respond = codes::DO;
@@ -447,6 +998,7 @@ impl TelnetProtocol {
                         handshake_remote = op;
                     }
                     state.remote.negotiating = false;
+                    state.remote.requested_at = None;
                     if state.remote.enabled {
                         disable_remote = true;
                         state.remote.enabled = false;
@@ -458,6 +1010,7 @@ impl TelnetProtocol {
                     if !state.local.enabled {
                         if state.local.negotiating {
                             state.local.negotiating = false;
+                            state.local.requested_at = None;
                         }
                         else {
                             respond = codes::WILL;
@@ -476,6 +1029,7 @@ impl TelnetProtocol {
                         handshake_local = op;
                     }
                     state.local.negotiating = false;
+                    state.local.requested_at = None;
                     if state.local.enabled {
                         disable_local = true;
                         state.local.enabled = false
@@ -495,13 +1049,14 @@ impl TelnetProtocol {
         }
 
         if respond > 0 {
+            self.trace(TraceDirection::Out, TelnetMessage::Negotiate(respond, op));
             let _ = self.send_data(writer,&[codes::IAC, respond, op]);
         }
         if handshake_local > 0 {
-
+            self.handshakes_left.local.remove(&handshake_local);
         }
         if handshake_remote > 0 {
-
+            self.handshakes_left.remote.remove(&handshake_remote);
         }
         if enable_local {
             self.enable_local(op, out, writer, capabilities);
@@ -517,6 +1072,38 @@ impl TelnetProtocol {
         }
     }
 
+    fn receive_timing_mark(&mut self, command: u8, writer: &mut impl Write) {
+        match command {
+            // Everything the client sent before this has been handled by the time we get
+            // here, and our replies are already queued ahead of the mark.
+            codes::DO => {
+                self.trace(TraceDirection::Out, TelnetMessage::Negotiate(codes::WILL, codes::TIMING_MARK));
+                self.send_data(writer, [codes::IAC, codes::WILL, codes::TIMING_MARK]);
+            },
+            // The answer to send_timing_mark. WONT still means the client got that far.
+            codes::WILL | codes::WONT => {
+                if let Some(sent) = self.timing_mark_sent.take() {
+                    self.round_trip = Some(self.now.saturating_duration_since(sent));
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // Asks the client for a timing mark. Its reply sets round_trip.
+    pub fn send_timing_mark(&mut self, writer: &mut impl Write) {
+        self.timing_mark_sent = Some(self.now);
+        self.trace(TraceDirection::Out, TelnetMessage::Negotiate(codes::DO, codes::TIMING_MARK));
+        self.send_data(writer, [codes::IAC, codes::DO, codes::TIMING_MARK]);
+    }
+
+    // Sends a GMCP Core.Ping. The client's Core.Ping back sets round_trip.
+    pub fn send_gmcp_ping(&mut self, writer: &mut impl Write) {
+        self.gmcp_ping_sent = Some(self.now);
+        self.gmcp_ping_outstanding = true;
+        self.send_sub(codes::GMCP, b"Core.Ping", writer);
+    }
+
     fn enable_local(&mut self, op: u8, mut out: &mut VecDeque<ProtocolEvent>, mut writer: &mut impl Write,
                     mut capabilities: &mut ProtocolCapabilities) {
         match op {
@@ -525,8 +1112,17 @@ impl TelnetProtocol {
             },
             codes::MXP => {
                 capabilities.mxp = true;
-                self.send_sub(codes::MXP, &[], writer);
-            }
+                self.send_sub(codes::MXP, [], writer);
+                self.send_data(writer, mxp::handshake());
+            },
+            codes::GMCP => capabilities.gmcp = true,
+            codes::MSDP => capabilities.msdp = true,
+            // The client said DO MSSP, so it wants the data now. Whether we offer it on
+            // connect at all is the option's start_local.
+            codes::MSSP => {
+                capabilities.mssp = true;
+                out.push_back(ProtocolEvent::RequestMSSP);
+            },
             _ => {
 
             }
@@ -541,9 +1137,15 @@ impl TelnetProtocol {
                 self.handshakes_left.mtts.insert(0);
                 self.handshakes_left.mtts.insert(1);
                 self.handshakes_left.mtts.insert(2);
-                self.send_sub(codes::MTTS, &[1], writer);
+                self.send_sub(codes::MTTS, [1], writer);
             },
             codes::LINEMODE => capabilities.linemode = true,
+            codes::NEW_ENVIRON => {
+                self.send_sub(codes::NEW_ENVIRON, [codes::ENV_SEND], writer);
+            },
+            codes::ENVIRON if !self.remote_enabled(codes::NEW_ENVIRON) => {
+                self.send_sub(codes::ENVIRON, [codes::ENV_SEND], writer);
+            },
             _ => {
                 // Whatever this option is.. well, whatever.
             }
@@ -577,10 +1179,402 @@ impl TelnetProtocol {
             },
             codes::MXP => {
                 capabilities.mxp = false;
-            }
+            },
+            codes::GMCP => capabilities.gmcp = false,
+            codes::MSDP => capabilities.msdp = false,
             _ => {
 
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::resources::TelnetOptions;
+    use crate::config::TelnetOptionConfig;
+    use crate::net::ProtocolCapabilities;
+
+    fn feed(telnet: &mut TelnetProtocol, bytes: &[u8], caps: &mut ProtocolCapabilities) -> Vec<u8> {
+        let mut pending = BytesMut::from(bytes);
+        let mut events = VecDeque::new();
+        let mut out = Vec::new();
+        while let Some(msg) = telnet.next_message(&mut pending).unwrap() {
+            telnet.process_message(msg, &mut events, &mut out, caps);
+        }
+        out
+    }
+
+    #[test]
+    fn naws_negotiation_is_traced_in_order() {
+        let start = Instant::now();
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, start);
+        telnet.enable_trace(start);
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.start(&mut Vec::new());
+        feed(&mut telnet, &[codes::IAC, codes::WILL, codes::NAWS], &mut caps);
+        feed(&mut telnet, &[codes::IAC, codes::SB, codes::NAWS, 0, 100, 0, 40, codes::IAC, codes::SE], &mut caps);
+
+        let naws: Vec<(TraceDirection, TelnetMessage)> = telnet.trace.as_ref().unwrap().entries.iter()
+            .filter(|e| match &e.message {
+                TelnetMessage::Negotiate(_, op) | TelnetMessage::SubNegotiate(op, _) => *op == codes::NAWS,
+                _ => false
+            })
+            .map(|e| (e.direction, e.message.clone()))
+            .collect();
+        assert_eq!(naws, vec![
+            (TraceDirection::Out, TelnetMessage::Negotiate(codes::DO, codes::NAWS)),
+            (TraceDirection::In, TelnetMessage::Negotiate(codes::WILL, codes::NAWS)),
+            (TraceDirection::In, TelnetMessage::SubNegotiate(codes::NAWS, vec![0, 100, 0, 40]))
+        ]);
+        assert_eq!((caps.width, caps.height), (100, 40));
+    }
+
+    #[test]
+    fn nothing_is_traced_unless_enabled() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        telnet.start(&mut Vec::new());
+        feed(&mut telnet, &[codes::IAC, codes::WILL, codes::NAWS], &mut ProtocolCapabilities::telnet());
+        assert!(telnet.trace.is_none());
+    }
+
+    fn environ_is(op: u8, vars: &[(&str, &str)]) -> Vec<u8> {
+        let mut out = vec![codes::IAC, codes::SB, op, codes::ENV_IS];
+        for (name, value) in vars {
+            out.push(codes::ENV_VAR);
+            out.extend_from_slice(name.as_bytes());
+            out.push(codes::ENV_VALUE);
+            out.extend_from_slice(value.as_bytes());
+        }
+        out.extend_from_slice(&[codes::IAC, codes::SE]);
+        out
+    }
+
+    #[test]
+    fn environ_is_fills_capability_variables() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.start(&mut Vec::new());
+        let answer = feed(&mut telnet, &[codes::IAC, codes::WILL, codes::ENVIRON], &mut caps);
+        assert!(answer.ends_with(&[codes::IAC, codes::SB, codes::ENVIRON, codes::ENV_SEND, codes::IAC, codes::SE]));
+
+        feed(&mut telnet, &environ_is(codes::ENVIRON, &[("USER", "bob"), ("CLIENT_NAME", "oldterm")]), &mut caps);
+        assert_eq!(caps.environ.get("USER").map(|s| s.as_str()), Some("bob"));
+        assert_eq!(caps.client_name, "OLDTERM");
+    }
+
+    #[test]
+    fn new_environ_is_preferred_over_environ() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.start(&mut Vec::new());
+        feed(&mut telnet, &[codes::IAC, codes::WILL, codes::NEW_ENVIRON, codes::IAC, codes::WILL, codes::ENVIRON], &mut caps);
+        feed(&mut telnet, &environ_is(codes::NEW_ENVIRON, &[("USER", "new")]), &mut caps);
+        feed(&mut telnet, &environ_is(codes::ENVIRON, &[("USER", "old")]), &mut caps);
+        assert_eq!(caps.environ.get("USER").map(|s| s.as_str()), Some("new"));
+    }
+
+    #[test]
+    fn lines_end_with_the_configured_terminator() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut out = Vec::new();
+        telnet.send_line(&mut out, "hello".to_string());
+        assert_eq!(out, b"hello\r\n");
+
+        telnet.line_ending = LineEnding::LF;
+        let mut out = Vec::new();
+        telnet.send_line(&mut out, "hello\r\n".to_string());
+        telnet.send_text(&mut out, "a\r\nb\n".to_string());
+        assert_eq!(out, b"hello\na\nb\n");
+    }
+
+    #[test]
+    fn mxp_handshake_requests_and_records_the_version() {
+        let mut changes = HashMap::new();
+        changes.insert("MXP".to_string(), TelnetOptionConfig {allow_local: Some(true), ..Default::default()});
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().with_changes(&changes).0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.start(&mut Vec::new());
+        let answer = feed(&mut telnet, &[codes::IAC, codes::DO, codes::MXP], &mut caps);
+        assert!(caps.mxp);
+        assert!(answer.windows(5).any(|w| w == [codes::IAC, codes::SB, codes::MXP, codes::IAC, codes::SE]));
+        let answer = String::from_utf8_lossy(&answer);
+        assert!(answer.contains("<VERSION>"));
+        assert!(answer.contains("<!ELEMENT RName"));
+
+        feed(&mut telnet, b"\x1b[1z<VERSION MXP=1.0 CLIENT=zMUD VERSION=6.16>\r\n", &mut caps);
+        assert_eq!(caps.mxp_version.as_deref(), Some("1.0"));
+        assert_eq!(caps.client_name, "ZMUD");
+        assert_eq!(caps.client_version, "6.16");
+    }
+
+    #[test]
+    fn typed_lines_are_echoed_except_passwords() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.start(&mut Vec::new());
+        feed(&mut telnet, &[codes::IAC, codes::DO, codes::ECHO], &mut caps);
+        assert!(telnet.local_enabled(codes::ECHO));
+
+        let echoed = feed(&mut telnet, b"look\r\n", &mut caps);
+        assert_eq!(echoed, b"look\r\n");
+
+        telnet.set_password_mode(true, &mut Vec::new());
+        let echoed = feed(&mut telnet, b"hunter2\r\n", &mut caps);
+        assert!(!String::from_utf8_lossy(&echoed).contains("hunter2"));
+    }
+
+    #[test]
+    fn large_gmcp_payload_is_gathered_across_reads() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let payload = format!("Room.Info \"{}\"", "x".repeat(64 * 1024));
+        let mut wire = vec![codes::IAC, codes::SB, codes::GMCP];
+        wire.extend_from_slice(payload.as_bytes());
+        wire.extend_from_slice(&[codes::IAC, codes::SE]);
+
+        let mut buf = BytesMut::new();
+        let mut received = None;
+        for chunk in wire.chunks(1024) {
+            buf.extend_from_slice(chunk);
+            if let Some(msg) = telnet.next_message(&mut buf).unwrap() {
+                received = Some(msg);
+            }
+            // Each read is taken in as it arrives rather than left to be scanned again.
+            assert!(buf.len() <= 1);
+        }
+        match received {
+            Some(TelnetMessage::SubNegotiate(op, data)) => {
+                assert_eq!(op, codes::GMCP);
+                assert_eq!(data, payload.as_bytes());
+            },
+            _ => panic!("the GMCP subnegotiation never arrived whole")
+        }
+    }
+
+    #[test]
+    fn unanswered_negotiations_expire_after_the_grace() {
+        let start = Instant::now();
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, start);
+        telnet.now = start;
+        telnet.start(&mut Vec::new());
+        assert!(!telnet.handshakes_left.is_empty());
+
+        telnet.expire_negotiations(start + NEGOTIATION_GRACE / 2, NEGOTIATION_GRACE);
+        assert!(!telnet.handshakes_left.is_empty());
+
+        telnet.expire_negotiations(start + NEGOTIATION_GRACE + Duration::from_millis(1), NEGOTIATION_GRACE);
+        assert!(telnet.handshakes_left.is_empty());
+        assert!(telnet.option_states().iter().all(|(_, s)| !s.local.negotiating && !s.remote.negotiating));
+    }
+
+    #[test]
+    fn absurd_naws_sizes_are_ignored_or_capped() {
+        use crate::net::MAX_WINDOW_DIMENSION;
+
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.start(&mut Vec::new());
+        feed(&mut telnet, &[codes::IAC, codes::WILL, codes::NAWS], &mut caps);
+        let before = (caps.width, caps.height);
+
+        feed(&mut telnet, &[codes::IAC, codes::SB, codes::NAWS, 0, 0, 0, 0, codes::IAC, codes::SE], &mut caps);
+        assert_eq!((caps.width, caps.height), before);
+
+        feed(&mut telnet, &[codes::IAC, codes::SB, codes::NAWS, 255, 255, 255, 255, 255, 255, 255, 255, codes::IAC, codes::SE], &mut caps);
+        assert_eq!((caps.width, caps.height), (MAX_WINDOW_DIMENSION, MAX_WINDOW_DIMENSION));
+    }
+
+    #[test]
+    fn mssp_send_requests_the_variables() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        let mut out = Vec::new();
+        telnet.start(&mut out);
+        assert!(out.windows(3).any(|w| w == [codes::IAC, codes::WILL, codes::MSSP]));
+
+        let requests = |telnet: &mut TelnetProtocol, caps: &mut ProtocolCapabilities, bytes: &[u8]| {
+            let mut pending = BytesMut::from(bytes);
+            let mut events = VecDeque::new();
+            while let Some(msg) = telnet.next_message(&mut pending).unwrap() {
+                telnet.process_message(msg, &mut events, &mut Vec::new(), caps);
+            }
+            events.iter().filter(|ev| matches!(ev, ProtocolEvent::RequestMSSP)).count()
+        };
+        assert_eq!(requests(&mut telnet, &mut caps, &[codes::IAC, codes::DO, codes::MSSP]), 1);
+        assert!(caps.mssp);
+        let mut send = vec![codes::IAC, codes::SB, codes::MSSP];
+        send.extend_from_slice(b"SEND");
+        send.extend_from_slice(&[codes::IAC, codes::SE]);
+        assert_eq!(requests(&mut telnet, &mut caps, &send), 1);
+    }
+
+    #[test]
+    fn mssp_variables_are_framed() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut out = Vec::new();
+        telnet.send_mssp(&mut out, &[("NAME".to_string(), "Crab".to_string()), ("PLAYERS".to_string(), "2".to_string())]);
+        let mut expected = vec![codes::IAC, codes::SB, codes::MSSP, codes::MSSP_VAR];
+        expected.extend_from_slice(b"NAME");
+        expected.push(codes::MSSP_VAL);
+        expected.extend_from_slice(b"Crab");
+        expected.push(codes::MSSP_VAR);
+        expected.extend_from_slice(b"PLAYERS");
+        expected.push(codes::MSSP_VAL);
+        expected.extend_from_slice(b"2");
+        expected.extend_from_slice(&[codes::IAC, codes::SE]);
+        assert_eq!(out, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_bom_and_zero_width_characters_dont_stop_a_command_matching() {
+        use crate::testing::Harness;
+
+        assert_eq!(sanitize_input("\u{FEFF}lo\u{200B}ok\u{00AD}", DEFAULT_STRIP_CHARS), "look");
+        // A family emoji is three people held together by joiners.
+        let family = "say \u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(sanitize_input(family, DEFAULT_STRIP_CHARS), family);
+        let mut h = Harness::new();
+        let mut client = h.login("alice");
+        let text = h.command(&mut client, "\u{FEFF}lo\u{200B}o\u{2060}k");
+        assert!(text.contains("You aren't anywhere."), "{:?}", text);
+    }
+
+    #[test]
+    fn the_eor_command_follows_prompts_only_once_the_eor_option_is_agreed() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        let mut offers = Vec::new();
+        telnet.start(&mut offers);
+        // We offer the option, 25, and never send the command, 239, on its own.
+        assert!(offers.windows(3).any(|w| w == [codes::IAC, codes::WILL, codes::TELOPT_EOR]));
+        assert!(!offers.windows(2).any(|w| w == [codes::IAC, codes::EOR]));
+
+        let prompt = |telnet: &TelnetProtocol| {
+            let mut out = Vec::new();
+            telnet.send_prompt(&mut out, "> ".to_string());
+            out
+        };
+        assert_eq!(prompt(&telnet), b"> ");
+
+        feed(&mut telnet, &[codes::IAC, codes::DO, codes::TELOPT_EOR], &mut caps);
+        assert_eq!(prompt(&telnet), [b'>', b' ', codes::IAC, codes::EOR]);
+        let mut line = Vec::new();
+        telnet.send_line(&mut line, "hello".to_string());
+        assert!(!line.contains(&codes::EOR));
+
+        feed(&mut telnet, &[codes::IAC, codes::DONT, codes::TELOPT_EOR], &mut caps);
+        assert_eq!(prompt(&telnet), b"> ");
+    }
+
+    // What the fuzz target checks: from_bytes never panics, and each message it returns used
+    // at least one byte and no more than it was given.
+    fn decode_all(bytes: &[u8]) -> Vec<TelnetMessage> {
+        let mut rest = bytes;
+        let mut out = Vec::new();
+        while let Some((msg, len)) = TelnetMessage::from_bytes(rest) {
+            assert!(len > 0 && len <= rest.len(), "{:?} used {} of {:?}", msg, len, rest);
+            rest = &rest[len..];
+            out.push(msg);
+        }
+        out
+    }
+
+    #[test]
+    fn from_bytes_crash_cases_are_incomplete_not_panics() {
+        assert_eq!(TelnetMessage::from_bytes(&[codes::IAC]), None);
+        assert_eq!(TelnetMessage::from_bytes(&[codes::IAC, codes::SB]), None);
+        assert_eq!(TelnetMessage::from_bytes(&[codes::IAC, codes::SB, codes::IAC, codes::SE]), None);
+        assert_eq!(decode_all(&[b'h', b'i', codes::IAC]), vec![TelnetMessage::Data(b"hi".to_vec())]);
+        assert_eq!(TelnetMessage::from_bytes(&[codes::IAC, codes::SB, codes::GMCP, 1, codes::IAC, codes::IAC, 2, codes::IAC, codes::SE]),
+                   Some((TelnetMessage::SubNegotiate(codes::GMCP, vec![1, codes::IAC, 2]), 9)));
+    }
+
+    #[test]
+    fn to_vec_doubles_iac_and_round_trips() {
+        let msg = TelnetMessage::SubNegotiate(codes::GMCP, vec![1, codes::IAC, codes::IAC, 2, codes::SE]);
+        let wire = msg.to_vec();
+        assert_eq!(wire, vec![codes::IAC, codes::SB, codes::GMCP, 1, codes::IAC, codes::IAC, codes::IAC, codes::IAC,
+                              2, codes::SE, codes::IAC, codes::SE]);
+        assert_eq!(TelnetMessage::from_bytes(&wire), Some((msg, wire.len())));
+
+        let data = TelnetMessage::Data(vec![b'a', codes::IAC, b'b']);
+        let wire = data.to_vec();
+        assert_eq!(wire, vec![b'a', codes::IAC, codes::IAC, b'b']);
+        assert_eq!(decode_all(&wire), vec![TelnetMessage::Data(vec![b'a']), TelnetMessage::Data(vec![codes::IAC]),
+                                           TelnetMessage::Data(vec![b'b'])]);
+    }
+
+    #[test]
+    fn from_bytes_survives_the_corpus_and_random_input() {
+        let corpus = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/telnet_from_bytes");
+        for entry in std::fs::read_dir(corpus).unwrap() {
+            decode_all(&std::fs::read(entry.unwrap().path()).unwrap());
+        }
+        // Mostly IAC and command bytes, so sequences collide in every way they can.
+        let alphabet = [codes::IAC, codes::SB, codes::SE, codes::WILL, codes::DO, codes::GMCP, b'a', 0];
+        for _ in 0..2000 {
+            let len = rand::random::<usize>() % 32;
+            let bytes: Vec<u8> = (0..len).map(|_| alphabet[rand::random::<usize>() % alphabet.len()]).collect();
+            decode_all(&bytes);
+        }
+    }
+
+    fn gmcp(body: &str) -> Vec<u8> {
+        let mut out = vec![codes::IAC, codes::SB, codes::GMCP];
+        out.extend_from_slice(body.as_bytes());
+        out.extend_from_slice(&[codes::IAC, codes::SE]);
+        out
+    }
+
+    #[test]
+    fn a_client_core_ping_is_answered_with_its_own_payload() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        let out = feed(&mut telnet, &gmcp("Core.Ping 1760640000123"), &mut caps);
+        assert_eq!(out, gmcp("Core.Ping 1760640000123"));
+        assert_eq!(feed(&mut telnet, &gmcp("Core.Ping"), &mut caps), gmcp("Core.Ping"));
+        assert_eq!(telnet.round_trip, None);
+    }
+
+    #[test]
+    fn our_core_ping_is_timed_by_the_answer() {
+        let start = Instant::now();
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, start);
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.set_time(start);
+        let mut sent = Vec::new();
+        telnet.send_gmcp_ping(&mut sent);
+        assert_eq!(sent, gmcp("Core.Ping"));
+
+        telnet.set_time(start + Duration::from_millis(40));
+        assert!(feed(&mut telnet, &gmcp("Core.Ping"), &mut caps).is_empty());
+        assert_eq!(telnet.round_trip, Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn do_timing_mark_is_answered_with_will() {
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.start(&mut Vec::new());
+        let out = feed(&mut telnet, &[codes::IAC, codes::DO, codes::TIMING_MARK], &mut caps);
+        assert_eq!(out, vec![codes::IAC, codes::WILL, codes::TIMING_MARK]);
+        assert!(!telnet.local_enabled(codes::TIMING_MARK));
+    }
+
+    #[test]
+    fn a_timing_mark_reply_measures_the_round_trip() {
+        let start = Instant::now();
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, start);
+        let mut caps = ProtocolCapabilities::telnet();
+        telnet.set_time(start);
+        let mut out = Vec::new();
+        telnet.send_timing_mark(&mut out);
+        assert_eq!(out, vec![codes::IAC, codes::DO, codes::TIMING_MARK]);
+
+        telnet.set_time(start + Duration::from_millis(120));
+        assert!(feed(&mut telnet, &[codes::IAC, codes::WILL, codes::TIMING_MARK], &mut caps).is_empty());
+        assert_eq!(telnet.round_trip, Some(Duration::from_millis(120)));
+    }
+}
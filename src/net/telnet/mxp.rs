@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+// MXP mode switches. They travel in the ordinary data stream, not as telnet commands.
+pub const MODE_OPEN: &str = "\x1b[0z";
+pub const MODE_SECURE: &str = "\x1b[1z";
+pub const MODE_LOCKED: &str = "\x1b[2z";
+pub const MODE_RESET: &str = "\x1b[3z";
+pub const MODE_LOCK_SECURE: &str = "\x1b[6z";
+
+// Elements and entities every client gets once MXP is on, so game output can tag room names,
+// exits and prompts without defining them itself.
+pub const DEFINITIONS: &[&str] = &[
+    "<!ELEMENT RName FLAG=\"RoomName\">",
+    "<!ELEMENT RDesc FLAG=\"RoomDesc\">",
+    "<!ELEMENT RExits FLAG=\"RoomExit\">",
+    "<!ELEMENT Ex \"<send>\">",
+    "<!ELEMENT Prompt FLAG=\"Prompt\">",
+    "<!ENTITY mudname \"mudcrab\">"
+];
+
+// What the server sends right after MXP is enabled: a <VERSION> request followed by the
+// standard definitions, all in secure mode.
+pub fn handshake() -> String {
+    let mut out = String::from(MODE_LOCK_SECURE);
+    out.push_str("<VERSION>");
+    for def in DEFINITIONS {
+        out.push_str(def);
+    }
+    out.push_str(MODE_RESET);
+    out
+}
+
+// Parses a client's "<VERSION MXP=1.0 CLIENT=zMUD VERSION=6.16>" reply into its attributes,
+// keyed in uppercase. The secure-mode escape clients put in front of it is skipped.
+pub fn parse_version(line: &str) -> Option<HashMap<String, String>> {
+    let line = line.trim().trim_start_matches(MODE_SECURE).trim();
+    let upper = line.to_uppercase();
+    if !upper.starts_with("<VERSION") || !line.ends_with('>') {
+        return None;
+    }
+    let body = &line["<VERSION".len()..line.len() - 1];
+
+    let mut attrs = HashMap::new();
+    let mut chars = body.chars().peekable();
+    loop {
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.peek() {
+            if *c == '=' || c.is_whitespace() {
+                break;
+            }
+            key.push(*c);
+            chars.next();
+        }
+        if key.is_empty() {
+            break;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    value.push(c);
+                }
+            } else {
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    value.push(*c);
+                    chars.next();
+                }
+            }
+        }
+        attrs.insert(key.to_uppercase(), value);
+    }
+    Some(attrs)
+}
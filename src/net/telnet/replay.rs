@@ -0,0 +1,198 @@
+use bytes::BytesMut;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::engine::resources::TelnetOptions;
+use crate::mudstring::color::ColorSystem;
+use crate::net::{NetError, ProtocolCapabilities, ProtocolEvent};
+use crate::net::telnet::{TelnetOption, TelnetProtocol};
+
+// Drives a TelnetProtocol with bytes a client sent, with no socket involved: what the
+// server writes collects in output instead. For checking negotiation against client
+// quirks without connecting the real thing.
+pub struct Replay {
+    pub telnet: TelnetProtocol,
+    pub capabilities: ProtocolCapabilities,
+    pub events: VecDeque<ProtocolEvent>,
+    pub output: Vec<u8>,
+    pending: BytesMut
+}
+
+impl Replay {
+    // Starts negotiation as a new connection would. Our opening offers are in output.
+    pub fn new(options: Arc<HashMap<u8, TelnetOption>>) -> Self {
+        // Nothing in a replay waits on time, so the wall clock will do.
+        let mut telnet = TelnetProtocol::new(options, Instant::now());
+        let mut output = Vec::new();
+        telnet.start(&mut output);
+        Self {
+            telnet,
+            capabilities: ProtocolCapabilities::telnet(),
+            events: Default::default(),
+            output,
+            pending: Default::default()
+        }
+    }
+
+    // Hands bytes to the protocol as if they had just been read off the socket.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), NetError> {
+        self.pending.extend_from_slice(bytes);
+        while let Some(msg) = self.telnet.next_message(&mut self.pending)? {
+            self.telnet.process_message(msg, &mut self.events, &mut self.output, &mut self.capabilities);
+        }
+        Ok(())
+    }
+
+    // Everything written since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    // The typed lines received so far.
+    pub fn lines(&self) -> Vec<String> {
+        self.events.iter().filter_map(|ev| match ev {
+            ProtocolEvent::Line(line) => Some(line.clone()),
+            _ => None
+        }).collect()
+    }
+
+    // Plays every SPLIT_LINES case and reports the ones that didn't come out as the single
+    // line "hello". Empty means they all did.
+    pub fn check_split_lines() -> Result<Vec<String>, NetError> {
+        let mut out = Vec::new();
+        for (i, reads) in SPLIT_LINES.iter().enumerate() {
+            let mut replay = Self::new(TelnetOptions::default().0);
+            for chunk in reads.iter() {
+                replay.feed(chunk)?;
+            }
+            let lines = replay.lines();
+            if lines != ["hello"] {
+                out.push(format!("case {} gave {:?}", i, lines));
+            }
+        }
+        Ok(out)
+    }
+
+    // Plays a recording against the options a listener offers by default, then reports
+    // where the result differs from what the recording expects. Empty means it matched.
+    pub fn run(recording: &Recording) -> Result<Vec<String>, NetError> {
+        let mut replay = Self::new(TelnetOptions::default().0);
+        for chunk in recording.bytes {
+            replay.feed(chunk)?;
+        }
+        Ok(recording.expect.differences(&replay.capabilities))
+    }
+}
+
+// What the capabilities should look like once a recording has played.
+pub struct Expect {
+    pub client_name: &'static str,
+    pub client_version: &'static str,
+    pub color: Option<ColorSystem>,
+    pub utf8: bool,
+    pub width: u16
+}
+
+impl Expect {
+    pub fn differences(&self, caps: &ProtocolCapabilities) -> Vec<String> {
+        let mut out = Vec::new();
+        if caps.client_name != self.client_name {
+            out.push(format!("client_name is {:?}, expected {:?}", caps.client_name, self.client_name));
+        }
+        if caps.client_version != self.client_version {
+            out.push(format!("client_version is {:?}, expected {:?}", caps.client_version, self.client_version));
+        }
+        if caps.color != self.color {
+            out.push(format!("color is {:?}, expected {:?}", caps.color, self.color));
+        }
+        if caps.utf8 != self.utf8 {
+            out.push(format!("utf8 is {}, expected {}", caps.utf8, self.utf8));
+        }
+        if caps.width != self.width {
+            out.push(format!("width is {}, expected {}", caps.width, self.width));
+        }
+        out
+    }
+}
+
+// A client's side of a handshake, one entry per read, each sent after our answer to the one
+// before.
+pub struct Recording {
+    pub client: &'static str,
+    pub bytes: &'static [&'static [u8]],
+    pub expect: Expect
+}
+
+// Reconstructed from each client's documented negotiation and MTTS replies (name, terminal
+// type, then the MTTS bitvector). Refresh them from a debug.trace_negotiation dump when a
+// client changes what it sends. Some clients put their version after the name.
+pub const RECORDINGS: &[Recording] = &[
+    Recording {
+        client: "Mudlet",
+        bytes: &[
+            b"\xff\xfd\x03\xff\xfb\x1f\xff\xfb\x18\xff\xfd\xc9\xff\xfe\x45\xff\xfd\x46\xff\xfd\x19\xff\xfc\x22\xff\xfb\x27\xff\xfc\x24\xff\xfa\x1f\x00\x78\x00\x28\xff\xf0",
+            b"\xff\xfa\x18\x00MUDLET\xff\xf0",
+            b"\xff\xfa\x18\x00ANSI-TRUECOLOR\xff\xf0",
+            b"\xff\xfa\x18\x00MTTS 2317\xff\xf0"
+        ],
+        expect: Expect {client_name: "MUDLET", client_version: "UNKNOWN", color: Some(ColorSystem::TrueColor), utf8: true, width: 120}
+    },
+    Recording {
+        client: "MUSHclient",
+        bytes: &[
+            b"\xff\xfd\x03\xff\xfb\x1f\xff\xfb\x18\xff\xfd\xc9\xff\xfe\x45\xff\xfe\x46\xff\xfe\x19\xff\xfc\x22\xff\xfc\x27\xff\xfc\x24\xff\xfa\x1f\x00\x64\x00\x32\xff\xf0",
+            b"\xff\xfa\x18\x00MUSHCLIENT\xff\xf0",
+            b"\xff\xfa\x18\x00XTERM\xff\xf0",
+            b"\xff\xfa\x18\x00MTTS 13\xff\xf0"
+        ],
+        expect: Expect {client_name: "MUSHCLIENT", client_version: "UNKNOWN", color: Some(ColorSystem::EightBit), utf8: true, width: 100}
+    },
+    Recording {
+        client: "TinTin++",
+        bytes: &[
+            b"\xff\xfd\x03\xff\xfb\x1f\xff\xfb\x18\xff\xfe\xc9\xff\xfe\x45\xff\xfd\x46\xff\xfd\x19\xff\xfc\x22\xff\xfb\x27\xff\xfc\x24\xff\xfa\x1f\x00\x50\x00\x18\xff\xf0",
+            b"\xff\xfa\x18\x00TINTIN++\xff\xf0",
+            b"\xff\xfa\x18\x00XTERM-256COLOR\xff\xf0",
+            b"\xff\xfa\x18\x00MTTS 2829\xff\xf0"
+        ],
+        expect: Expect {client_name: "TINTIN++", client_version: "UNKNOWN", color: Some(ColorSystem::TrueColor), utf8: true, width: 80}
+    },
+    Recording {
+        client: "Mudlet 4.10",
+        bytes: &[
+            b"\xff\xfd\x03\xff\xfb\x1f\xff\xfb\x18\xff\xfd\xc9\xff\xfe\x45\xff\xfd\x46\xff\xfd\x19\xff\xfc\x22\xff\xfb\x27\xff\xfc\x24\xff\xfa\x1f\x00\x78\x00\x28\xff\xf0",
+            b"\xff\xfa\x18\x00MUDLET 4.10\xff\xf0",
+            b"\xff\xfa\x18\x00ANSI-TRUECOLOR\xff\xf0",
+            b"\xff\xfa\x18\x00MTTS 2317\xff\xf0"
+        ],
+        expect: Expect {client_name: "MUDLET", client_version: "4.10", color: Some(ColorSystem::TrueColor), utf8: true, width: 120}
+    }
+];
+
+// Negotiation can arrive between any two bytes of a line, even part way through a UTF-8
+// character or split over reads itself. receive_data keeps the partial line in app_buffer
+// until its LF, so each of these, fed a read at a time, must still give just "hello".
+pub const SPLIT_LINES: &[&[&[u8]]] = &[
+    &[b"hel\xff\xfd\x01lo\n"],
+    &[b"hel\xff", b"\xfd\x01lo\r\n"],
+    &[b"hel\xff\xfd", b"\x01lo\r\n"],
+    &[b"hel\xff\xfb\x1f\xff\xfa\x1f\x00\x50", b"\x00\x18\xff\xf0lo\r\n"],
+    &[b"he\xff\xfd\x01", b"l", b"lo\r", b"\n"]
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_clients_negotiate_what_they_should() {
+        for recording in RECORDINGS {
+            assert_eq!(Replay::run(recording).unwrap(), Vec::<String>::new(), "{}", recording.client);
+        }
+    }
+
+    #[test]
+    fn negotiation_inside_a_line_leaves_just_the_line() {
+        assert_eq!(Replay::check_split_lines().unwrap(), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use bytes::{Buf, BytesMut};
+use flate2::{Compress, Decompress, Compression, FlushCompress, FlushDecompress};
+use crate::config::WebSocketConfig;
+use std::sync::Arc;
+use crate::net::telnet::{DEFAULT_STRIP_CHARS, sanitize_input};
+use crate::net::{NetError, ProtocolCapabilities, ProtocolEvent, oob_from_json, apply_window_size};
+
+// RFC 6455 WebSocket: the HTTP upgrade, then framed messages. Only what a MUD needs is here.
+//
+// Every text message is one JSON object, tagged by "type":
+//   {"type": "text", "data": "look"}                          a line of input, or output
+//   {"type": "prompt", "data": "> "}                          a prompt (server to client only)
+//   {"type": "oob", "package": "Char.Vitals", "data": {...}}   the same thing GMCP carries
+// Text that isn't one of those is taken as typed lines, so a bare client still works.
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// The most we'll hold of a handshake request, or of one message once it's reassembled
+// (and, for a compressed one, inflated).
+pub const MAX_HANDSHAKE: usize = 8192;
+pub const MAX_MESSAGE: usize = 1048576;
+
+// Messages smaller than this aren't worth compressing.
+pub const DEFLATE_MIN_SIZE: usize = 64;
+
+pub const OP_CONTINUATION: u8 = 0;
+pub const OP_TEXT: u8 = 1;
+pub const OP_BINARY: u8 = 2;
+pub const OP_CLOSE: u8 = 8;
+pub const OP_PING: u8 = 9;
+pub const OP_PONG: u8 = 10;
+
+#[derive(Debug, Clone)]
+pub enum WebSocketMessage {
+    // The handshake was accepted; this is the 101 response to send.
+    Upgrade(Vec<u8>),
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close
+}
+
+// permessage-deflate (RFC 7692) state for one connection.
+pub struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    // With no context takeover, each message starts from a fresh window.
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool
+}
+
+impl std::fmt::Debug for Deflate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deflate")
+            .field("server_no_context_takeover", &self.server_no_context_takeover)
+            .field("client_no_context_takeover", &self.client_no_context_takeover)
+            .finish()
+    }
+}
+
+impl Deflate {
+    pub fn new(server_bits: u8, server_no_context_takeover: bool, client_no_context_takeover: bool) -> Self {
+        Self {
+            compress: Compress::new_with_window_bits(Compression::default(), false, server_bits),
+            // A full-size window inflates anything the client was allowed to send.
+            decompress: Decompress::new_with_window_bits(false, 15),
+            server_no_context_takeover,
+            client_no_context_takeover
+        }
+    }
+
+    pub fn deflate(&mut self, data: &[u8]) -> Result<Vec<u8>, NetError> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| NetError::ProtocolViolation(e.to_string()))?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // Room left over means the flush is complete.
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(1024);
+        }
+        // The sync flush ends with an empty stored block that the receiver adds back itself.
+        if out.ends_with(&[0, 0, 0xff, 0xff]) {
+            out.truncate(out.len() - 4);
+        }
+        if self.server_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    pub fn inflate(&mut self, data: &[u8]) -> Result<Vec<u8>, NetError> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&[0, 0, 0xff, 0xff]);
+        let mut out = Vec::with_capacity(input.len() * 2);
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            self.decompress.decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| NetError::ProtocolViolation(e.to_string()))?;
+            if out.len() > MAX_MESSAGE {
+                return Err(NetError::BufferOverflow(MAX_MESSAGE));
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(4096);
+        }
+        if self.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Debug)]
+pub struct WebSocketProtocol {
+    pub config: WebSocketConfig,
+    pub upgraded: bool,
+    // Set when the client offered permessage-deflate and we accepted.
+    pub deflate: Option<Deflate>,
+    // A fragmented message being put back together: opcode, whether it's compressed, and
+    // the payload so far.
+    pub partial: Option<(u8, bool, Vec<u8>)>,
+    // Removed from typed lines, as for telnet. See DEFAULT_STRIP_CHARS.
+    pub strip_chars: Arc<Vec<char>>,
+    // Messages that broke the protocol without being worth dropping the connection over,
+    // for ProtocolComponent::review_conduct to score.
+    pub violations: Vec<String>
+}
+
+impl WebSocketProtocol {
+    pub fn new(config: WebSocketConfig) -> Self {
+        Self {
+            config,
+            upgraded: false,
+            deflate: None,
+            partial: None,
+            strip_chars: Arc::new(DEFAULT_STRIP_CHARS.to_vec()),
+            violations: Vec::new()
+        }
+    }
+
+    // Takes the next complete message off the front of buf, handshake first.
+    pub fn next_message(&mut self, buf: &mut BytesMut) -> Result<Option<WebSocketMessage>, NetError> {
+        if !self.upgraded {
+            return self.read_handshake(buf);
+        }
+        loop {
+            let (Frame {fin, rsv1, opcode, payload}, len) = match parse_frame(buf)? {
+                Some(frame) => frame,
+                None => return Ok(None)
+            };
+            buf.advance(len);
+
+            match opcode {
+                OP_CLOSE => return Ok(Some(WebSocketMessage::Close)),
+                OP_PING => return Ok(Some(WebSocketMessage::Ping(payload))),
+                OP_PONG => return Ok(Some(WebSocketMessage::Pong(payload))),
+                OP_TEXT | OP_BINARY => {
+                    if self.partial.is_some() {
+                        return Err(NetError::ProtocolViolation("new message inside a fragmented one".to_string()));
+                    }
+                    self.partial = Some((opcode, rsv1, payload));
+                },
+                OP_CONTINUATION => {
+                    match &mut self.partial {
+                        Some((_, _, data)) => {
+                            data.extend_from_slice(&payload);
+                            if data.len() > MAX_MESSAGE {
+                                return Err(NetError::BufferOverflow(MAX_MESSAGE));
+                            }
+                        },
+                        None => return Err(NetError::ProtocolViolation("continuation without a message".to_string()))
+                    }
+                },
+                other => return Err(NetError::ProtocolViolation(format!("unknown opcode {}", other)))
+            }
+
+            if !fin {
+                continue;
+            }
+            let (opcode, compressed, data) = self.partial.take().unwrap_or_default();
+            let data = if compressed {
+                match &mut self.deflate {
+                    Some(deflate) => deflate.inflate(&data)?,
+                    None => return Err(NetError::ProtocolViolation("compressed message without permessage-deflate".to_string()))
+                }
+            } else {
+                data
+            };
+            return Ok(Some(if opcode == OP_TEXT {
+                WebSocketMessage::Text(String::from_utf8(data).map_err(|_| NetError::ProtocolViolation("text message isn't UTF-8".to_string()))?)
+            } else {
+                WebSocketMessage::Binary(data)
+            }));
+        }
+    }
+
+    fn read_handshake(&mut self, buf: &mut BytesMut) -> Result<Option<WebSocketMessage>, NetError> {
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if buf.len() > MAX_HANDSHAKE => return Err(NetError::BufferOverflow(MAX_HANDSHAKE)),
+            None => return Ok(None)
+        };
+        let request = String::from_utf8_lossy(&buf[..end]).to_string();
+        buf.advance(end);
+
+        let mut lines = request.split("\r\n");
+        if !lines.next().map(|l| l.starts_with("GET ")).unwrap_or(false) {
+            return Err(NetError::ProtocolViolation("websocket handshake isn't a GET".to_string()));
+        }
+        let mut key = None;
+        let mut extensions = Vec::new();
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "sec-websocket-key" => key = Some(value.to_string()),
+                    "sec-websocket-extensions" => extensions.push(value.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        let key = key.ok_or_else(|| NetError::ProtocolViolation("websocket handshake without a key".to_string()))?;
+
+        let mut response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n", accept_key(&key));
+        if self.config.deflate {
+            if let Some((deflate, header)) = negotiate_deflate(&extensions.join(", "), self.config.max_window_bits) {
+                self.deflate = Some(deflate);
+                response += format!("Sec-WebSocket-Extensions: {}\r\n", header).as_str();
+            }
+        }
+        response += "\r\n";
+        self.upgraded = true;
+        Ok(Some(WebSocketMessage::Upgrade(response.into_bytes())))
+    }
+
+    // Handles one message: lines go to out, control frames are answered here.
+    pub fn process_message(&mut self, msg: WebSocketMessage, out: &mut VecDeque<ProtocolEvent>, writer: &mut impl Write,
+                           capabilities: &mut ProtocolCapabilities) -> bool {
+        match msg {
+            WebSocketMessage::Upgrade(response) => {
+                let _ = writer.write_all(&response);
+            },
+            WebSocketMessage::Text(text) => {
+                match decode_json_message(&text) {
+                    Some(ProtocolEvent::Resize(width, height)) => apply_window_size(capabilities, out, width, height),
+                    Some(ProtocolEvent::Line(line)) => out.push_back(ProtocolEvent::Line(self.clean(&line))),
+                    Some(ev) => out.push_back(ev),
+                    // One of ours, but with a type we don't know or fields missing.
+                    None if is_typed_json(&text) => self.violations.push("JSON message".to_string()),
+                    None => {
+                        for line in text.lines() {
+                            out.push_back(ProtocolEvent::Line(self.clean(line)));
+                        }
+                    }
+                }
+            },
+            WebSocketMessage::Binary(_) => self.violations.push("binary message".to_string()),
+            WebSocketMessage::Pong(_) => {},
+            WebSocketMessage::Ping(payload) => {
+                let _ = writer.write_all(&frame(OP_PONG, false, &payload));
+            },
+            WebSocketMessage::Close => {
+                let _ = writer.write_all(&frame(OP_CLOSE, false, &[]));
+                return false;
+            }
+        }
+        true
+    }
+
+    fn clean(&self, line: &str) -> String {
+        sanitize_input(line, &self.strip_chars).trim().to_string()
+    }
+
+    pub fn send_text(&mut self, writer: &mut impl Write, text: &str) {
+        let data = text.as_bytes();
+        let compressed = match &mut self.deflate {
+            Some(deflate) if data.len() >= DEFLATE_MIN_SIZE => deflate.deflate(data).ok(),
+            _ => None
+        };
+        let out = match compressed {
+            Some(c) => frame(OP_TEXT, true, &c),
+            None => frame(OP_TEXT, false, data)
+        };
+        let _ = writer.write_all(&out);
+    }
+
+    pub fn send_json(&mut self, writer: &mut impl Write, value: &serde_json::Value) {
+        self.send_text(writer, &value.to_string());
+    }
+}
+
+// A JSON message from the client as the event it stands for, or None if it isn't one.
+pub fn decode_json_message(text: &str) -> Option<ProtocolEvent> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let obj = value.as_object()?;
+    match obj.get("type")?.as_str()? {
+        "text" => Some(ProtocolEvent::Line(obj.get("data")?.as_str()?.trim().to_string())),
+        // {"type": "resize", "width": 120, "height": 40}, sent whenever the terminal changes.
+        "resize" => {
+            let dimension = |key: &str| obj.get(key).and_then(|v| v.as_u64()).map(|v| v.min(u16::MAX as u64) as u16).unwrap_or(0);
+            Some(ProtocolEvent::Resize(dimension("width"), dimension("height")))
+        },
+        "oob" => {
+            let package = obj.get("package")?.as_str()?.to_string();
+            let (args, kwargs) = oob_from_json(obj.get("data").cloned());
+            Some(ProtocolEvent::OOB(package, args, kwargs))
+        },
+        _ => None
+    }
+}
+
+// Whether text is a JSON object with a "type", which makes it a message rather than a line.
+fn is_typed_json(text: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(text).ok()
+        .map(|value| value.get("type").is_some())
+        .unwrap_or(false)
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+    base64::encode(hasher.digest().bytes())
+}
+
+// Picks the first permessage-deflate offer we can honour. Returns the compressor state and
+// the extension header to answer with.
+pub fn negotiate_deflate(offers: &str, max_window_bits: u8) -> Option<(Deflate, String)> {
+    // zlib can't compress with a window smaller than 2^9.
+    let max_bits = max_window_bits.clamp(9, 15);
+    'offers: for offer in offers.split(',') {
+        let mut params = offer.split(';').map(|p| p.trim());
+        if params.next() != Some("permessage-deflate") {
+            continue;
+        }
+        let mut server_bits = max_bits;
+        let mut client_bits = None;
+        let mut server_nct = false;
+        let mut client_nct = false;
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+                None => (param, None)
+            };
+            let bits = match value.map(|v| v.parse::<u8>()) {
+                Some(Ok(b)) if (8..=15).contains(&b) => Some(b),
+                Some(_) => continue 'offers,
+                None => None
+            };
+            match name {
+                "server_no_context_takeover" => server_nct = true,
+                "client_no_context_takeover" => client_nct = true,
+                "server_max_window_bits" => match bits {
+                    Some(b) if b >= 9 => server_bits = server_bits.min(b),
+                    _ => continue 'offers
+                },
+                "client_max_window_bits" => client_bits = Some(bits.unwrap_or(15).min(max_bits)),
+                _ => continue 'offers
+            }
+        }
+
+        let mut header = "permessage-deflate".to_string();
+        if server_nct {
+            header += "; server_no_context_takeover";
+        }
+        if client_nct {
+            header += "; client_no_context_takeover";
+        }
+        if server_bits < 15 {
+            header += format!("; server_max_window_bits={}", server_bits).as_str();
+        }
+        if let Some(bits) = client_bits {
+            if bits < 15 {
+                header += format!("; client_max_window_bits={}", bits).as_str();
+            }
+        }
+        return Some((Deflate::new(server_bits, server_nct, client_nct), header));
+    }
+    None
+}
+
+struct Frame {
+    fin: bool,
+    // Set on the first frame of a compressed message.
+    rsv1: bool,
+    opcode: u8,
+    // Already unmasked.
+    payload: Vec<u8>
+}
+
+// Reads one frame off the front of buf, along with how many bytes it took up.
+fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, NetError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let rsv1 = buf[0] & 0x40 != 0;
+    let opcode = buf[0] & 0x0f;
+    if buf[1] & 0x80 == 0 {
+        return Err(NetError::ProtocolViolation("unmasked client frame".to_string()));
+    }
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+        },
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(b), 10)
+        },
+        n => (n as u64, 2)
+    };
+    if len > MAX_MESSAGE as u64 {
+        return Err(NetError::BufferOverflow(MAX_MESSAGE));
+    }
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(NetError::ProtocolViolation("bad control frame".to_string()));
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload: Vec<u8> = buf[pos..pos + len].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+    Ok(Some((Frame {fin, rsv1, opcode, payload}, pos + len)))
+}
+
+// A single unmasked server frame.
+pub fn frame(opcode: u8, compressed: bool, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(0x80 | if compressed { 0x40 } else { 0 } | opcode);
+    if payload.len() < 126 {
+        out.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A masked client frame, as a browser would send it.
+    fn client_frame(opcode: u8, compressed: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut out = vec![0x80 | if compressed { 0x40 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            out.push(0x80 | payload.len() as u8);
+        } else {
+            out.push(0x80 | 126);
+            out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    fn upgrade(ws: &mut WebSocketProtocol, extensions: Option<&str>) -> String {
+        let mut request = "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n".to_string();
+        if let Some(ext) = extensions {
+            request += format!("Sec-WebSocket-Extensions: {}\r\n", ext).as_str();
+        }
+        request += "\r\n";
+        match ws.next_message(&mut BytesMut::from(request.as_bytes())).unwrap() {
+            Some(WebSocketMessage::Upgrade(response)) => String::from_utf8(response).unwrap(),
+            other => panic!("expected an upgrade, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn deflate_is_negotiated_and_used_both_ways() {
+        let mut ws = WebSocketProtocol::new(WebSocketConfig::default());
+        let response = upgrade(&mut ws, Some("permessage-deflate; client_max_window_bits"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Extensions: permessage-deflate"));
+
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(20);
+        let mut sent = Vec::new();
+        ws.send_text(&mut sent, &text);
+        assert_eq!(sent[0], 0x80 | 0x40 | OP_TEXT);
+        assert!(sent.len() < text.len() / 4, "{} bytes", sent.len());
+
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut packed = Vec::with_capacity(text.len());
+        compress.compress_vec(text.as_bytes(), &mut packed, FlushCompress::Sync).unwrap();
+        packed.truncate(packed.len() - 4);
+        let mut buf = BytesMut::from(client_frame(OP_TEXT, true, &packed).as_slice());
+        match ws.next_message(&mut buf).unwrap() {
+            Some(WebSocketMessage::Text(received)) => assert_eq!(received, text),
+            other => panic!("expected text, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn without_an_offer_messages_go_uncompressed() {
+        let mut ws = WebSocketProtocol::new(WebSocketConfig::default());
+        assert!(!upgrade(&mut ws, None).contains("Sec-WebSocket-Extensions"));
+
+        let text = "x".repeat(200);
+        let mut sent = Vec::new();
+        ws.send_text(&mut sent, &text);
+        assert_eq!(sent[0], 0x80 | OP_TEXT);
+        assert!(sent.ends_with(text.as_bytes()));
+
+        let mut buf = BytesMut::from(client_frame(OP_TEXT, true, b"junk").as_slice());
+        assert!(ws.next_message(&mut buf).is_err());
+    }
+
+    #[test]
+    fn json_oob_decodes_like_gmcp() {
+        use crate::engine::resources::TelnetOptions;
+        use crate::net::telnet::TelnetProtocol;
+        use crate::net::telnet::codes as tc;
+
+        let mut telnet = TelnetProtocol::new(TelnetOptions::default().0, std::time::Instant::now());
+        let mut caps = ProtocolCapabilities::telnet();
+        let mut events = VecDeque::new();
+        telnet.start(&mut Vec::new());
+        let mut pending = BytesMut::from(&[tc::IAC, tc::DO, tc::GMCP][..]);
+        pending.extend_from_slice(&[tc::IAC, tc::SB, tc::GMCP]);
+        pending.extend_from_slice(br#"Char.Vitals {"hp": 10, "name": "Bob"}"#);
+        pending.extend_from_slice(&[tc::IAC, tc::SE]);
+        while let Some(msg) = telnet.next_message(&mut pending).unwrap() {
+            telnet.process_message(msg, &mut events, &mut Vec::new(), &mut caps);
+        }
+        let gmcp = events.into_iter().find_map(|ev| match ev {
+            ProtocolEvent::OOB(package, args, kwargs) => Some((package, args, kwargs)),
+            _ => None
+        }).expect("no GMCP event");
+
+        let json = match decode_json_message(r#"{"type": "oob", "package": "Char.Vitals", "data": {"hp": 10, "name": "Bob"}}"#) {
+            Some(ProtocolEvent::OOB(package, args, kwargs)) => (package, args, kwargs),
+            other => panic!("expected OOB, got {:?}", other)
+        };
+        assert_eq!(json, gmcp);
+        assert_eq!(json.2.get("hp").map(|s| s.as_str()), Some("10"));
+
+        match decode_json_message(r#"{"type": "text", "data": "look "}"#) {
+            Some(ProtocolEvent::Line(line)) => assert_eq!(line, "look"),
+            other => panic!("expected a line, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn zero_width_characters_are_stripped_from_typed_lines() {
+        let mut ws = WebSocketProtocol::new(WebSocketConfig::default());
+        let mut caps = ProtocolCapabilities::websocket();
+        let mut out = VecDeque::new();
+        for text in ["\u{FEFF}lo\u{200B}ok", r#"{"type": "text", "data": "lo\u200bok"}"#] {
+            ws.process_message(WebSocketMessage::Text(text.to_string()), &mut out, &mut Vec::new(), &mut caps);
+        }
+        let lines: Vec<&ProtocolEvent> = out.iter().collect();
+        assert!(matches!(lines.as_slice(), [ProtocolEvent::Line(a), ProtocolEvent::Line(b)] if a == "look" && b == "look"),
+                "{:?}", lines);
+    }
+}
@@ -0,0 +1,203 @@
+// Drives a whole Engine from tests: clients connect over the mock Unix socket, and time only
+// moves when the test says so.
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use legion::{Entity, IntoQuery};
+use crate::config::{Config, GameConfig, NetConfig};
+use crate::engine::Engine;
+use crate::engine::clock::MockClock;
+use crate::game::accounts::PasswordWorker;
+use crate::game::objects::UserComponent;
+use crate::mudstring::ansi::strip_control;
+use crate::mudstring::color::ColorSystem;
+use crate::net::ProtocolComponent;
+use crate::net::telnet::NEGOTIATION_GRACE;
+
+// A path in the temp directory no other test will use. Nothing is created there.
+pub fn temp_path(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("mudcrab-test-{}-{}-{}", std::process::id(), n, name))
+}
+
+// An area of two rooms, The Void leading north to A Hall and back, and two items. The exit
+// out of The Void is capitalized, to show exit names don't care.
+pub const TWO_ROOMS: &str = r#"{"name": "limbo", "prototypes": [
+    {"id": "void", "type": "Room", "name": "The Void", "description": "Nothing at all.", "exits": {"North": "hall"}},
+    {"id": "hall", "type": "Room", "name": "A Hall", "exits": {"south": "void"}},
+    {"id": "rock", "type": "Item", "name": "a rock"},
+    {"id": "lamp", "type": "Item", "name": "a brass lamp"}
+]}"#;
+
+pub struct Harness {
+    pub engine: Engine,
+    pub clock: MockClock
+}
+
+pub struct Client {
+    pub stream: UnixStream
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let (engine, clock) = Engine::new_for_test();
+        Self {engine, clock}
+    }
+
+    pub fn with_config(net: Option<NetConfig>, game: Option<GameConfig>) -> Self {
+        let mut engine = Engine::new(Config {net, game, debug: None});
+        let clock = MockClock::default();
+        engine.set_clock(clock.clone());
+        Self {engine, clock}
+    }
+
+    pub fn with_game(game: GameConfig) -> Self {
+        Self::with_config(None, Some(game))
+    }
+
+    // Runs n ticks, pausing a little between them so socket traffic has arrived.
+    pub fn tick(&mut self, n: usize) {
+        for _ in 0..n {
+            self.engine.tick_once(Duration::from_millis(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn advance(&mut self, by: Duration) {
+        self.clock.advance(by);
+        self.tick(3);
+    }
+
+    // A client past negotiation, which it never answers, and the welcome screen.
+    pub fn connect(&mut self) -> Client {
+        let stream = self.engine.connect_mock().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        let mut client = Client {stream};
+        self.tick(3);
+        self.advance(NEGOTIATION_GRACE + Duration::from_secs(1));
+        client.read();
+        client
+    }
+
+    // A client logged in to a new account called name, with its output so far drained.
+    pub fn login(&mut self, name: &str) -> Client {
+        let mut client = self.connect();
+        self.send(&mut client, &format!("create {}=password", name));
+        client.read();
+        client
+    }
+
+    // Makes the named account an admin. Everyone else keeps what they had.
+    pub fn make_admin(&mut self, name: &str) {
+        for user in <&mut UserComponent>::query().iter_mut(&mut self.engine.world) {
+            if user.username == name {
+                user.admin = true;
+            }
+        }
+    }
+
+    // Has every connection logged in as the named account render color as given.
+    pub fn set_color(&mut self, name: &str, color: Option<ColorSystem>) {
+        let user = <(Entity, &UserComponent)>::query().iter(&self.engine.world)
+            .find(|(_, u)| u.username == name)
+            .map(|(ent, _)| *ent);
+        for prot in <&mut ProtocolComponent>::query().iter_mut(&mut self.engine.world) {
+            if user.is_some() && prot.user == user {
+                prot.capabilities.color = color;
+            }
+        }
+    }
+
+    // Sends a line and runs enough ticks for it to be handled and answered, waiting out any
+    // password hashing it started.
+    pub fn send(&mut self, client: &mut Client, line: &str) {
+        client.write(format!("{}\r\n", line).as_bytes());
+        self.tick(8);
+        self.settle_passwords();
+    }
+
+    // Ticks until the password thread has nothing left, then enough more for the answers to
+    // go out.
+    pub fn settle_passwords(&mut self) {
+        let busy = |h: &Self| h.engine.resources.get::<PasswordWorker>().map(|w| w.is_busy()).unwrap_or(false);
+        if busy(self) {
+            while busy(self) {
+                self.tick(1);
+            }
+            self.tick(4);
+        }
+    }
+
+    // What client sees in answer to line, without telnet or color codes.
+    pub fn command(&mut self, client: &mut Client, line: &str) -> String {
+        client.read();
+        self.send(client, line);
+        client.text()
+    }
+}
+
+impl Client {
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    // Everything received since the last read, as sent.
+    pub fn read_bytes(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 8192];
+        while let Ok(n) = self.stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        out
+    }
+
+    pub fn read(&mut self) -> String {
+        String::from_utf8_lossy(&self.read_bytes()).to_string()
+    }
+
+    // Everything received since the last read, with telnet negotiation and escapes removed.
+    pub fn text(&mut self) -> String {
+        let bytes = self.read_bytes();
+        let mut plain = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match (bytes[i], bytes.get(i + 1)) {
+                (255, Some(250)) => {
+                    while i < bytes.len() && !(bytes[i] == 255 && bytes.get(i + 1) == Some(&240)) {
+                        i += 1;
+                    }
+                    i += 2;
+                },
+                (255, Some(251..=254)) => i += 3,
+                (255, Some(255)) => {
+                    plain.push(255);
+                    i += 2;
+                },
+                (255, Some(_)) => i += 2,
+                (b, _) => {
+                    plain.push(b);
+                    i += 1;
+                }
+            }
+        }
+        strip_control(&String::from_utf8_lossy(&plain))
+    }
+
+    // Whether the other end has closed the connection.
+    pub fn is_closed(&mut self) -> bool {
+        let mut buf = [0u8; 8192];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(_) => continue,
+                Err(_) => return false
+            }
+        }
+    }
+}